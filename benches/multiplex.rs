@@ -0,0 +1,170 @@
+//! Throughput of a multiplexed substream over the mock channels without throttling, framed
+//! as binary `LayerCodec` frames by `multiplex_with_options` and, for comparison, as the length
+//! delimited JSON messages the substream data was serialized to before.
+//!
+//! The `json` variant forwards the data the way `divide_channel` used to: the substream is
+//! a duplex, a forwarding loop reads it in chunks, copies every chunk into a `Vec<u8>` and
+//! serializes it, the remote loop writes the decoded chunks to its duplex. It has no flow
+//! control. The mock pipe capacity, the duplex capacity and the read chunk size are set
+//! to the buffer size for both framings, the receive window to four times that.
+
+use std::pin::Pin;
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::{io::{duplex, AsyncReadExt, AsyncWriteExt}, runtime::Runtime};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use upgradable_channel::{multiplex_with_options, BufferOptions, mocks::{channel_mock_pair, ChannelMockOptions}};
+
+const DATA_SIZES: [usize; 3] = [1 << 20, 4 << 20, 16 << 20];
+const SAMPLE_SIZE: usize = 10;
+// pipe capacity and read chunk size in KiB, the receive window is four times that
+const BUFFER_SIZES: [usize; 3] = [1, 16, 64];
+
+fn buffers(buffer_size: usize) -> BufferOptions {
+    return BufferOptions::default()
+        .duplex_capacity(buffer_size * 1024)
+        .read_chunk_size(buffer_size * 1024)
+        .max_buffered_size(4 * buffer_size * 1024);
+}
+
+async fn transfer(
+    data: &[u8],
+    buffer_size: usize,
+) {
+    let mock_options = ChannelMockOptions::default()
+        .buffer_size(buffer_size * 1024);
+
+    let (local_channel, remote_channel) = channel_mock_pair(mock_options.clone(), mock_options);
+
+    let mut local_substreams = multiplex_with_options(local_channel, 2, buffers(buffer_size));
+    let mut remote_substreams = multiplex_with_options(remote_channel, 2, buffers(buffer_size));
+
+    let mut local_substream = local_substreams.remove(0);
+    let mut remote_substream = remote_substreams.remove(0);
+
+    let mut received_data = vec![0; data.len()];
+
+    tokio::join!(
+        async {
+            local_substream.write_all(data).await
+                .expect("Cannot write data.");
+        },
+        async {
+            remote_substream.read_exact(&mut received_data).await
+                .expect("Cannot read data.");
+        },
+    );
+
+    assert!(
+        received_data == data,
+        "Must receive the data.",
+    );
+}
+
+/// Message of the JSON framing, the substream id and the data.
+#[derive(Serialize, Deserialize)]
+enum JsonLayerMessage {
+    Data(u32, Vec<u8>),
+}
+
+async fn transfer_json(
+    data: &[u8],
+    buffer_size: usize,
+) {
+    let mock_options = ChannelMockOptions::default()
+        .buffer_size(buffer_size * 1024);
+
+    let (local_channel, remote_channel) = channel_mock_pair(mock_options.clone(), mock_options);
+
+    let mut local_channel = Framed::new(Pin::new(local_channel), LengthDelimitedCodec::new());
+    let mut remote_channel = Framed::new(Pin::new(remote_channel), LengthDelimitedCodec::new());
+
+    let (mut local_substream, mut local_child) = duplex(buffer_size * 1024);
+    let (mut remote_substream, mut remote_child) = duplex(buffer_size * 1024);
+
+    let mut received_data = vec![0; data.len()];
+
+    tokio::join!(
+        async {
+            local_substream.write_all(data).await
+                .expect("Cannot write data.");
+        },
+        async {
+            let mut buf = vec![0; buffer_size * 1024];
+            let mut bytes_sent = 0;
+
+            while bytes_sent < data.len() {
+                let bytes_read = local_child.read(&mut buf).await
+                    .expect("Cannot read data.");
+
+                let message = serde_json::to_vec(&JsonLayerMessage::Data(0, buf[..bytes_read].to_vec()))
+                    .expect("Cannot encode message.");
+
+                local_channel.send(Bytes::from(message)).await
+                    .expect("Cannot send message.");
+
+                bytes_sent += bytes_read;
+            }
+        },
+        async {
+            let mut bytes_received = 0;
+
+            while bytes_received < data.len() {
+                let frame = remote_channel.next().await
+                    .expect("Stream closed.")
+                    .expect("Cannot receive message.");
+
+                let JsonLayerMessage::Data(_, chunk) = serde_json::from_slice(&frame)
+                    .expect("Cannot decode message.");
+
+                remote_child.write_all(&chunk).await
+                    .expect("Cannot write data.");
+
+                bytes_received += chunk.len();
+            }
+        },
+        async {
+            remote_substream.read_exact(&mut received_data).await
+                .expect("Cannot read data.");
+        },
+    );
+
+    assert!(
+        received_data == data,
+        "Must receive the data.",
+    );
+}
+
+fn multiplex(criterion: &mut Criterion) {
+    let runtime = Runtime::new().expect("Cannot create runtime.");
+
+    for buffer_size in BUFFER_SIZES {
+        let mut group = criterion.benchmark_group(format!("multiplex/{}KiB_buffers", buffer_size));
+
+        group.sample_size(SAMPLE_SIZE);
+
+        for data_size in DATA_SIZES {
+            let data: Vec<u8> = (0..data_size).map(|_| rand::random()).collect();
+
+            group.throughput(Throughput::Bytes(data_size as u64));
+            group.bench_with_input(BenchmarkId::new("binary", data_size / 1024), &data, |bencher, data| {
+                bencher.iter(|| {
+                    runtime.block_on(transfer(data, buffer_size));
+                });
+            });
+            group.bench_with_input(BenchmarkId::new("json", data_size / 1024), &data, |bencher, data| {
+                bencher.iter(|| {
+                    runtime.block_on(transfer_json(data, buffer_size));
+                });
+            });
+        }
+
+        group.finish();
+    }
+}
+
+criterion_group!(benches, multiplex);
+criterion_main!(benches);
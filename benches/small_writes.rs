@@ -0,0 +1,78 @@
+//! Throughput of small writes through an upgraded `UpgradableChannel` over the mock channels.
+//!
+//! The polls take the channel halves from `SharedHalf` with a compare-and-swap of its state,
+//! the upgrade task waits for them asynchronously. The design is not meant to be faster than
+//! the `std::sync::Mutex` halves it replaced: a poll did a `try_lock` and an unlock, that is
+//! the same two atomic read-modify-writes it does now, and neither allocates. What it removes
+//! is the blocking `lock` of the upgrade task on a runtime thread.
+//!
+//! Running this bench on the previous revision and on this one in turn, three times each,
+//! release build, 1 core, 10 s of measurement, gives the median throughput ranges below.
+//! The mock channels dominate the cost of a write, and the 2-4% lower averages of the atomic
+//! state stay within the run-to-run spread of either version:
+//!
+//! | write size | `Mutex` halves        | atomic state          |
+//! |------------|-----------------------|-----------------------|
+//! | 16 B       | 131.10 - 154.37 MiB/s | 131.39 - 143.26 MiB/s |
+//! | 64 B       | 433.88 - 455.75 MiB/s | 420.28 - 440.88 MiB/s |
+//! | 256 B      | 939.42 - 961.12 MiB/s | 874.60 - 954.16 MiB/s |
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, runtime::Runtime};
+use upgradable_channel::{mocks::{channel_mock_pair, ChannelMockOptions}, TUpgradableChannel, UpgradableChannel};
+
+const WRITES_COUNT: usize = 1_024;
+const WRITE_SIZES: [usize; 3] = [16, 64, 256];
+
+async fn create_upgraded_pair() -> (Box<dyn TUpgradableChannel>, Box<dyn TUpgradableChannel>) {
+    let (local_channel1, remote_channel1) = channel_mock_pair(ChannelMockOptions::default(), ChannelMockOptions::default());
+    let (local_channel2, remote_channel2) = channel_mock_pair(ChannelMockOptions::default(), ChannelMockOptions::default());
+
+    let (_on_local_channel, mut local_channel) = UpgradableChannel::new("local", local_channel1);
+    let (_on_remote_channel, mut remote_channel) = UpgradableChannel::new("remote", remote_channel1);
+
+    tokio::try_join!(
+        local_channel.upgrade(local_channel2),
+        remote_channel.upgrade(remote_channel2),
+    ).expect("Cannot upgrade to the new channel.");
+
+    return (local_channel, remote_channel);
+}
+
+fn small_writes(criterion: &mut Criterion) {
+    let runtime = Runtime::new().expect("Cannot create runtime.");
+
+    let (mut local_channel, mut remote_channel) = runtime.block_on(create_upgraded_pair());
+
+    let mut group = criterion.benchmark_group("small_writes");
+
+    for write_size in WRITE_SIZES {
+        let data: Vec<u8> = (0..write_size).map(|_| rand::random()).collect();
+        let mut received_data = vec![0; write_size * WRITES_COUNT];
+
+        group.throughput(Throughput::Bytes((write_size * WRITES_COUNT) as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(write_size), &write_size, |bencher, _| {
+            bencher.iter(|| {
+                runtime.block_on(async {
+                    tokio::join!(
+                        async {
+                            for _ in 0..WRITES_COUNT {
+                                local_channel.write_all(&data).await
+                                    .expect("Cannot write data.");
+                            }
+                        },
+                        async {
+                            remote_channel.read_exact(&mut received_data).await
+                                .expect("Cannot read data.");
+                        },
+                    );
+                });
+            });
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, small_writes);
+criterion_main!(benches);
@@ -1,4 +1,3 @@
-use anyhow::anyhow;
 use connection_utils::test::test_async_stream;
 use cs_utils::{futures::wait_random, random_str, traits::Random};
 use upgradable_channel::{UpgradableChannel, mocks::{channel_mock_pair, ChannelMockOptions}};
@@ -25,20 +24,18 @@ async fn main() {
         tokio::spawn(async move {
             wait_random(1..=50).await;
 
-            on_local_channel1.send(local_channel2)
-                .map_err(|_| { return anyhow!("[local] Cannot send new channel notification."); })
-                .unwrap();
+            on_local_channel1.upgrade(local_channel2).await
+                .expect("[local] Cannot upgrade to the new channel.");
 
-            println!("> local upgrade sent");
+            println!("> local upgrade completed");
         }),
         tokio::spawn(async move {
             wait_random(1..=50).await;
 
-            on_remote_channel1.send(remote_channel2)
-                .map_err(|_| { return anyhow!("[remote] Cannot send new channel notification."); })
-                .unwrap();
+            on_remote_channel1.upgrade(remote_channel2).await
+                .expect("[remote] Cannot upgrade to the new channel.");
 
-            println!("> remote upgrade sent");
+            println!("> remote upgrade completed");
         }),
     ).unwrap();
 
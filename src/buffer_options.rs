@@ -0,0 +1,123 @@
+use std::cmp;
+
+/// Receive window of a substream unless configured otherwise.
+pub const DEFAULT_WINDOW_SIZE: usize = 64 * 1024;
+
+/// Capacity of the in-memory pipe of a substream unless configured otherwise.
+pub const DEFAULT_DUPLEX_CAPACITY: usize = 16 * 1024;
+
+/// Largest chunk of data read at once unless configured otherwise.
+pub const DEFAULT_READ_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Largest frame payload accepted from the remote side unless configured otherwise.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Largest amount of data written to an unpaired upgrade unless configured otherwise.
+pub const DEFAULT_MAX_UNCONFIRMED_SIZE: usize = 64 * 1024;
+
+/// Sizes of the buffers along the data path of the multiplexed channels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BufferOptions {
+    duplex_capacity: usize,
+    read_chunk_size: usize,
+    max_frame_size: usize,
+    max_buffered_size: usize,
+    max_unconfirmed_size: usize,
+}
+
+impl Default for BufferOptions {
+    fn default() -> BufferOptions {
+        return BufferOptions {
+            duplex_capacity: DEFAULT_DUPLEX_CAPACITY,
+            read_chunk_size: DEFAULT_READ_CHUNK_SIZE,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_buffered_size: DEFAULT_WINDOW_SIZE,
+            max_unconfirmed_size: DEFAULT_MAX_UNCONFIRMED_SIZE,
+        };
+    }
+}
+
+impl BufferOptions {
+    /// Capacity of the in-memory pipe between a substream and the forwarders,
+    /// the writes to the substream wait once it is full.
+    pub fn duplex_capacity(
+        self,
+        duplex_capacity: usize,
+    ) -> BufferOptions {
+        return BufferOptions {
+            duplex_capacity: cmp::max(duplex_capacity, 1),
+            ..self
+        };
+    }
+
+    /// Largest chunk of substream data sent in a single frame.
+    pub fn read_chunk_size(
+        self,
+        read_chunk_size: usize,
+    ) -> BufferOptions {
+        return BufferOptions {
+            read_chunk_size: cmp::max(read_chunk_size, 1),
+            ..self
+        };
+    }
+
+    /// Largest frame payload accepted from the remote side, a larger frame
+    /// fails the multiplexed channel. The remote side's `read_chunk_size`
+    /// must not exceed it.
+    pub fn max_frame_size(
+        self,
+        max_frame_size: usize,
+    ) -> BufferOptions {
+        return BufferOptions {
+            max_frame_size: cmp::max(max_frame_size, 1),
+            ..self
+        };
+    }
+
+    /// Largest amount of data received for a substream but not read yet,
+    /// the remote side waits for it to be read once it is reached. That is the
+    /// receive window of the substreams, see `MultiplexerOptions::window_size`.
+    pub fn max_buffered_size(
+        self,
+        max_buffered_size: usize,
+    ) -> BufferOptions {
+        return BufferOptions {
+            max_buffered_size: cmp::max(max_buffered_size, 1),
+            ..self
+        };
+    }
+
+    /// Largest amount of data written to the new channel of an upgrade the remote side
+    /// has not paired yet, the writes wait for the upgrade to be paired once it is reached.
+    /// The data is kept until then to be written to the previous channel if the upgrade
+    /// is called off, it is independent of the `max_buffered_size`.
+    pub fn max_unconfirmed_size(
+        self,
+        max_unconfirmed_size: usize,
+    ) -> BufferOptions {
+        return BufferOptions {
+            max_unconfirmed_size: cmp::max(max_unconfirmed_size, 1),
+            ..self
+        };
+    }
+
+    pub fn get_duplex_capacity(&self) -> usize {
+        return self.duplex_capacity;
+    }
+
+    pub fn get_read_chunk_size(&self) -> usize {
+        return self.read_chunk_size;
+    }
+
+    pub fn get_max_frame_size(&self) -> usize {
+        return self.max_frame_size;
+    }
+
+    pub fn get_max_buffered_size(&self) -> usize {
+        return self.max_buffered_size;
+    }
+
+    pub fn get_max_unconfirmed_size(&self) -> usize {
+        return self.max_unconfirmed_size;
+    }
+}
@@ -1,93 +1,185 @@
-use std::{sync::{Arc, Mutex}, task::Waker};
-
 use connection_utils::Channel;
-use tokio::{sync::oneshot::{self, Sender}, io::split};
+use tokio::{sync::mpsc, io::split};
 
 mod channel_message;
-pub use channel_message::ChannelMessage;
+pub use channel_message::{ChannelMessage, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION};
+
+mod capability;
+pub use capability::Capability;
+
+mod channel_event;
+pub use channel_event::ChannelEvent;
+
+mod channel_reader;
+pub use channel_reader::ChannelReader;
+
+mod channel_writer;
+pub use channel_writer::ChannelWriter;
+
+mod shared_half;
+pub use shared_half::SharedHalf;
+
+mod channel_state;
+pub use channel_state::ChannelState;
+
+mod upgrade_state;
+pub use upgrade_state::UpgradeState;
 
-use crate::{interleaved_channel::divide_channel, types::{TReadHalf, TWriteHalf}};
+mod upgrade_report;
+pub use upgrade_report::UpgradeReport;
 
-use self::implementations::handle_upgrade;
+mod upgradable_channel_options;
+pub use upgradable_channel_options::UpgradableChannelOptions;
+
+mod upgradable_channel_builder;
+pub use upgradable_channel_builder::UpgradableChannelBuilder;
+
+mod upgrade_handle;
+pub use upgrade_handle::{UpgradeHandle, UpgradeRequest, HandleRequest, TUpgradeResult};
+
+mod resumption_token;
+pub use resumption_token::ResumptionToken;
+
+use crate::{interleaved_channel::divide_channel, MultiplexerOptions, TUpgradableChannel};
+
+mod handle_upgrade;
+use handle_upgrade::handle_upgrade;
 
 mod implementations;
 
 pub struct UpgradableChannel {
     id: u16,
-    test_id: String,
     label: String,
-    // main_channel: Pin<Box<dyn Channel>>,
-    main_channel_reader: Arc<tokio::sync::Mutex<TReadHalf>>,
-    main_channel_writer: Arc<tokio::sync::Mutex<TWriteHalf>>,
-    channel2_reader: Arc<Mutex<Option<(TReadHalf, Vec<u8>)>>>,
-    channel2_writer: Arc<Mutex<Option<TWriteHalf>>>,
-    last_read_waker: Arc<Mutex<Option<Waker>>>,
+    state: ChannelState,
+    upgrade_handle: UpgradeHandle,
+    resume_handle: UpgradeHandle,
 }
 
 impl UpgradableChannel {
+    /// Create a new upgradable channel on top of the `main_channel`.
+    /// 
+    /// Returns a handle that accepts a sequence of new channels to upgrade to,
+    /// each one of them replaces the currently active channel once both sides
+    /// of the connection have supplied their counterparts.
+    ///
+    /// Dropping the channel along with its `UpgradeHandle`s stops its background
+    /// tasks, the remote side gets the data written so far and EOF, pending
+    /// upgrades fail then, see `UpgradableChannelOptions::close_timeout`.
+    #[allow(clippy::new_ret_no_self)] // the channel is handed out boxed, along with its handle
     pub fn new(
         id: impl AsRef<str> + ToString,
         main_channel: Box<dyn Channel>,
-    ) -> (Sender<Box<dyn Channel>>, Box<dyn Channel>) {
-        let test_id = id.to_string(); // TODO: take the main channel id instead
+    ) -> (UpgradeHandle, Box<dyn TUpgradableChannel>) {
+        return UpgradableChannel::new_with_options(id, main_channel, UpgradableChannelOptions::default());
+    }
+
+    /// Create a new upgradable channel on top of the `main_channel`
+    /// with the provided `options`, see `UpgradableChannel::new`.
+    pub fn new_with_options(
+        id: impl AsRef<str> + ToString,
+        main_channel: Box<dyn Channel>,
+        options: UpgradableChannelOptions,
+    ) -> (UpgradeHandle, Box<dyn TUpgradableChannel>) {
+        let name = id.to_string();
         let id = main_channel.id();
         let label = main_channel.label().clone();
 
-        let (main_channel, control_channel) = divide_channel(main_channel);
+        let trace = child_trace!(options.get_trace(), "upgradable_channel", name = %name, id = id, label = %label);
+        let spawner = options.get_spawner().tracked();
+
+        let lanes = MultiplexerOptions::default()
+            .buffers(options.get_buffers().clone())
+            .trace(trace)
+            .spawner(spawner.clone())
+            .linger_timeout(options.get_close_timeout())
+            .substreams(1)
+            .substream(options.get_control_window_size());
+
+        let (main_channel, control_channel) = divide_channel(main_channel, lanes.clone());
 
         let (main_channel_reader, main_channel_writer) = split(main_channel);
 
-        let main_channel_reader = Arc::new(tokio::sync::Mutex::new(Box::pin(main_channel_reader)));
-        let main_channel_writer = Arc::new(tokio::sync::Mutex::new(Box::pin(main_channel_writer)));
+        let (
+            event_sender,
+            event_receiver,
+        ) = mpsc::unbounded_channel();
 
-        let channel2_reader = Arc::new(Mutex::new(None));
-        let channel2_writer = Arc::new(Mutex::new(None));
+        let state = ChannelState::new(
+            Box::pin(main_channel_reader),
+            Box::pin(main_channel_writer),
+            id,
+            &label,
+            &options,
+            lanes,
+            event_sender,
+        );
 
         let (
             new_channel_sender,
             new_channel_receiver,
-        ) = oneshot::channel();
-
-        let last_read_waker = Arc::new(Mutex::new(None));
-
-        // let id = random_str(4);
-
-        // TODO: return handle or add `on_error` notification
-        let _handle = tokio::spawn(
-            handle_upgrade(
-                test_id.clone(),
-                new_channel_receiver,
-                control_channel,
-                Arc::clone(&main_channel_reader),
-                Arc::clone(&main_channel_writer),
-                Arc::clone(&channel2_reader),
-                Arc::clone(&channel2_writer),
-                Arc::clone(&last_read_waker),
-            ),
+        ) = mpsc::unbounded_channel();
+
+        let (
+            resume_sender,
+            resume_receiver,
+        ) = mpsc::unbounded_channel();
+
+        let upgrade_task = handle_upgrade(
+            new_channel_receiver,
+            resume_receiver,
+            event_receiver,
+            control_channel,
+            state.clone(),
         );
 
+        // the task result is reported to the `UpgradeHandle` callers
+        spawner.spawn("handle_upgrade", async move {
+            let _res = upgrade_task.await;
+        });
+
+        let upgrade_handle = UpgradeHandle::new(new_channel_sender, spawner.tasks());
+
         return (
-            new_channel_sender,
+            upgrade_handle.clone(),
             Box::new(
                 UpgradableChannel {
                     id,
-                    test_id,
                     label,
-                    main_channel_reader,
-                    main_channel_writer,
-                    // main_channel: Pin::new(main_channel),
-                    channel2_reader,
-                    channel2_writer,
-                    last_read_waker,
+                    state,
+                    upgrade_handle,
+                    resume_handle: UpgradeHandle::new(resume_sender, spawner.tasks()),
                 },
             ),
         );
     }
+
+    /// Configure a new upgradable channel, see `UpgradableChannel::new`.
+    pub fn builder() -> UpgradableChannelBuilder {
+        return UpgradableChannelBuilder::default();
+    }
+
+    /// Resume the session of the `token` on the `new_channel`, once both the active
+    /// and the control channels are lost, the remote side must resume its side of the
+    /// session on the counterpart of the `new_channel`. Requires the `resumable` option.
+    ///
+    /// Resolves once both `reads` and `writes` are switched to the `new_channel` on both
+    /// sides, the unacknowledged data is replayed on it if the replay is enabled. Waits
+    /// until the connection loss is detected if the session is still connected. Fails if
+    /// the remote side does not own the session, the session can be resumed again then.
+    pub async fn resume(
+        token: &ResumptionToken,
+        new_channel: Box<dyn Channel>,
+    ) -> TUpgradeResult {
+        return token.resume(new_channel).await;
+    }
 }
 
+#[cfg(test)]
 static DATA_TRANSFER_CHARACTERS: &str = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ@#$%&*+=_";
+#[cfg(test)]
 static DATA_TRANSFER_DELIMITER: &str = "----";
 
+#[cfg(test)]
 fn add_data_transfer_string(
     start_string: impl AsRef<str> + ToString,
     len: usize,
@@ -114,6 +206,7 @@ fn add_data_transfer_string(
     return add_data_transfer_string(result, len, sequence_len);
 }
 
+#[cfg(test)]
 pub fn data_transfer_string(
     len: usize,
 ) -> String {
@@ -126,15 +219,15 @@ mod tests {
     use super::data_transfer_string;
 
     mod binary_data_transfer {
-        use std::ops::RangeInclusive;
+        use std::{ops::RangeInclusive, time::Duration};
 
-        use anyhow::anyhow;
-        use cs_utils::{random_str_rg, traits::Random, futures::wait_random};
+        use cs_utils::{random_number, random_str_rg, traits::Random, futures::wait_random};
         use rstest::rstest;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
         use connection_utils::test::test_async_stream;
 
         use super::data_transfer_string;
-        use crate::{mocks::{ChannelMockOptions, channel_mock_pair}, UpgradableChannel};
+        use crate::{mocks::{ChannelMockOptions, channel_mock_pair}, UpgradableChannel, UpgradableChannelOptions, BufferOptions};
 
         #[rstest]
         #[case(random_str_rg(100..=128))]
@@ -172,16 +265,184 @@ mod tests {
                 Box::pin(async move {
                     wait_random(5..=25).await;
 
-                    on_local_channel1.send(local_channel2)
-                        .map_err(|_| { return anyhow!("[local] Cannot send new channel notification."); })
-                        .unwrap();
+                    on_local_channel1.upgrade(local_channel2).await
+                        .expect("[local] Cannot upgrade to the new channel.");
+                }),
+                Box::pin(async move {
+                    wait_random(5..=25).await;
+
+                    on_remote_channel1.upgrade(remote_channel2).await
+                        .expect("[remote] Cannot upgrade to the new channel.");
+                }),
+            );
+        }
+
+        /// Write the `data` in chunks of random size up to `max_chunk_size` while
+        /// both sides upgrade to a new channel, and read it in chunks of random size too.
+        async fn upgrade_with_chunked_data(
+            data: Vec<u8>,
+            max_chunk_size: usize,
+            options: UpgradableChannelOptions,
+        ) {
+            let (local_channel1, remote_channel1) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+            let (local_channel2, remote_channel2) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+
+            let (on_local_channel1, mut local_upgradable_channel1) = UpgradableChannel::new_with_options("local", local_channel1, options.clone());
+            let (on_remote_channel1, mut remote_upgradable_channel1) = UpgradableChannel::new_with_options("remote", remote_channel1, options);
+
+            let mut received_data = vec![];
+
+            tokio::join!(
+                async {
+                    for chunk in data.chunks(random_number(1..=max_chunk_size)) {
+                        local_upgradable_channel1.write_all(chunk).await
+                            .expect("Cannot write data.");
+                    }
+                },
+                async {
+                    while received_data.len() < data.len() {
+                        let mut buf = vec![0; random_number(1..=max_chunk_size)];
+
+                        let bytes_read = remote_upgradable_channel1.read(&mut buf).await
+                            .expect("Cannot read data.");
+
+                        assert!(bytes_read > 0, "Channel closed.");
+
+                        received_data.extend_from_slice(&buf[..bytes_read]);
+                    }
+                },
+                async {
+                    wait_random(5..=25).await;
+
+                    on_local_channel1.upgrade(local_channel2).await
+                        .expect("[local] Cannot upgrade to the new channel.");
+                },
+                async {
+                    wait_random(5..=25).await;
+
+                    on_remote_channel1.upgrade(remote_channel2).await
+                        .expect("[remote] Cannot upgrade to the new channel.");
+                },
+            );
+
+            assert!(
+                received_data == data,
+                "Must receive the data unchanged.",
+            );
+        }
+
+        #[rstest]
+        #[case(1_024, 128, UpgradableChannelOptions::default())]
+        #[case(16_384, 1_024, UpgradableChannelOptions::default())]
+        #[case(65_536, 4_096, UpgradableChannelOptions::default())]
+        #[case(16_384, 1_024, UpgradableChannelOptions::default().fallback(true).replay_buffer_size(64 * 1_024))]
+        #[tokio::test]
+        async fn upgrades_with_binary_data(
+            #[case] data_size: usize,
+            #[case] max_chunk_size: usize,
+            #[case] options: UpgradableChannelOptions,
+        ) {
+            let data = (0..data_size).map(|_| rand::random()).collect();
+
+            upgrade_with_chunked_data(data, max_chunk_size, options).await;
+        }
+
+        #[rstest]
+        #[case("é")]
+        #[case("€")]
+        #[case("😀")]
+        #[case("aé€😀")]
+        #[tokio::test]
+        async fn upgrades_with_split_utf8_sequences(
+            #[case] sequence: &str,
+        ) {
+            // chunks of up to 7 bytes split most of the characters
+            let data = sequence.repeat(2_048).into_bytes();
+
+            upgrade_with_chunked_data(data, 7, UpgradableChannelOptions::default()).await;
+        }
+
+        #[rstest]
+        #[case(BufferOptions::default().duplex_capacity(512).read_chunk_size(256).max_buffered_size(1_024))]
+        #[case(BufferOptions::default().duplex_capacity(256 * 1_024).read_chunk_size(64 * 1_024).max_buffered_size(1_024 * 1_024))]
+        #[tokio::test]
+        async fn upgrades_with_custom_buffers(
+            #[case] buffers: BufferOptions,
+        ) {
+            let test_data = random_str_rg(30_000..=32_768);
+            let options = UpgradableChannelOptions::default()
+                .buffers(buffers);
+
+            let (local_channel1, remote_channel1) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+            let (local_channel2, remote_channel2) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+
+            let (on_local_channel1, local_upgradable_channel1) = UpgradableChannel::new_with_options("local", local_channel1, options.clone());
+            let (on_remote_channel1, remote_upgradable_channel1) = UpgradableChannel::new_with_options("remote", remote_channel1, options);
+
+            let _res = tokio::join!(
+                Box::pin(async move {
+                    test_async_stream(
+                        local_upgradable_channel1,
+                        remote_upgradable_channel1,
+                        test_data,
+                    ).await;
+                }),
+                Box::pin(async move {
+                    wait_random(5..=25).await;
+
+                    on_local_channel1.upgrade(local_channel2).await
+                        .expect("[local] Cannot upgrade to the new channel.");
                 }),
                 Box::pin(async move {
                     wait_random(5..=25).await;
 
-                    on_remote_channel1.send(remote_channel2)
-                        .map_err(|_| { return anyhow!("[remote] Cannot send new channel notification."); })
-                        .unwrap();
+                    on_remote_channel1.upgrade(remote_channel2).await
+                        .expect("[remote] Cannot upgrade to the new channel.");
+                }),
+            );
+        }
+
+        #[rstest]
+        #[case(2, random_str_rg(16_000..=16_384))]
+        #[case(3, random_str_rg(30_000..=32_768))]
+        #[case(5, random_str_rg(64_000..=65_536))]
+        #[case(8, data_transfer_string(40_000))]
+        #[tokio::test]
+        async fn upgrades_multiple_times(
+            #[case] upgrades_count: usize,
+            #[case] test_data: String,
+        ) {
+            let options = ChannelMockOptions::random();
+
+            let (local_channel1, remote_channel1) = channel_mock_pair(options.clone(), options.clone());
+
+            let (on_local_channel1, local_upgradable_channel1) = UpgradableChannel::new("local", local_channel1);
+            let (on_remote_channel1, remote_upgradable_channel1) = UpgradableChannel::new("remote", remote_channel1);
+
+            let _res = tokio::join!(
+                Box::pin(async move {
+                    test_async_stream(
+                        local_upgradable_channel1,
+                        remote_upgradable_channel1,
+                        test_data,
+                    ).await;
+                }),
+                Box::pin(async move {
+                    for _ in 0..upgrades_count {
+                        wait_random(5..=25).await;
+
+                        let options = ChannelMockOptions::random();
+                        let (local_channel, remote_channel) = channel_mock_pair(options.clone(), options.clone());
+
+                        tokio::try_join!(
+                            on_local_channel1.upgrade(local_channel),
+                            async {
+                                wait_random(0..=10).await;
+
+                                return on_remote_channel1.upgrade(remote_channel).await;
+                            },
+                        ).expect("Cannot upgrade to the new channel.");
+                    }
                 }),
             );
         }
@@ -208,8 +469,12 @@ mod tests {
             let (local_channel1, remote_channel1) = channel_mock_pair(options1.clone(), options1.clone());
             let (local_channel2, remote_channel2) = channel_mock_pair(options2.clone(), options2.clone());
 
-            let (on_local_channel1, local_upgradable_channel1) = UpgradableChannel::new("local", local_channel1);
-            let (on_remote_channel1, remote_upgradable_channel1) = UpgradableChannel::new("remote", remote_channel1);
+            // the `Hello` message waits behind a full window of the throttled data
+            let options = UpgradableChannelOptions::default()
+                .handshake_timeout(Duration::from_secs(60));
+
+            let (on_local_channel1, local_upgradable_channel1) = UpgradableChannel::new_with_options("local", local_channel1, options.clone());
+            let (on_remote_channel1, remote_upgradable_channel1) = UpgradableChannel::new_with_options("remote", remote_channel1, options);
 
             let _res = tokio::join!(
                 Box::pin(async move {
@@ -222,18 +487,2399 @@ mod tests {
                 Box::pin(async move {
                     wait_random(5..=25).await;
 
-                    on_local_channel1.send(local_channel2)
-                        .map_err(|_| { return anyhow!("[local] Cannot send new channel notification."); })
-                        .unwrap();
+                    on_local_channel1.upgrade(local_channel2).await
+                        .expect("[local] Cannot upgrade to the new channel.");
                 }),
                 Box::pin(async move {
                     wait_random(5..=25).await;
 
-                    on_remote_channel1.send(remote_channel2)
-                        .map_err(|_| { return anyhow!("[remote] Cannot send new channel notification."); })
-                        .unwrap();
+                    on_remote_channel1.upgrade(remote_channel2).await
+                        .expect("[remote] Cannot upgrade to the new channel.");
                 }),
             );
         }
     }
+
+    mod upgrade_handle {
+        use std::{ops::RangeInclusive, sync::{Arc, atomic::Ordering}, time::Duration};
+
+        use cs_utils::{random_str, random_str_rg, traits::Random, futures::{wait, wait_random}};
+        use futures::{SinkExt, StreamExt};
+        use rstest::rstest;
+        use tokio::{io::{AsyncReadExt, AsyncWriteExt}, sync::Barrier};
+        use connection_utils::test::test_async_stream;
+
+        use crate::{
+            mocks::{ChannelMockOptions, channel_mock_pair},
+            interleaved_channel::divide_channel,
+            MultiplexerOptions,
+            utils::{create_framed_stream, ReadCounter},
+            channel::{ChannelMessage, Capability, TUpgradeResult, PROTOCOL_VERSION},
+            UpgradableChannel,
+            UpgradableChannelOptions,
+            UpgradeHandle,
+            UpgradeState,
+            UpgradeError,
+            TUpgradableChannel,
+            BufferOptions,
+        };
+
+        use super::fallback::transfer;
+
+        #[tokio::test]
+        async fn reports_completed_upgrade() {
+            let options1 = ChannelMockOptions::random();
+            let options2 = ChannelMockOptions::random();
+
+            let (local_channel1, remote_channel1) = channel_mock_pair(options1.clone(), options1.clone());
+            let (local_channel2, remote_channel2) = channel_mock_pair(options2.clone(), options2.clone());
+
+            let channel2_id = local_channel2.id();
+            let channel2_label = local_channel2.label().clone();
+
+            let (on_local_channel1, local_upgradable_channel1) = UpgradableChannel::new("local", local_channel1);
+            let (on_remote_channel1, remote_upgradable_channel1) = UpgradableChannel::new("remote", remote_channel1);
+
+            let (local_report, remote_report) = tokio::try_join!(
+                on_local_channel1.upgrade(local_channel2),
+                async {
+                    wait_random(5..=25).await;
+
+                    return on_remote_channel1.upgrade(remote_channel2).await;
+                },
+            ).expect("Cannot upgrade to the new channel.");
+
+            assert_eq!(
+                local_report.channel_id(),
+                channel2_id,
+                "Must report the new channel id.",
+            );
+
+            assert_eq!(
+                local_report.channel_label(),
+                &channel2_label,
+                "Must report the new channel label.",
+            );
+
+            assert_eq!(
+                local_report.local_sync_id(),
+                remote_report.remote_sync_id(),
+                "Local and remote sync ids must match.",
+            );
+
+            assert_eq!(
+                local_report.remote_sync_id(),
+                remote_report.local_sync_id(),
+                "Remote and local sync ids must match.",
+            );
+
+            test_async_stream(
+                local_upgradable_channel1,
+                remote_upgradable_channel1,
+                random_str_rg(1_000..=4_096),
+            ).await;
+        }
+
+        #[rstest]
+        #[case(1)]
+        #[case(256)]
+        #[case(512)]
+        #[case(1_000)]
+        #[tokio::test]
+        async fn hands_over_at_write_offset(
+            #[case] bytes_before_upgrade: usize,
+        ) {
+            let options1 = ChannelMockOptions::random();
+            let options2 = ChannelMockOptions::random();
+
+            let (local_channel1, remote_channel1) = channel_mock_pair(options1.clone(), options1.clone());
+            let (local_channel2, remote_channel2) = channel_mock_pair(options2.clone(), options2.clone());
+
+            let (on_local_channel1, mut local_upgradable_channel1) = UpgradableChannel::new("local", local_channel1);
+            let (on_remote_channel1, mut remote_upgradable_channel1) = UpgradableChannel::new("remote", remote_channel1);
+
+            let data_before_upgrade = random_str(bytes_before_upgrade);
+            let data_after_upgrade = random_str_rg(1..=1_024);
+
+            local_upgradable_channel1.write_all(data_before_upgrade.as_bytes()).await
+                .expect("Cannot write data before the upgrade.");
+
+            let (local_report, remote_report) = tokio::try_join!(
+                on_local_channel1.upgrade(local_channel2),
+                async {
+                    wait_random(5..=25).await;
+
+                    return on_remote_channel1.upgrade(remote_channel2).await;
+                },
+            ).expect("Cannot upgrade to the new channel.");
+
+            assert_eq!(
+                remote_report.handover_offset(),
+                bytes_before_upgrade,
+                "Must hand over after the data written to the previous channel.",
+            );
+
+            assert_eq!(
+                local_report.handover_offset(),
+                0,
+                "Must hand over right away if no data written to the previous channel.",
+            );
+
+            local_upgradable_channel1.write_all(data_after_upgrade.as_bytes()).await
+                .expect("Cannot write data after the upgrade.");
+
+            let mut received_data = vec![0; data_before_upgrade.len() + data_after_upgrade.len()];
+
+            remote_upgradable_channel1.read_exact(&mut received_data).await
+                .expect("Cannot read the data.");
+
+            assert_eq!(
+                received_data,
+                format!("{}{}", data_before_upgrade, data_after_upgrade).as_bytes(),
+                "Must receive the data in order.",
+            );
+        }
+
+        #[tokio::test]
+        async fn backpressures_writes_until_reads_switch() {
+            let (local_channel1, remote_channel1) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+            let (local_channel2, remote_channel2) = channel_mock_pair(ChannelMockOptions::default(), ChannelMockOptions::default());
+
+            let (on_local_channel1, mut local_upgradable_channel1) = UpgradableChannel::new("local", local_channel1);
+            let (on_remote_channel1, mut remote_upgradable_channel1) = UpgradableChannel::new("remote", remote_channel1);
+
+            // the local side does not read the data of the current channel,
+            // hence its reads stay on it once the upgrade completes
+            let early_data = random_str_rg(1..=512);
+
+            remote_upgradable_channel1.write_all(early_data.as_bytes()).await
+                .expect("Cannot write data.");
+
+            tokio::try_join!(
+                on_local_channel1.upgrade(local_channel2),
+                on_remote_channel1.upgrade(remote_channel2),
+            ).expect("Cannot upgrade to the new channel.");
+
+            // the data of the new channel is not read before the reads switch to it,
+            // hence the remote side waits once the channel itself is full
+            let data = random_str(64 * 1_024);
+
+            let mut write = Box::pin(remote_upgradable_channel1.write_all(data.as_bytes()));
+
+            assert!(
+                futures::poll!(&mut write).is_pending(),
+                "Must wait for the local side to read the data.",
+            );
+
+            // the channel does not drain by itself
+            wait(50).await;
+
+            assert!(
+                futures::poll!(&mut write).is_pending(),
+                "Must keep waiting for the local side to read the data.",
+            );
+
+            let mut received_data = vec![0; early_data.len() + data.len()];
+
+            let (read_result, write_result) = tokio::join!(
+                local_upgradable_channel1.read_exact(&mut received_data),
+                write,
+            );
+
+            read_result.expect("Cannot read the data.");
+            write_result.expect("Cannot write the data.");
+
+            assert_eq!(
+                &received_data[..],
+                [early_data.as_bytes(), data.as_bytes()].concat(),
+                "Must receive the data.",
+            );
+        }
+
+        #[tokio::test]
+        async fn bounds_new_channel_data_until_reads_switch() {
+            let (local_channel1, remote_channel1) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+            let (local_channel2, remote_channel2) = channel_mock_pair(ChannelMockOptions::default(), ChannelMockOptions::default());
+
+            let (local_channel2, bytes_read) = ReadCounter::new(local_channel2);
+
+            let max_buffered_size = 1_024;
+            let options = UpgradableChannelOptions::default()
+                .buffers(BufferOptions::default().max_buffered_size(max_buffered_size));
+
+            let (on_local_channel1, mut local_upgradable_channel1) = UpgradableChannel::new_with_options("local", local_channel1, options.clone());
+            let (on_remote_channel1, mut remote_upgradable_channel1) = UpgradableChannel::new_with_options("remote", remote_channel1, options);
+
+            // the local reads stay on the current channel until its data is read
+            let early_data = random_str_rg(1..=512);
+
+            remote_upgradable_channel1.write_all(early_data.as_bytes()).await
+                .expect("Cannot write data.");
+
+            tokio::try_join!(
+                on_local_channel1.upgrade(local_channel2),
+                on_remote_channel1.upgrade(remote_channel2),
+            ).expect("Cannot upgrade to the new channel.");
+
+            // the remote side floods the new channel before the local reads switch to it
+            let data = random_str(64 * 1_024);
+
+            let mut write = Box::pin(remote_upgradable_channel1.write_all(data.as_bytes()));
+
+            for _ in 0..10 {
+                assert!(
+                    futures::poll!(&mut write).is_pending(),
+                    "Must wait for the local side to read the data.",
+                );
+
+                assert!(
+                    bytes_read.load(Ordering::SeqCst) <= max_buffered_size,
+                    "Must not buffer more of the new channel than the max buffered size, got {} bytes.", bytes_read.load(Ordering::SeqCst),
+                );
+
+                wait(10).await;
+            }
+
+            let mut received_early_data = vec![0; early_data.len()];
+
+            local_upgradable_channel1.read_exact(&mut received_early_data).await
+                .expect("Cannot read the data.");
+
+            // the local side drains the new channel, the remote side goes on as it does
+            let read = async {
+                let mut received_data = vec![0; data.len()];
+                let mut bytes_received = 0;
+
+                while bytes_received < data.len() {
+                    bytes_received += local_upgradable_channel1.read(&mut received_data[bytes_received..]).await
+                        .expect("Cannot read the data.");
+
+                    assert!(
+                        bytes_read.load(Ordering::SeqCst).saturating_sub(bytes_received) <= max_buffered_size,
+                        "Must not read ahead of the reads by more than the max buffered size.",
+                    );
+                }
+
+                return received_data;
+            };
+
+            let (received_data, write_result) = tokio::join!(read, write);
+
+            write_result.expect("Cannot write the data.");
+
+            assert_eq!(
+                [received_early_data, received_data].concat(),
+                [early_data.as_bytes(), data.as_bytes()].concat(),
+                "Must receive the data.",
+            );
+        }
+
+        #[tokio::test]
+        async fn bounds_data_of_unpaired_upgrade() {
+            let (local_channel1, remote_channel1) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+
+            // the new channel itself would take all the data
+            let options2 = ChannelMockOptions::default().buffer_size(64 * 1_024);
+            let (local_channel2, remote_channel2) = channel_mock_pair(options2.clone(), options2);
+
+            // the receive window of the lanes does not bound the unpaired upgrade
+            let options = UpgradableChannelOptions::default()
+                .buffers(BufferOptions::default().max_unconfirmed_size(1_024).max_buffered_size(256 * 1_024));
+
+            let (on_local_channel1, mut local_upgradable_channel1) = UpgradableChannel::new_with_options("local", local_channel1, options.clone());
+            let (on_remote_channel1, mut remote_upgradable_channel1) = UpgradableChannel::new_with_options("remote", remote_channel1, options);
+
+            let local_upgrade = tokio::spawn(async move {
+                return on_local_channel1.upgrade(local_channel2).await;
+            });
+
+            local_upgradable_channel1.upgrade_state()
+                .wait_for(|state| { return state == &UpgradeState::SyncSent; }).await
+                .expect("Cannot get the upgrade state.");
+
+            let data = random_str(4 * 1_024);
+
+            let bytes_written = local_upgradable_channel1.write(data.as_bytes()).await
+                .expect("Cannot write data.");
+
+            assert_eq!(
+                bytes_written,
+                1_024,
+                "Must not keep more data for the unpaired upgrade than the max unconfirmed size.",
+            );
+
+            assert!(
+                futures::poll!(Box::pin(local_upgradable_channel1.write(&data.as_bytes()[bytes_written..]))).is_pending(),
+                "Must wait for the remote side to pair the upgrade.",
+            );
+
+            on_remote_channel1.upgrade(remote_channel2).await
+                .expect("Cannot pair the upgrade.");
+            local_upgrade.await
+                .expect("Upgrade task failed.")
+                .expect("Cannot upgrade to the new channel.");
+
+            let mut received_data = vec![0; data.len()];
+
+            tokio::try_join!(
+                local_upgradable_channel1.write_all(&data.as_bytes()[bytes_written..]),
+                remote_upgradable_channel1.read_exact(&mut received_data),
+            ).expect("Cannot transfer the data.");
+
+            assert_eq!(
+                received_data,
+                data.as_bytes(),
+                "Must receive the data.",
+            );
+        }
+
+        #[tokio::test]
+        async fn fails_if_remote_rejects_upgrade() {
+            let options1 = ChannelMockOptions::random();
+            let options2 = ChannelMockOptions::random();
+
+            let (local_channel1, remote_channel1) = channel_mock_pair(options1.clone(), options1.clone());
+            let (local_channel2, _remote_channel2) = channel_mock_pair(options2.clone(), options2.clone());
+
+            let main_channel_id = local_channel1.id();
+
+            let (on_local_channel1, local_upgradable_channel1) = UpgradableChannel::new("local", local_channel1);
+
+            // remote side has no new channel to upgrade to
+            let (_remote_main_channel, remote_control_channel) = divide_channel(remote_channel1, MultiplexerOptions::default());
+            let mut remote_control_channel = create_framed_stream::<ChannelMessage>(remote_control_channel);
+
+            let hello = ChannelMessage::Hello {
+                version: PROTOCOL_VERSION,
+                capabilities: vec![Capability::OffsetHandover.name().to_string()],
+            };
+
+            remote_control_channel.send(hello).await
+                .expect("Cannot send control message.");
+
+            let (result, _) = tokio::join!(
+                on_local_channel1.upgrade(local_channel2),
+                async {
+                    let sync_id = loop {
+                        match remote_control_channel.next().await {
+                            Some(Ok(ChannelMessage::Sync(sync_id, _, _))) => break sync_id,
+                            Some(Ok(_)) => continue,
+                            _ => panic!("Cannot get the Sync message."),
+                        };
+                    };
+
+                    remote_control_channel.send(ChannelMessage::Reject(sync_id)).await
+                        .expect("Cannot send control message.");
+                },
+            );
+
+            assert_eq!(
+                result,
+                Err(UpgradeError::Rejected),
+                "Upgrade must be rejected.",
+            );
+
+            // must keep using the main channel
+            assert!(
+                !local_upgradable_channel1.is_upgraded(),
+                "Must not be upgraded.",
+            );
+
+            assert_eq!(
+                local_upgradable_channel1.channel_id(),
+                main_channel_id,
+                "Must keep using the main channel.",
+            );
+        }
+
+        #[rstest]
+        #[case(0..=0)]
+        #[case(0..=5)]
+        #[case(5..=50)]
+        #[case(50..=100)]
+        #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+        async fn resolves_simultaneous_upgrades(
+            #[case] throttle_range: RangeInclusive<u64>,
+        ) {
+            let options1 = ChannelMockOptions::default().throttle(throttle_range.clone());
+
+            let (local_channel1, remote_channel1) = channel_mock_pair(options1.clone(), options1.clone());
+
+            let (on_local_channel1, mut local_upgradable_channel1) = UpgradableChannel::new("local", local_channel1);
+            let (on_remote_channel1, mut remote_upgradable_channel1) = UpgradableChannel::new("remote", remote_channel1);
+
+            let barrier = Arc::new(Barrier::new(2));
+            let mut bytes_written = 0;
+
+            for _ in 0..3 {
+                let options2 = ChannelMockOptions::default().throttle(throttle_range.clone());
+
+                let (local_channel2, remote_channel2) = channel_mock_pair(options2.clone(), options2.clone());
+
+                let data = random_str_rg(1..=512);
+
+                local_upgradable_channel1.write_all(data.as_bytes()).await
+                    .expect("Cannot write data before the upgrade.");
+
+                bytes_written += data.len();
+
+                // initiate the upgrade on both sides at the same instant
+                let local_upgrade = tokio::spawn({
+                    let barrier = barrier.clone();
+                    let on_local_channel1 = on_local_channel1.clone();
+
+                    async move {
+                        barrier.wait().await;
+
+                        return on_local_channel1.upgrade(local_channel2).await;
+                    }
+                });
+
+                let remote_upgrade = tokio::spawn({
+                    let barrier = barrier.clone();
+                    let on_remote_channel1 = on_remote_channel1.clone();
+
+                    async move {
+                        barrier.wait().await;
+
+                        return on_remote_channel1.upgrade(remote_channel2).await;
+                    }
+                });
+
+                let local_report = local_upgrade.await
+                    .expect("Cannot join the local upgrade.")
+                    .expect("Cannot upgrade the local side.");
+
+                let remote_report = remote_upgrade.await
+                    .expect("Cannot join the remote upgrade.")
+                    .expect("Cannot upgrade the remote side.");
+
+                assert_eq!(
+                    local_report.upgrade_id(),
+                    remote_report.upgrade_id(),
+                    "Both sides must agree on the upgrade.",
+                );
+
+                assert_eq!(
+                    local_report.local_sync_id(),
+                    remote_report.remote_sync_id(),
+                    "Must pair the local upgrade with the remote one.",
+                );
+
+                assert_eq!(
+                    remote_report.handover_offset(),
+                    bytes_written,
+                    "Must hand over after the data written before the upgrade.",
+                );
+
+                let mut received_data = vec![0; data.len()];
+
+                remote_upgradable_channel1.read_exact(&mut received_data).await
+                    .expect("Cannot read the data written before the upgrade.");
+
+                assert_eq!(
+                    received_data,
+                    data.as_bytes(),
+                    "Must receive the data written before the upgrade.",
+                );
+
+                let data = random_str_rg(1..=512);
+                let mut received_data = vec![0; data.len()];
+
+                tokio::try_join!(
+                    remote_upgradable_channel1.write_all(data.as_bytes()),
+                    local_upgradable_channel1.read_exact(&mut received_data),
+                ).expect("Cannot transfer data after the upgrade.");
+
+                assert_eq!(
+                    received_data,
+                    data.as_bytes(),
+                    "Must receive the data written after the upgrade.",
+                );
+            }
+        }
+
+        /// Upgrade the local side while the misbehaving remote side answers
+        /// the local `Sync` message with the provided control messages.
+        async fn upgrade_with_remote_replies(
+            replies: impl FnOnce(String) -> Vec<ChannelMessage>,
+        ) -> TUpgradeResult {
+            let options1 = ChannelMockOptions::random();
+            let options2 = ChannelMockOptions::random();
+
+            let (local_channel1, remote_channel1) = channel_mock_pair(options1.clone(), options1.clone());
+            let (local_channel2, _remote_channel2) = channel_mock_pair(options2.clone(), options2.clone());
+
+            let (on_local_channel1, _local_upgradable_channel1) = UpgradableChannel::new("local", local_channel1);
+
+            let (_remote_main_channel, remote_control_channel) = divide_channel(remote_channel1, MultiplexerOptions::default());
+            let mut remote_control_channel = create_framed_stream::<ChannelMessage>(remote_control_channel);
+
+            let hello = ChannelMessage::Hello {
+                version: PROTOCOL_VERSION,
+                capabilities: vec![Capability::OffsetHandover.name().to_string()],
+            };
+
+            remote_control_channel.send(hello).await
+                .expect("Cannot send control message.");
+
+            let (result, _) = tokio::join!(
+                on_local_channel1.upgrade(local_channel2),
+                async {
+                    let sync_id = loop {
+                        match remote_control_channel.next().await {
+                            Some(Ok(ChannelMessage::Sync(sync_id, _, _))) => break sync_id,
+                            Some(Ok(_)) => continue,
+                            _ => panic!("Cannot get the Sync message."),
+                        };
+                    };
+
+                    for message in replies(sync_id) {
+                        remote_control_channel.send(message).await
+                            .expect("Cannot send control message.");
+                    }
+                },
+            );
+
+            return result;
+        }
+
+        #[tokio::test]
+        async fn fails_on_reflected_sync() {
+            let result = upgrade_with_remote_replies(|sync_id| {
+                return vec![ChannelMessage::Sync(sync_id, 0, 0)];
+            }).await;
+
+            assert_eq!(
+                result,
+                Err(UpgradeError::IdMismatch("Sync".to_string())),
+                "Must not accept its own Sync message.",
+            );
+        }
+
+        #[tokio::test]
+        async fn fails_on_sync_ack_id_mismatch() {
+            let result = upgrade_with_remote_replies(|_sync_id| {
+                return vec![
+                    ChannelMessage::Sync("remote-sync-id".to_string(), 0, 0),
+                    ChannelMessage::SyncAck(random_str(32), "remote-sync-id".to_string()),
+                ];
+            }).await;
+
+            assert_eq!(
+                result,
+                Err(UpgradeError::IdMismatch("SyncAck".to_string())),
+                "Must not accept a SyncAck message of another upgrade.",
+            );
+        }
+
+        /// Upgrade both sides to a new channel and transfer data both ways.
+        async fn upgrade_and_transfer(
+            on_local_channel: &UpgradeHandle,
+            on_remote_channel: &UpgradeHandle,
+            local_channel: &mut Box<dyn TUpgradableChannel>,
+            remote_channel: &mut Box<dyn TUpgradableChannel>,
+        ) {
+            let options = ChannelMockOptions::random();
+            let (local_channel2, remote_channel2) = channel_mock_pair(options.clone(), options.clone());
+
+            tokio::try_join!(
+                on_local_channel.upgrade(local_channel2),
+                on_remote_channel.upgrade(remote_channel2),
+            ).expect("Cannot upgrade to the new channel.");
+
+            transfer(local_channel, remote_channel).await;
+            transfer(remote_channel, local_channel).await;
+        }
+
+        #[tokio::test]
+        async fn times_out_if_remote_never_upgrades() {
+            let options = UpgradableChannelOptions::default()
+                .upgrade_timeout(Duration::from_millis(100));
+
+            let options1 = ChannelMockOptions::random();
+            let options2 = ChannelMockOptions::random();
+
+            let (local_channel1, remote_channel1) = channel_mock_pair(options1.clone(), options1.clone());
+            let (local_channel2, _remote_channel2) = channel_mock_pair(options2.clone(), options2.clone());
+
+            let main_channel_id = local_channel1.id();
+
+            let (on_local_channel1, mut local_upgradable_channel1) = UpgradableChannel::new_with_options("local", local_channel1, options.clone());
+            let (on_remote_channel1, mut remote_upgradable_channel1) = UpgradableChannel::new_with_options("remote", remote_channel1, options);
+
+            let data = random_str_rg(1..=512);
+            let mut local_state = local_upgradable_channel1.upgrade_state();
+
+            let (result, _) = tokio::join!(
+                on_local_channel1.upgrade(local_channel2),
+                async {
+                    // the data written to the new channel goes to the main one once the upgrade times out
+                    local_state.wait_for(|state| { return state == &UpgradeState::SyncSent; }).await
+                        .expect("Cannot get the upgrade state.");
+
+                    local_upgradable_channel1.write_all(data.as_bytes()).await
+                        .expect("Cannot write data.");
+                },
+            );
+
+            assert_eq!(
+                result,
+                Err(UpgradeError::Timeout),
+                "Must time out if the remote side does not supply a new channel.",
+            );
+
+            let mut received_data = vec![0; data.len()];
+
+            remote_upgradable_channel1.read_exact(&mut received_data).await
+                .expect("Cannot read data.");
+
+            assert_eq!(
+                received_data,
+                data.as_bytes(),
+                "Must receive the data written during the upgrade.",
+            );
+
+            assert_eq!(
+                local_upgradable_channel1.channel_id(),
+                main_channel_id,
+                "Must keep using the main channel.",
+            );
+
+            upgrade_and_transfer(
+                &on_local_channel1,
+                &on_remote_channel1,
+                &mut local_upgradable_channel1,
+                &mut remote_upgradable_channel1,
+            ).await;
+        }
+
+        #[tokio::test]
+        async fn cancels_upgrade() {
+            let options1 = ChannelMockOptions::random();
+            let options2 = ChannelMockOptions::random();
+
+            let (local_channel1, remote_channel1) = channel_mock_pair(options1.clone(), options1.clone());
+            let (local_channel2, _remote_channel2) = channel_mock_pair(options2.clone(), options2.clone());
+
+            let (on_local_channel1, mut local_upgradable_channel1) = UpgradableChannel::new("local", local_channel1);
+            let (on_remote_channel1, mut remote_upgradable_channel1) = UpgradableChannel::new("remote", remote_channel1);
+
+            let data = random_str_rg(1..=512);
+            let mut local_state = local_upgradable_channel1.upgrade_state();
+
+            let (result, _) = tokio::join!(
+                on_local_channel1.upgrade(local_channel2),
+                async {
+                    local_state.wait_for(|state| { return state == &UpgradeState::SyncSent; }).await
+                        .expect("Cannot get the upgrade state.");
+
+                    local_upgradable_channel1.write_all(data.as_bytes()).await
+                        .expect("Cannot write data.");
+
+                    on_local_channel1.cancel_upgrade();
+                },
+            );
+
+            assert_eq!(
+                result,
+                Err(UpgradeError::Cancelled),
+                "Must cancel the upgrade.",
+            );
+
+            let mut received_data = vec![0; data.len()];
+
+            remote_upgradable_channel1.read_exact(&mut received_data).await
+                .expect("Cannot read data.");
+
+            assert_eq!(
+                received_data,
+                data.as_bytes(),
+                "Must receive the data written during the upgrade.",
+            );
+
+            upgrade_and_transfer(
+                &on_local_channel1,
+                &on_remote_channel1,
+                &mut local_upgradable_channel1,
+                &mut remote_upgradable_channel1,
+            ).await;
+        }
+
+        #[tokio::test]
+        async fn cancels_upgrade_right_after_request() {
+            let options1 = ChannelMockOptions::random();
+            let options2 = ChannelMockOptions::random();
+
+            let (local_channel1, remote_channel1) = channel_mock_pair(options1.clone(), options1.clone());
+            let (local_channel2, _remote_channel2) = channel_mock_pair(options2.clone(), options2.clone());
+
+            let (on_local_channel1, mut local_upgradable_channel1) = UpgradableChannel::new("local", local_channel1);
+            let (on_remote_channel1, mut remote_upgradable_channel1) = UpgradableChannel::new("remote", remote_channel1);
+
+            // the upgrade is requested on the first poll, before the sides agree on the protocol
+            let (result, _) = tokio::join!(
+                on_local_channel1.upgrade(local_channel2),
+                async {
+                    on_local_channel1.cancel_upgrade();
+                },
+            );
+
+            assert_eq!(
+                result,
+                Err(UpgradeError::Cancelled),
+                "Must cancel the upgrade requested right before.",
+            );
+
+            upgrade_and_transfer(
+                &on_local_channel1,
+                &on_remote_channel1,
+                &mut local_upgradable_channel1,
+                &mut remote_upgradable_channel1,
+            ).await;
+        }
+
+        #[rstest]
+        #[case(0)]
+        #[case(5)]
+        #[case(25)]
+        #[tokio::test]
+        async fn cancel_races_with_remote_upgrade(
+            #[case] remote_delay_ms: u64,
+        ) {
+            let options = UpgradableChannelOptions::default()
+                .upgrade_timeout(Duration::from_millis(250));
+
+            let options1 = ChannelMockOptions::random();
+            let options2 = ChannelMockOptions::random();
+
+            let (local_channel1, remote_channel1) = channel_mock_pair(options1.clone(), options1.clone());
+            let (local_channel2, remote_channel2) = channel_mock_pair(options2.clone(), options2.clone());
+
+            let (on_local_channel1, mut local_upgradable_channel1) = UpgradableChannel::new_with_options("local", local_channel1, options.clone());
+            let (on_remote_channel1, mut remote_upgradable_channel1) = UpgradableChannel::new_with_options("remote", remote_channel1, options);
+
+            let mut local_state = local_upgradable_channel1.upgrade_state();
+
+            let (local_result, remote_result, _) = tokio::join!(
+                on_local_channel1.upgrade(local_channel2),
+                async {
+                    wait(remote_delay_ms).await;
+
+                    return on_remote_channel1.upgrade(remote_channel2).await;
+                },
+                async {
+                    local_state.wait_for(|state| { return state == &UpgradeState::SyncSent; }).await
+                        .expect("Cannot get the upgrade state.");
+
+                    on_local_channel1.cancel_upgrade();
+                },
+            );
+
+            // either the cancellation wins or the upgrade completes on both sides
+            assert_eq!(
+                local_result.is_ok(),
+                remote_result.is_ok(),
+                "Both sides must agree on the upgrade, got: {:?}, {:?}", local_result, remote_result,
+            );
+
+            upgrade_and_transfer(
+                &on_local_channel1,
+                &on_remote_channel1,
+                &mut local_upgradable_channel1,
+                &mut remote_upgradable_channel1,
+            ).await;
+        }
+    }
+
+    mod upgradable_channel {
+        use std::{sync::atomic::Ordering, time::Duration};
+
+        use cs_utils::{random_str_rg, traits::Random, futures::{wait, wait_random}};
+        use connection_utils::{Channel, test::test_async_stream};
+        use rstest::rstest;
+
+        use crate::{mocks::{ChannelMockOptions, channel_mock_pair}, utils::PollCounter, UpgradableChannel, UpgradableChannelBuilder, UpgradableChannelOptions, TUpgradableChannel, UpgradeReport, UpgradeError, BufferOptions};
+
+        use super::fallback::transfer;
+
+        async fn upgrade_channel(
+            channel: &mut Box<dyn TUpgradableChannel>,
+            new_channel: Box<dyn Channel>,
+        ) -> Result<UpgradeReport, UpgradeError> {
+            wait_random(5..=25).await;
+
+            return channel.upgrade(new_channel).await;
+        }
+
+        #[rstest]
+        #[case(UpgradableChannelOptions::default())]
+        #[case(UpgradableChannelOptions::default().fallback(true).replay_buffer_size(1_024).resumable(true))]
+        #[tokio::test]
+        async fn does_not_poll_idle_channels(
+            #[case] options: UpgradableChannelOptions,
+        ) {
+            let (local_channel1, remote_channel1) = channel_mock_pair(ChannelMockOptions::default(), ChannelMockOptions::default());
+            let (local_channel2, remote_channel2) = channel_mock_pair(ChannelMockOptions::default(), ChannelMockOptions::default());
+
+            let (local_channel1, main_channel_polls) = PollCounter::new(local_channel1);
+            let (local_channel2, new_channel_polls) = PollCounter::new(local_channel2);
+
+            let (_on_local_channel1, mut local_upgradable_channel1) = UpgradableChannel::new_with_options("local", local_channel1, options.clone());
+            let (_on_remote_channel1, mut remote_upgradable_channel1) = UpgradableChannel::new_with_options("remote", remote_channel1, options);
+
+            tokio::try_join!(
+                upgrade_channel(&mut local_upgradable_channel1, local_channel2),
+                upgrade_channel(&mut remote_upgradable_channel1, remote_channel2),
+            ).expect("Cannot upgrade to the new channel.");
+
+            transfer(&mut local_upgradable_channel1, &mut remote_upgradable_channel1).await;
+            transfer(&mut remote_upgradable_channel1, &mut local_upgradable_channel1).await;
+
+            // let the acknowledgements settle
+            wait(100).await;
+
+            // the runtime advances the paused clock whenever it has nothing else
+            // to do, hence any busy-wait sleep would poll the channels in no time
+            tokio::time::pause();
+
+            let main_channel_polls_before = main_channel_polls.load(Ordering::SeqCst);
+            let new_channel_polls_before = new_channel_polls.load(Ordering::SeqCst);
+
+            tokio::time::sleep(Duration::from_secs(60)).await;
+
+            assert_eq!(
+                main_channel_polls.load(Ordering::SeqCst),
+                main_channel_polls_before,
+                "Must not poll the main channel while idle.",
+            );
+
+            assert_eq!(
+                new_channel_polls.load(Ordering::SeqCst),
+                new_channel_polls_before,
+                "Must not poll the new channel while idle.",
+            );
+        }
+
+        #[tokio::test]
+        async fn reports_upgrade_status() {
+            let options1 = ChannelMockOptions::random();
+
+            let (local_channel1, remote_channel1) = channel_mock_pair(options1.clone(), options1.clone());
+
+            let main_channel_id = local_channel1.id();
+            let main_channel_label = local_channel1.label().clone();
+
+            let (_on_local_channel1, mut local_upgradable_channel1) = UpgradableChannel::new("local", local_channel1);
+            let (_on_remote_channel1, mut remote_upgradable_channel1) = UpgradableChannel::new("remote", remote_channel1);
+
+            assert!(
+                !local_upgradable_channel1.is_upgraded(),
+                "Must not be upgraded initially.",
+            );
+
+            assert_eq!(
+                local_upgradable_channel1.channel_id(),
+                main_channel_id,
+                "Must report the main channel id initially.",
+            );
+
+            assert_eq!(
+                local_upgradable_channel1.channel_label(),
+                main_channel_label,
+                "Must report the main channel label initially.",
+            );
+
+            for upgrade_count in 1..=3 {
+                let options = ChannelMockOptions::random();
+                let (local_channel, remote_channel) = channel_mock_pair(options.clone(), options.clone());
+
+                let channel_id = local_channel.id();
+                let channel_label = local_channel.label().clone();
+
+                tokio::try_join!(
+                    upgrade_channel(&mut local_upgradable_channel1, local_channel),
+                    upgrade_channel(&mut remote_upgradable_channel1, remote_channel),
+                ).expect("Cannot upgrade to the new channel.");
+
+                assert!(
+                    local_upgradable_channel1.is_upgraded(),
+                    "Must be upgraded.",
+                );
+
+                assert_eq!(
+                    local_upgradable_channel1.upgrade_count(),
+                    upgrade_count,
+                    "Must report the upgrade count.",
+                );
+
+                assert_eq!(
+                    local_upgradable_channel1.channel_id(),
+                    channel_id,
+                    "Must report the new channel id.",
+                );
+
+                assert_eq!(
+                    local_upgradable_channel1.channel_label(),
+                    channel_label,
+                    "Must report the new channel label.",
+                );
+
+                assert_eq!(
+                    local_upgradable_channel1.id(),
+                    main_channel_id,
+                    "Channel id must not change.",
+                );
+            }
+
+            test_async_stream(
+                local_upgradable_channel1,
+                remote_upgradable_channel1,
+                random_str_rg(1_000..=4_096),
+            ).await;
+        }
+
+        #[rstest]
+        #[case(UpgradableChannel::builder())]
+        #[case(UpgradableChannel::builder().name("channel").upgrade_timeout(Duration::from_secs(5)))]
+        #[case(UpgradableChannel::builder().control_window_size(16))]
+        #[case(UpgradableChannel::builder().buffers(BufferOptions::default().max_buffered_size(256)).control_window_size(1))]
+        #[case(UpgradableChannel::builder().fallback(true).replay_buffer_size(1_024).resumable(true))]
+        #[tokio::test]
+        async fn upgrades_built_channel(
+            #[case] builder: UpgradableChannelBuilder,
+        ) {
+            let (local_channel1, remote_channel1) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+            let (local_channel2, remote_channel2) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+
+            let (_on_local_channel1, mut local_upgradable_channel1) = builder.clone().build(local_channel1);
+            let (_on_remote_channel1, mut remote_upgradable_channel1) = builder.build(remote_channel1);
+
+            // the data acks on the control lane outgrow its window
+            transfer(&mut local_upgradable_channel1, &mut remote_upgradable_channel1).await;
+
+            tokio::try_join!(
+                upgrade_channel(&mut local_upgradable_channel1, local_channel2),
+                upgrade_channel(&mut remote_upgradable_channel1, remote_channel2),
+            ).expect("Cannot upgrade to the new channel.");
+
+            test_async_stream(
+                local_upgradable_channel1,
+                remote_upgradable_channel1,
+                random_str_rg(1_000..=4_096),
+            ).await;
+        }
+    }
+
+    mod upgrade_state {
+        use futures::SinkExt;
+        use tokio::io::AsyncWriteExt;
+        use cs_utils::{traits::Random, futures::wait_random};
+
+        use crate::{
+            mocks::{ChannelMockOptions, channel_mock_pair},
+            interleaved_channel::divide_channel,
+            MultiplexerOptions,
+            utils::create_framed_stream,
+            channel::{ChannelMessage, PROTOCOL_VERSION},
+            UpgradableChannel,
+            UpgradeState,
+        };
+
+        fn phase_index(state: &UpgradeState) -> usize {
+            return match state {
+                UpgradeState::Idle => 0,
+                UpgradeState::ChannelReceived => 1,
+                UpgradeState::WritesUpgraded => 2,
+                UpgradeState::SyncSent => 3,
+                UpgradeState::ReadsUpgraded => 4,
+                UpgradeState::Completed => 5,
+                UpgradeState::Interrupted => 6,
+                UpgradeState::Disconnected => 7,
+                UpgradeState::Failed(_) => 8,
+            };
+        }
+
+        #[tokio::test]
+        async fn transitions_through_upgrade_phases() {
+            let options1 = ChannelMockOptions::random();
+            let options2 = ChannelMockOptions::random();
+
+            let (local_channel1, remote_channel1) = channel_mock_pair(options1.clone(), options1.clone());
+            let (local_channel2, remote_channel2) = channel_mock_pair(options2.clone(), options2.clone());
+
+            let (on_local_channel1, local_upgradable_channel1) = UpgradableChannel::new("local", local_channel1);
+            let (on_remote_channel1, _remote_upgradable_channel1) = UpgradableChannel::new("remote", remote_channel1);
+
+            let mut local_state = local_upgradable_channel1.upgrade_state();
+
+            assert_eq!(
+                *local_state.borrow(),
+                UpgradeState::Idle,
+                "Must be idle initially.",
+            );
+
+            let (states, _reports) = tokio::join!(
+                async move {
+                    let mut states = vec![];
+
+                    while local_state.changed().await.is_ok() {
+                        let state = local_state.borrow().clone();
+
+                        states.push(state.clone());
+
+                        if state == UpgradeState::Completed {
+                            break;
+                        }
+                    }
+
+                    return states;
+                },
+                async {
+                    return tokio::try_join!(
+                        on_local_channel1.upgrade(local_channel2),
+                        async {
+                            wait_random(5..=25).await;
+
+                            return on_remote_channel1.upgrade(remote_channel2).await;
+                        },
+                    ).expect("Cannot upgrade to the new channel.");
+                },
+            );
+
+            assert_eq!(
+                states.last(),
+                Some(&UpgradeState::Completed),
+                "Must complete the upgrade.",
+            );
+
+            assert!(
+                states.windows(2).all(|pair| phase_index(&pair[0]) < phase_index(&pair[1])),
+                "Must transition the phases in order, got: {:?}", states,
+            );
+        }
+
+        #[tokio::test]
+        async fn fails_on_unexpected_control_message() {
+            let options1 = ChannelMockOptions::random();
+
+            let (local_channel1, remote_channel1) = channel_mock_pair(options1.clone(), options1.clone());
+
+            let (_on_local_channel1, local_upgradable_channel1) = UpgradableChannel::new("local", local_channel1);
+
+            // misbehaving remote side
+            let (_remote_main_channel, remote_control_channel) = divide_channel(remote_channel1, MultiplexerOptions::default());
+            let mut remote_control_channel = create_framed_stream::<ChannelMessage>(remote_control_channel);
+
+            remote_control_channel.send(ChannelMessage::Hello { version: PROTOCOL_VERSION, capabilities: vec!["offset-handover".to_string()] }).await
+                .expect("Cannot send control message.");
+            remote_control_channel.send(ChannelMessage::SyncAck("a".to_string(), "b".to_string())).await
+                .expect("Cannot send control message.");
+
+            let mut local_state = local_upgradable_channel1.upgrade_state();
+
+            let state = local_state.wait_for(|state| matches!(state, UpgradeState::Failed(_))).await
+                .expect("Cannot get the upgrade state.")
+                .clone();
+
+            assert_eq!(
+                state,
+                UpgradeState::Failed("Unexpected SyncAck message.".to_string()),
+                "Must fail with the protocol error.",
+            );
+        }
+
+        #[tokio::test]
+        async fn fails_on_malformed_control_message() {
+            let options1 = ChannelMockOptions::random();
+
+            let (local_channel1, remote_channel1) = channel_mock_pair(options1.clone(), options1.clone());
+
+            let (_on_local_channel1, local_upgradable_channel1) = UpgradableChannel::new("local", local_channel1);
+
+            let (_remote_main_channel, mut remote_control_channel) = divide_channel(remote_channel1, MultiplexerOptions::default());
+
+            // a length delimited frame with a payload that is not a message
+            remote_control_channel.write_all(&[0, 0, 0, 4, b'{', b'}', b'{', b'}']).await
+                .expect("Cannot write data.");
+
+            let mut local_state = local_upgradable_channel1.upgrade_state();
+
+            let state = local_state.wait_for(|state| matches!(state, UpgradeState::Failed(_))).await
+                .expect("Cannot get the upgrade state.")
+                .clone();
+
+            assert!(
+                matches!(&state, UpgradeState::Failed(error) if error.starts_with("Cannot decode message:")),
+                "Must fail with the decode error, got: {:?}", state,
+            );
+        }
+    }
+
+    mod negotiation {
+        use std::time::Duration;
+
+        use futures::SinkExt;
+        use rstest::rstest;
+        use cs_utils::{random_str_rg, traits::Random};
+        use tokio::{io::{AsyncReadExt, AsyncWriteExt}, time::timeout};
+
+        use crate::{
+            mocks::{ChannelMockOptions, channel_mock_pair},
+            interleaved_channel::divide_channel,
+            MultiplexerOptions,
+            utils::create_framed_stream,
+            channel::{ChannelMessage, Capability, PROTOCOL_VERSION},
+            UpgradableChannel,
+            UpgradableChannelOptions,
+            UpgradeState,
+            UpgradeError,
+            TUpgradableChannel,
+        };
+
+        fn hello(
+            version: u16,
+            capabilities: &[&str],
+        ) -> ChannelMessage {
+            let capabilities = capabilities.iter()
+                .map(|capability| { return capability.to_string(); })
+                .collect();
+
+            return ChannelMessage::Hello { version, capabilities };
+        }
+
+        async fn negotiated_capabilities(channel: &dyn TUpgradableChannel) -> Vec<Capability> {
+            let mut capabilities = channel.capabilities();
+
+            return capabilities.wait_for(|capabilities| capabilities.is_some()).await
+                .expect("Cannot get the capabilities.")
+                .clone()
+                .expect("Capabilities must be negotiated.");
+        }
+
+        #[tokio::test]
+        async fn uses_capabilities_supported_by_both_sides() {
+            let options1 = ChannelMockOptions::random();
+
+            let (local_channel1, remote_channel1) = channel_mock_pair(options1.clone(), options1.clone());
+
+            let local_options = UpgradableChannelOptions::default()
+                .fallback(true)
+                .replay_buffer_size(16);
+
+            let (_on_local_channel1, mut local_upgradable_channel1) = UpgradableChannel::new_with_options("local", local_channel1, local_options);
+            let (_on_remote_channel1, mut remote_upgradable_channel1) = UpgradableChannel::new("remote", remote_channel1);
+
+            let expected_capabilities = vec![Capability::OffsetHandover, Capability::RepeatedUpgrades, Capability::Close, Capability::EndOfStream];
+
+            assert_eq!(
+                negotiated_capabilities(local_upgradable_channel1.as_ref()).await,
+                expected_capabilities,
+                "Local side must use the common capabilities only.",
+            );
+
+            assert_eq!(
+                negotiated_capabilities(remote_upgradable_channel1.as_ref()).await,
+                expected_capabilities,
+                "Remote side must use the common capabilities only.",
+            );
+
+            // the remote side never acknowledges the data, hence the
+            // replay buffer must not limit the `writes` anymore
+            let data = random_str_rg(64..=128);
+
+            let received_data = tokio::join!(
+                async {
+                    local_upgradable_channel1.write_all(data.as_bytes()).await
+                        .expect("Cannot write data.");
+                },
+                async {
+                    let mut received_data = vec![0; data.len()];
+
+                    remote_upgradable_channel1.read_exact(&mut received_data).await
+                        .expect("Cannot read data.");
+
+                    return received_data;
+                },
+            ).1;
+
+            assert_eq!(
+                String::from_utf8(received_data).unwrap(),
+                data,
+                "Must receive the data.",
+            );
+        }
+
+        #[tokio::test]
+        async fn accepts_newer_remote_side() {
+            let options1 = ChannelMockOptions::random();
+
+            let (local_channel1, remote_channel1) = channel_mock_pair(options1.clone(), options1.clone());
+
+            let local_options = UpgradableChannelOptions::default()
+                .resumable(true);
+
+            let (_on_local_channel1, local_upgradable_channel1) = UpgradableChannel::new_with_options("local", local_channel1, local_options);
+
+            let (_remote_main_channel, remote_control_channel) = divide_channel(remote_channel1, MultiplexerOptions::default());
+            let mut remote_control_channel = create_framed_stream::<ChannelMessage>(remote_control_channel);
+
+            remote_control_channel.send(hello(PROTOCOL_VERSION + 1, &["offset-handover", "compression", "resume"])).await
+                .expect("Cannot send control message.");
+
+            assert_eq!(
+                negotiated_capabilities(local_upgradable_channel1.as_ref()).await,
+                vec![Capability::OffsetHandover, Capability::Resume],
+                "Must ignore the unknown capabilities.",
+            );
+        }
+
+        #[tokio::test]
+        async fn fails_upgrades_if_remote_side_is_silent() {
+            let options1 = ChannelMockOptions::random();
+            let options2 = ChannelMockOptions::random();
+
+            let (local_channel1, remote_channel1) = channel_mock_pair(options1.clone(), options1.clone());
+
+            // the upgrades themselves never time out
+            let local_options = UpgradableChannelOptions::default()
+                .handshake_timeout(Duration::from_millis(200));
+
+            let (on_local_channel1, _local_upgradable_channel1) = UpgradableChannel::new_with_options("local", local_channel1, local_options);
+
+            // the remote side never sends the `Hello` message
+            let (_remote_main_channel, _remote_control_channel) = divide_channel(remote_channel1, MultiplexerOptions::default());
+
+            let (local_channel2, _remote_channel2) = channel_mock_pair(options2.clone(), options2.clone());
+
+            let result = timeout(Duration::from_secs(3), on_local_channel1.upgrade(local_channel2)).await
+                .expect("Must not wait for the remote side forever.");
+
+            assert_eq!(
+                result,
+                Err(UpgradeError::HandshakeTimeout),
+                "Must fail the upgrade requested before the handshake deadline.",
+            );
+
+            let (local_channel3, _remote_channel3) = channel_mock_pair(options2.clone(), options2.clone());
+
+            let result = timeout(Duration::from_secs(3), on_local_channel1.upgrade(local_channel3)).await
+                .expect("Must not wait for the remote side forever.");
+
+            assert_eq!(
+                result,
+                Err(UpgradeError::HandshakeTimeout),
+                "Must fail the upgrade requested after the handshake deadline.",
+            );
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn fails_upgrades_if_remote_side_is_silent_by_default() {
+            let (local_channel1, remote_channel1) = channel_mock_pair(ChannelMockOptions::default(), ChannelMockOptions::default());
+
+            let (on_local_channel1, _local_upgradable_channel1) = UpgradableChannel::new("local", local_channel1);
+
+            // the remote side never sends the `Hello` message
+            let (_remote_main_channel, _remote_control_channel) = divide_channel(remote_channel1, MultiplexerOptions::default());
+
+            let (local_channel2, _remote_channel2) = channel_mock_pair(ChannelMockOptions::default(), ChannelMockOptions::default());
+
+            // the paused clock jumps to the default handshake deadline once the channels are idle
+            let result = timeout(Duration::from_secs(60), on_local_channel1.upgrade(local_channel2)).await
+                .expect("Must not wait for the remote side forever.");
+
+            assert_eq!(
+                result,
+                Err(UpgradeError::HandshakeTimeout),
+                "Must fail the upgrade without a configured timeout.",
+            );
+        }
+
+        #[tokio::test]
+        async fn fails_on_json_framing_remote_side() {
+            let options1 = ChannelMockOptions::random();
+
+            let (local_channel1, mut remote_channel1) = channel_mock_pair(options1.clone(), options1.clone());
+
+            let (_on_local_channel1, local_upgradable_channel1) = UpgradableChannel::new("local", local_channel1);
+
+            // the remote side without the `Hello` message frames the lanes with length delimited JSON
+            let frame = br#"{"Channel2":[123,125]}"#;
+
+            remote_channel1.write_all(&(frame.len() as u32).to_be_bytes()).await
+                .expect("Cannot write data.");
+            remote_channel1.write_all(frame).await
+                .expect("Cannot write data.");
+
+            let mut local_state = local_upgradable_channel1.upgrade_state();
+
+            let state = timeout(Duration::from_secs(3), local_state.wait_for(|state| matches!(state, UpgradeState::Failed(_)))).await
+                .expect("Must fail the upgrades.")
+                .expect("Cannot get the upgrade state.")
+                .clone();
+
+            assert_eq!(
+                state,
+                UpgradeState::Failed("Unsupported protocol version 0.".to_string()),
+                "Must fail with the version error.",
+            );
+        }
+
+        #[rstest]
+        #[case(hello(0, &["offset-handover"]), "Unsupported protocol version 0.")]
+        #[case(ChannelMessage::Sync("a".to_string(), 0, 0), "Unsupported protocol version 0.")]
+        #[case(hello(PROTOCOL_VERSION, &["repeated-upgrades", "replay"]), "The remote side does not support offset-handover.")]
+        #[tokio::test]
+        async fn fails_on_incompatible_remote_side(
+            #[case] message: ChannelMessage,
+            #[case] expected_error: &str,
+        ) {
+            let options1 = ChannelMockOptions::random();
+
+            let (local_channel1, remote_channel1) = channel_mock_pair(options1.clone(), options1.clone());
+
+            let (_on_local_channel1, local_upgradable_channel1) = UpgradableChannel::new("local", local_channel1);
+
+            let (_remote_main_channel, remote_control_channel) = divide_channel(remote_channel1, MultiplexerOptions::default());
+            let mut remote_control_channel = create_framed_stream::<ChannelMessage>(remote_control_channel);
+
+            remote_control_channel.send(message).await
+                .expect("Cannot send control message.");
+
+            let mut local_state = local_upgradable_channel1.upgrade_state();
+
+            let state = local_state.wait_for(|state| matches!(state, UpgradeState::Failed(_))).await
+                .expect("Cannot get the upgrade state.")
+                .clone();
+
+            assert_eq!(
+                state,
+                UpgradeState::Failed(expected_error.to_string()),
+                "Must fail with the negotiation error.",
+            );
+
+            assert!(
+                local_upgradable_channel1.capabilities().borrow().is_none(),
+                "Must not agree on any capabilities.",
+            );
+        }
+    }
+
+    mod fallback {
+        use std::time::Duration;
+
+        use connection_utils::Channel;
+        use cs_utils::{random_str_rg, traits::Random, futures::wait_random};
+        use tokio::{io::{AsyncReadExt, AsyncWriteExt, copy_bidirectional}, task::JoinHandle, time::timeout};
+
+        use crate::{
+            mocks::{ChannelMockOptions, channel_mock_pair},
+            UpgradableChannel,
+            UpgradableChannelOptions,
+            TUpgradableChannel,
+        };
+
+        /// Create a channel pair connected through a proxy task,
+        /// aborting the task breaks the channels.
+        pub fn proxied_channel_mock_pair(
+            options: ChannelMockOptions,
+        ) -> (Box<dyn Channel>, Box<dyn Channel>, JoinHandle<()>) {
+            let (local_channel, mut local_proxy) = channel_mock_pair(options.clone(), options.clone());
+            let (mut remote_proxy, remote_channel) = channel_mock_pair(options.clone(), options.clone());
+
+            let proxy = tokio::spawn(async move {
+                let _res = copy_bidirectional(&mut local_proxy, &mut remote_proxy).await;
+            });
+
+            return (local_channel, remote_channel, proxy);
+        }
+
+        pub async fn transfer(
+            from: &mut Box<dyn TUpgradableChannel>,
+            to: &mut Box<dyn TUpgradableChannel>,
+        ) {
+            let data = random_str_rg(1..=1_024);
+
+            from.write_all(data.as_bytes()).await
+                .expect("Cannot write data.");
+
+            let mut received_data = vec![0; data.len()];
+
+            to.read_exact(&mut received_data).await
+                .expect("Cannot read data.");
+
+            assert_eq!(
+                received_data,
+                data.as_bytes(),
+                "Must receive the sent data.",
+            );
+        }
+
+        /// Shut the `writes` of the `from` channel down, the `to` channel
+        /// must get the data written before and EOF, not a failure.
+        pub async fn shut_down(
+            from: &mut Box<dyn TUpgradableChannel>,
+            to: &mut Box<dyn TUpgradableChannel>,
+        ) {
+            let data = random_str_rg(1..=1_024);
+
+            from.write_all(data.as_bytes()).await
+                .expect("Cannot write data.");
+            from.shutdown().await
+                .expect("Cannot shut the writes down.");
+
+            let mut received_data = vec![];
+
+            timeout(Duration::from_secs(3), to.read_to_end(&mut received_data)).await
+                .expect("Must get EOF once the remote side shuts its writes down.")
+                .expect("Cannot read data.");
+
+            assert_eq!(
+                received_data,
+                data.as_bytes(),
+                "Must receive the data written before the shutdown.",
+            );
+        }
+
+        pub async fn create_upgraded_pair(
+            options: UpgradableChannelOptions,
+        ) -> (Box<dyn TUpgradableChannel>, Box<dyn TUpgradableChannel>, JoinHandle<()>) {
+            let options1 = ChannelMockOptions::random();
+
+            let (local_channel1, remote_channel1) = channel_mock_pair(options1.clone(), options1.clone());
+            let (local_channel2, remote_channel2, proxy) = proxied_channel_mock_pair(ChannelMockOptions::random());
+
+            let (on_local_channel1, local_upgradable_channel1) = UpgradableChannel::new_with_options("local", local_channel1, options.clone());
+            let (on_remote_channel1, remote_upgradable_channel1) = UpgradableChannel::new_with_options("remote", remote_channel1, options);
+
+            tokio::try_join!(
+                on_local_channel1.upgrade(local_channel2),
+                async {
+                    wait_random(5..=25).await;
+
+                    return on_remote_channel1.upgrade(remote_channel2).await;
+                },
+            ).expect("Cannot upgrade to the new channel.");
+
+            return (local_upgradable_channel1, remote_upgradable_channel1, proxy);
+        }
+
+        #[tokio::test]
+        async fn falls_back_to_main_channel_on_failure() {
+            let (
+                mut local_channel,
+                mut remote_channel,
+                proxy,
+            ) = create_upgraded_pair(UpgradableChannelOptions::default().fallback(true)).await;
+
+            transfer(&mut local_channel, &mut remote_channel).await;
+            transfer(&mut remote_channel, &mut local_channel).await;
+
+            // break the upgraded channel
+            proxy.abort();
+            let _res = proxy.await;
+
+            transfer(&mut local_channel, &mut remote_channel).await;
+            transfer(&mut remote_channel, &mut local_channel).await;
+
+            assert_eq!(
+                local_channel.channel_id(),
+                local_channel.id(),
+                "Must fall back to the main channel.",
+            );
+
+            assert_eq!(
+                remote_channel.channel_id(),
+                remote_channel.id(),
+                "Must fall back to the main channel.",
+            );
+
+            assert_eq!(
+                local_channel.upgrade_count(),
+                1,
+                "Must keep the upgrade count.",
+            );
+        }
+
+        #[tokio::test]
+        async fn ends_stream_on_shutdown_after_upgrade() {
+            let (
+                mut local_channel,
+                mut remote_channel,
+                _proxy,
+            ) = create_upgraded_pair(UpgradableChannelOptions::default().fallback(true)).await;
+
+            shut_down(&mut local_channel, &mut remote_channel).await;
+
+            // the other direction is still open
+            transfer(&mut remote_channel, &mut local_channel).await;
+
+            assert_ne!(
+                remote_channel.channel_id(),
+                remote_channel.id(),
+                "Must not fall back to the main channel.",
+            );
+        }
+
+        #[tokio::test]
+        async fn fails_without_fallback() {
+            let (
+                mut local_channel,
+                mut remote_channel,
+                proxy,
+            ) = create_upgraded_pair(UpgradableChannelOptions::default()).await;
+
+            transfer(&mut local_channel, &mut remote_channel).await;
+
+            // break the upgraded channel
+            proxy.abort();
+            let _res = proxy.await;
+
+            let mut buf = [0; 1024];
+
+            let bytes_read = remote_channel.read(&mut buf).await
+                .expect("Cannot read data.");
+
+            assert_eq!(
+                bytes_read,
+                0,
+                "Must reach EOF of the failed channel.",
+            );
+
+            assert!(
+                local_channel.write_all(b"data").await.is_err(),
+                "Must fail to write to the failed channel.",
+            );
+        }
+    }
+
+    mod replay {
+        use cs_utils::{random_str_rg, traits::Random, futures::wait_random};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use super::fallback::{create_upgraded_pair, shut_down};
+        use crate::{mocks::{ChannelMockOptions, channel_mock_pair}, UpgradableChannel, UpgradableChannelOptions, UpgradeState};
+
+        #[tokio::test]
+        async fn replays_unacknowledged_data_on_new_channel() {
+            let (
+                mut local_channel,
+                mut remote_channel,
+                proxy,
+            ) = create_upgraded_pair(UpgradableChannelOptions::default().replay_buffer_size(64 * 1_024)).await;
+
+            let data_before_failure = random_str_rg(1_024..=2_048);
+            let data_after_failure = random_str_rg(1..=2_048);
+
+            local_channel.write_all(data_before_failure.as_bytes()).await
+                .expect("Cannot write data before the failure.");
+
+            // break the upgraded channel, the data in flight is lost
+            proxy.abort();
+            let _res = proxy.await;
+
+            // the local side finds the channel failed once it writes, it replays
+            // the unacknowledged data on the next channel only if it has failed
+            let mut local_state = local_channel.upgrade_state();
+
+            tokio::select! {
+                _ = local_channel.write_all(data_after_failure.as_bytes()) => panic!("Must wait for a new channel."),
+                result = local_state.wait_for(|state| { return state == &UpgradeState::Interrupted; }) => {
+                    result.expect("Cannot get the upgrade state.");
+                },
+            };
+
+            let options3 = ChannelMockOptions::random();
+            let (local_channel3, remote_channel3) = channel_mock_pair(options3.clone(), options3.clone());
+
+            tokio::try_join!(
+                local_channel.upgrade(local_channel3),
+                async {
+                    wait_random(5..=25).await;
+
+                    return remote_channel.upgrade(remote_channel3).await;
+                },
+            ).expect("Cannot upgrade to the new channel.");
+
+            let mut received_data = vec![0; data_before_failure.len() + data_after_failure.len()];
+
+            tokio::try_join!(
+                local_channel.write_all(data_after_failure.as_bytes()),
+                remote_channel.read_exact(&mut received_data),
+            ).expect("Cannot transfer the data after the failure.");
+
+            assert_eq!(
+                received_data,
+                format!("{}{}", data_before_failure, data_after_failure).as_bytes(),
+                "Must receive all the data exactly once.",
+            );
+        }
+
+        #[tokio::test]
+        async fn replays_unacknowledged_data_on_fallback() {
+            let options = UpgradableChannelOptions::default()
+                .fallback(true)
+                .replay_buffer_size(64 * 1_024);
+
+            let (
+                mut local_channel,
+                mut remote_channel,
+                proxy,
+            ) = create_upgraded_pair(options).await;
+
+            let data_before_failure = random_str_rg(1_024..=2_048);
+            let data_after_failure = random_str_rg(1..=2_048);
+
+            local_channel.write_all(data_before_failure.as_bytes()).await
+                .expect("Cannot write data before the failure.");
+
+            // break the upgraded channel, the data in flight is lost
+            proxy.abort();
+            let _res = proxy.await;
+
+            let mut received_data = vec![0; data_before_failure.len() + data_after_failure.len()];
+
+            tokio::try_join!(
+                local_channel.write_all(data_after_failure.as_bytes()),
+                remote_channel.read_exact(&mut received_data),
+            ).expect("Cannot transfer the data after the failure.");
+
+            assert_eq!(
+                received_data,
+                format!("{}{}", data_before_failure, data_after_failure).as_bytes(),
+                "Must receive all the data exactly once.",
+            );
+
+            assert_eq!(
+                local_channel.channel_id(),
+                local_channel.id(),
+                "Must fall back to the main channel.",
+            );
+        }
+
+        #[tokio::test]
+        async fn ends_stream_on_shutdown() {
+            let options = ChannelMockOptions::random();
+            let (local_channel, remote_channel) = channel_mock_pair(options.clone(), options.clone());
+
+            let options = UpgradableChannelOptions::default().replay_buffer_size(1_024);
+
+            let (_, mut local_channel) = UpgradableChannel::new_with_options("local", local_channel, options.clone());
+            let (_, mut remote_channel) = UpgradableChannel::new_with_options("remote", remote_channel, options);
+
+            shut_down(&mut local_channel, &mut remote_channel).await;
+        }
+
+        #[tokio::test]
+        async fn waits_for_acknowledgement_if_buffer_is_full() {
+            let (
+                mut local_channel,
+                mut remote_channel,
+                _proxy,
+            ) = create_upgraded_pair(UpgradableChannelOptions::default().replay_buffer_size(16)).await;
+
+            let data = random_str_rg(64..=128);
+
+            let bytes_written = local_channel.write(data.as_bytes()).await
+                .expect("Cannot write data.");
+
+            assert!(
+                bytes_written <= 16,
+                "Must not write more than the replay buffer size.",
+            );
+
+            let mut received_data = vec![0; data.len()];
+
+            tokio::try_join!(
+                local_channel.write_all(&data.as_bytes()[bytes_written..]),
+                remote_channel.read_exact(&mut received_data),
+            ).expect("Cannot transfer the data.");
+
+            assert_eq!(
+                received_data,
+                data.as_bytes(),
+                "Must receive the data.",
+            );
+        }
+    }
+
+    mod resume {
+        use cs_utils::{random_str_rg, traits::Random, futures::wait_random};
+        use tokio::{io::{AsyncReadExt, AsyncWriteExt}, task::JoinHandle};
+
+        use super::fallback::{proxied_channel_mock_pair, shut_down};
+        use crate::{
+            mocks::{ChannelMockOptions, channel_mock_pair},
+            UpgradableChannel,
+            UpgradableChannelOptions,
+            TUpgradableChannel,
+            UpgradeState,
+            UpgradeError,
+            UpgradeReport,
+            ResumptionToken,
+        };
+
+        fn create_resumable_pair() -> (Box<dyn TUpgradableChannel>, Box<dyn TUpgradableChannel>, JoinHandle<()>) {
+            let options = UpgradableChannelOptions::default()
+                .resumable(true)
+                .replay_buffer_size(64 * 1_024);
+
+            let (local_channel1, remote_channel1, proxy) = proxied_channel_mock_pair(ChannelMockOptions::random());
+
+            let (_, local_channel) = UpgradableChannel::new_with_options("local", local_channel1, options.clone());
+            let (_, remote_channel) = UpgradableChannel::new_with_options("remote", remote_channel1, options);
+
+            return (local_channel, remote_channel, proxy);
+        }
+
+        async fn wait_disconnected(channel: &dyn TUpgradableChannel) {
+            channel.upgrade_state()
+                .wait_for(|state| { return state == &UpgradeState::Disconnected; }).await
+                .expect("Cannot get the upgrade state.");
+        }
+
+        async fn get_tokens(
+            local_channel: &dyn TUpgradableChannel,
+            remote_channel: &dyn TUpgradableChannel,
+        ) -> (ResumptionToken, ResumptionToken) {
+            return tokio::try_join!(
+                local_channel.resumption_token(),
+                remote_channel.resumption_token(),
+            ).expect("Cannot get the resumption tokens.");
+        }
+
+        async fn resume(
+            local_token: &ResumptionToken,
+            remote_token: &ResumptionToken,
+        ) -> (u16, Result<(UpgradeReport, UpgradeReport), UpgradeError>) {
+            let options = ChannelMockOptions::random();
+            let (local_channel, remote_channel) = channel_mock_pair(options.clone(), options.clone());
+
+            let local_channel_id = local_channel.id();
+
+            let result = tokio::try_join!(
+                UpgradableChannel::resume(local_token, local_channel),
+                async {
+                    wait_random(5..=25).await;
+
+                    return UpgradableChannel::resume(remote_token, remote_channel).await;
+                },
+            );
+
+            return (local_channel_id, result);
+        }
+
+        #[tokio::test]
+        async fn resumes_on_new_transport_after_connection_loss() {
+            let (
+                mut local_channel,
+                mut remote_channel,
+                proxy,
+            ) = create_resumable_pair();
+
+            let (local_token, remote_token) = get_tokens(local_channel.as_ref(), remote_channel.as_ref()).await;
+
+            let data_before_loss = random_str_rg(1_024..=2_048);
+            let data_after_loss = random_str_rg(1..=2_048);
+
+            local_channel.write_all(data_before_loss.as_bytes()).await
+                .expect("Cannot write data before the connection loss.");
+
+            // break both the main and the control channels, the data in flight is lost
+            proxy.abort();
+            let _res = proxy.await;
+
+            wait_disconnected(local_channel.as_ref()).await;
+            wait_disconnected(remote_channel.as_ref()).await;
+
+            let mut received_data = vec![0; data_before_loss.len()];
+
+            // the replayed data is read concurrently, as it might not fit
+            // the transport buffers and hold back the control messages
+            let ((new_channel_id, result), read_result) = tokio::join!(
+                resume(&local_token, &remote_token),
+                remote_channel.read_exact(&mut received_data),
+            );
+
+            result.expect("Cannot resume the session.");
+            read_result.expect("Cannot read the replayed data.");
+
+            assert_eq!(
+                local_channel.channel_id(),
+                new_channel_id,
+                "Must switch to the new transport.",
+            );
+
+            let mut received_data_after_loss = vec![0; data_after_loss.len()];
+
+            tokio::try_join!(
+                local_channel.write_all(data_after_loss.as_bytes()),
+                remote_channel.read_exact(&mut received_data_after_loss),
+            ).expect("Cannot transfer the data after the resumption.");
+
+            received_data.extend(received_data_after_loss);
+
+            assert_eq!(
+                received_data,
+                format!("{}{}", data_before_loss, data_after_loss).as_bytes(),
+                "Must receive all the data exactly once.",
+            );
+
+            let data = random_str_rg(1..=1_024);
+            let mut received_data = vec![0; data.len()];
+
+            tokio::try_join!(
+                remote_channel.write_all(data.as_bytes()),
+                local_channel.read_exact(&mut received_data),
+            ).expect("Cannot transfer the data back.");
+
+            assert_eq!(
+                received_data,
+                data.as_bytes(),
+                "Must receive the data.",
+            );
+        }
+
+        #[tokio::test]
+        async fn ends_stream_on_shutdown() {
+            let options = ChannelMockOptions::random();
+            let (local_channel, remote_channel) = channel_mock_pair(options.clone(), options.clone());
+
+            let options = UpgradableChannelOptions::default().resumable(true);
+
+            let (_, mut local_channel) = UpgradableChannel::new_with_options("local", local_channel, options.clone());
+            let (_, mut remote_channel) = UpgradableChannel::new_with_options("remote", remote_channel, options);
+
+            shut_down(&mut local_channel, &mut remote_channel).await;
+        }
+
+        #[tokio::test]
+        async fn fails_to_resume_foreign_session() {
+            let (
+                mut local_channel,
+                mut remote_channel,
+                proxy,
+            ) = create_resumable_pair();
+
+            let (
+                other_local_channel,
+                other_remote_channel,
+                other_proxy,
+            ) = create_resumable_pair();
+
+            let (local_token, remote_token) = get_tokens(local_channel.as_ref(), remote_channel.as_ref()).await;
+            let (_, other_remote_token) = get_tokens(other_local_channel.as_ref(), other_remote_channel.as_ref()).await;
+
+            proxy.abort();
+            other_proxy.abort();
+
+            wait_disconnected(local_channel.as_ref()).await;
+            wait_disconnected(other_remote_channel.as_ref()).await;
+
+            let (_, result) = resume(&local_token, &other_remote_token).await;
+
+            assert_eq!(
+                result.err(),
+                Some(UpgradeError::SessionMismatch),
+                "Must not resume a foreign session.",
+            );
+
+            // the session can still be resumed by its owner
+            wait_disconnected(local_channel.as_ref()).await;
+            wait_disconnected(remote_channel.as_ref()).await;
+
+            let (_, result) = resume(&local_token, &remote_token).await;
+
+            result.expect("Cannot resume the session.");
+
+            let data = random_str_rg(1..=1_024);
+            let mut received_data = vec![0; data.len()];
+
+            tokio::try_join!(
+                local_channel.write_all(data.as_bytes()),
+                remote_channel.read_exact(&mut received_data),
+            ).expect("Cannot transfer the data.");
+
+            assert_eq!(
+                received_data,
+                data.as_bytes(),
+                "Must receive the data.",
+            );
+        }
+    }
+
+    mod trace {
+        use std::{env, fs};
+
+        use cs_trace::{create_trace, create_trace_listener, TraceListenerOptions, SubscriberInitExt};
+        use cs_utils::{random_str, traits::Random};
+        use rstest::rstest;
+
+        use crate::{mocks::{ChannelMockOptions, channel_mock_pair}, UpgradableChannel, UpgradableChannelOptions, TraceSink};
+
+        use super::fallback::transfer;
+
+        #[rstest]
+        #[case(true)]
+        #[case(false)]
+        #[tokio::test]
+        async fn traces_to_injected_sink(
+            #[case] is_traced: bool,
+        ) {
+            let trace_file_path = env::temp_dir()
+                .join(format!("upgradable-channel-{}.log", random_str(16)));
+            let trace_file_path = trace_file_path.to_str()
+                .expect("Cannot get the trace file path.");
+
+            // the tasks of the test runtime run on this thread
+            let _subscriber = create_trace_listener(
+                TraceListenerOptions::new()
+                    .with_stdout(false)
+                    // only the traces of the channels, not of their dependencies
+                    .with_env_filter("cs_trace=trace,upgradable_channel=trace")
+                    .with_file_path(trace_file_path, true),
+            ).set_default();
+
+            let options = match is_traced {
+                true => UpgradableChannelOptions::default().trace(TraceSink::new(create_trace!("test"))),
+                false => UpgradableChannelOptions::default(),
+            };
+
+            let (local_channel1, remote_channel1) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+            let (local_channel2, remote_channel2) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+
+            let (on_local_channel1, mut local_upgradable_channel1) = UpgradableChannel::new_with_options("local", local_channel1, options);
+            let (on_remote_channel1, mut remote_upgradable_channel1) = UpgradableChannel::new("remote", remote_channel1);
+
+            tokio::try_join!(
+                on_local_channel1.upgrade(local_channel2),
+                on_remote_channel1.upgrade(remote_channel2),
+            ).expect("Cannot upgrade to the new channel.");
+
+            transfer(&mut local_upgradable_channel1, &mut remote_upgradable_channel1).await;
+
+            let traces = fs::read_to_string(trace_file_path)
+                .expect("Cannot read the trace file.");
+
+            let _res = fs::remove_file(trace_file_path);
+
+            if !is_traced {
+                assert!(
+                    traces.is_empty(),
+                    "Must trace nothing by default: {}",
+                    traces,
+                );
+
+                return;
+            }
+
+            for trace in ["upgradable_channel{name=local", "upgrade{sync_id=", "upgrade completed", "bytes to the channel"] {
+                assert!(
+                    traces.contains(trace),
+                    "Must trace the \"{}\".",
+                    trace,
+                );
+            }
+
+            assert!(
+                !traces.contains("name=remote"),
+                "Must not trace the channel without the sink.",
+            );
+        }
+    }
+
+    mod spawner {
+        use std::sync::atomic::Ordering;
+
+        use connection_utils::test::test_async_stream;
+        use cs_utils::{random_str_rg, traits::Random};
+        use rstest::rstest;
+        use tokio::{runtime::Builder, task::LocalSet};
+
+        use crate::{mocks::{ChannelMockOptions, channel_mock_pair}, utils::TaskCounter, UpgradableChannel, UpgradableChannelBuilder, Spawner};
+
+        use super::fallback::transfer;
+
+        #[rstest]
+        #[case(UpgradableChannel::builder())]
+        #[case(UpgradableChannel::builder().fallback(true).replay_buffer_size(1_024).resumable(true))]
+        #[tokio::test]
+        async fn upgrades_on_local_set(
+            #[case] builder: UpgradableChannelBuilder,
+        ) {
+            let builder = builder.spawner(Spawner::local());
+
+            LocalSet::new().run_until(async move {
+                let (local_channel1, remote_channel1) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+                let (local_channel2, remote_channel2) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+
+                let (on_local_channel1, local_upgradable_channel1) = builder.clone().name("local").build(local_channel1);
+                let (on_remote_channel1, remote_upgradable_channel1) = builder.name("remote").build(remote_channel1);
+
+                tokio::try_join!(
+                    on_local_channel1.upgrade(local_channel2),
+                    on_remote_channel1.upgrade(remote_channel2),
+                ).expect("Cannot upgrade to the new channel.");
+
+                test_async_stream(
+                    local_upgradable_channel1,
+                    remote_upgradable_channel1,
+                    random_str_rg(1_000..=4_096),
+                ).await;
+            }).await;
+        }
+
+        #[test]
+        fn creates_channel_outside_of_runtime() {
+            let runtime = Builder::new_multi_thread()
+                .worker_threads(2)
+                .enable_all()
+                .build()
+                .expect("Cannot create runtime.");
+
+            let builder = UpgradableChannel::builder()
+                .spawner(Spawner::runtime(runtime.handle().clone()));
+
+            let (local_channel1, remote_channel1) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+
+            let (_on_local_channel1, mut local_upgradable_channel1) = builder.clone().name("local").build(local_channel1);
+            let (_on_remote_channel1, mut remote_upgradable_channel1) = builder.name("remote").build(remote_channel1);
+
+            runtime.block_on(async {
+                transfer(&mut local_upgradable_channel1, &mut remote_upgradable_channel1).await;
+                transfer(&mut remote_upgradable_channel1, &mut local_upgradable_channel1).await;
+            });
+        }
+
+        #[tokio::test]
+        async fn spawns_with_custom_spawner() {
+            let (spawner, task_count) = TaskCounter::new();
+
+            let builder = UpgradableChannel::builder()
+                .spawner(spawner);
+
+            let (local_channel1, remote_channel1) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+
+            let (_on_local_channel1, mut local_upgradable_channel1) = builder.clone().build(local_channel1);
+            let (_on_remote_channel1, mut remote_upgradable_channel1) = builder.build(remote_channel1);
+
+            transfer(&mut local_upgradable_channel1, &mut remote_upgradable_channel1).await;
+
+            // both sides, each with the upgrade task, the forwarder, and
+            // the substream reader and writer of the data and the control lanes
+            assert_eq!(
+                task_count.load(Ordering::SeqCst),
+                2 * (1 + 1 + 2 + 2),
+                "Must spawn all the tasks with the spawner.",
+            );
+        }
+    }
+
+    mod close {
+        use std::{sync::{Arc, atomic::{AtomicUsize, Ordering}}, time::Duration};
+
+        use cs_utils::{random_str_rg, futures::wait, traits::Random};
+        use rstest::rstest;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use super::fallback::{proxied_channel_mock_pair, transfer};
+        use crate::{
+            mocks::{ChannelMockOptions, channel_mock_pair},
+            utils::TaskCounter,
+            UpgradableChannel,
+            UpgradableChannelBuilder,
+            UpgradeState,
+            UpgradeError,
+        };
+
+        async fn wait_stopped(
+            task_count: &Arc<AtomicUsize>,
+            timeout: Duration,
+        ) {
+            tokio::time::timeout(timeout, async {
+                while task_count.load(Ordering::SeqCst) > 0 {
+                    wait(10).await;
+                }
+            }).await
+                .expect("Must stop all the tasks once the channels are dropped.");
+        }
+
+        #[rstest]
+        #[case(1, false)]
+        #[case(1, true)]
+        #[case(5_000, false)]
+        #[case(5_000, true)]
+        #[tokio::test]
+        async fn stops_background_tasks_once_dropped(
+            #[case] channel_count: usize,
+            #[case] is_upgraded: bool,
+        ) {
+            let (spawner, task_count) = TaskCounter::new();
+            let builder = UpgradableChannel::builder()
+                .spawner(spawner);
+
+            for _ in 0..channel_count {
+                let (local_channel1, remote_channel1) = channel_mock_pair(ChannelMockOptions::default(), ChannelMockOptions::default());
+
+                let (on_local_channel1, mut local_upgradable_channel1) = builder.clone().name("local").build(local_channel1);
+                let (on_remote_channel1, mut remote_upgradable_channel1) = builder.clone().name("remote").build(remote_channel1);
+
+                if is_upgraded {
+                    let (local_channel2, remote_channel2) = channel_mock_pair(ChannelMockOptions::default(), ChannelMockOptions::default());
+
+                    tokio::try_join!(
+                        on_local_channel1.upgrade(local_channel2),
+                        on_remote_channel1.upgrade(remote_channel2),
+                    ).expect("Cannot upgrade to the new channel.");
+                }
+
+                transfer(&mut local_upgradable_channel1, &mut remote_upgradable_channel1).await;
+            }
+
+            wait_stopped(&task_count, Duration::from_secs(10)).await;
+        }
+
+        #[tokio::test]
+        async fn stops_waiting_for_resume_once_dropped() {
+            let (spawner, task_count) = TaskCounter::new();
+            let builder = UpgradableChannel::builder()
+                .resumable(true)
+                .spawner(spawner);
+
+            let (local_channel1, remote_channel1, proxy) = proxied_channel_mock_pair(ChannelMockOptions::random());
+
+            let (on_local_channel1, mut local_upgradable_channel1) = builder.clone().name("local").build(local_channel1);
+            let (on_remote_channel1, mut remote_upgradable_channel1) = builder.name("remote").build(remote_channel1);
+
+            // the token keeps the session around until the channel is dropped
+            let _token = local_upgradable_channel1.resumption_token().await
+                .expect("Cannot get the resumption token.");
+
+            transfer(&mut local_upgradable_channel1, &mut remote_upgradable_channel1).await;
+
+            proxy.abort();
+
+            for channel in [&local_upgradable_channel1, &remote_upgradable_channel1] {
+                channel.upgrade_state()
+                    .wait_for(|state| { return state == &UpgradeState::Disconnected; }).await
+                    .expect("Cannot get the upgrade state.");
+            }
+
+            drop(on_local_channel1);
+            drop(local_upgradable_channel1);
+            drop(on_remote_channel1);
+            drop(remote_upgradable_channel1);
+
+            wait_stopped(&task_count, Duration::from_secs(10)).await;
+        }
+
+        #[tokio::test]
+        async fn stops_background_tasks_once_handles_dropped() {
+            let (spawner, task_count) = TaskCounter::new();
+            let builder = UpgradableChannel::builder()
+                .spawner(spawner);
+
+            let (local_channel1, remote_channel1) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+            let (local_channel2, remote_channel2) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+
+            let (on_local_channel1, mut local_upgradable_channel1) = builder.clone().name("local").build(local_channel1);
+            let (on_remote_channel1, mut remote_upgradable_channel1) = builder.name("remote").build(remote_channel1);
+
+            transfer(&mut local_upgradable_channel1, &mut remote_upgradable_channel1).await;
+
+            drop(local_upgradable_channel1);
+            drop(remote_upgradable_channel1);
+
+            // the handles can still upgrade the session of the dropped channels
+            tokio::try_join!(
+                on_local_channel1.upgrade(local_channel2),
+                on_remote_channel1.upgrade(remote_channel2),
+            ).expect("Must upgrade while the handles are alive.");
+
+            assert!(
+                task_count.load(Ordering::SeqCst) > 0,
+                "Must keep the tasks running while the handles are alive.",
+            );
+
+            drop(on_local_channel1);
+            drop(on_remote_channel1);
+
+            wait_stopped(&task_count, Duration::from_secs(10)).await;
+        }
+
+        #[tokio::test]
+        async fn stops_background_tasks_after_close_timeout() {
+            let (spawner, task_count) = TaskCounter::new();
+
+            let (local_channel1, remote_channel1) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+
+            let (on_local_channel1, mut local_upgradable_channel1) = UpgradableChannel::builder()
+                .spawner(spawner)
+                .close_timeout(Duration::from_millis(100))
+                .name("local")
+                .build(local_channel1);
+
+            local_upgradable_channel1.write_all(random_str_rg(1..=1_024).as_bytes()).await
+                .expect("Cannot write data.");
+
+            drop(on_local_channel1);
+            drop(local_upgradable_channel1);
+
+            wait_stopped(&task_count, Duration::from_secs(1)).await;
+
+            // the remote side never reads the data nor closes the channel
+            drop(remote_channel1);
+        }
+
+        #[tokio::test]
+        async fn joins_background_tasks_once_dropped() {
+            let (spawner, task_count) = TaskCounter::new();
+
+            let (local_channel1, remote_channel1) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+
+            let (on_local_channel1, local_upgradable_channel1) = UpgradableChannel::builder()
+                .spawner(spawner)
+                .close_timeout(Duration::from_millis(100))
+                .name("local")
+                .build(local_channel1);
+
+            let tasks = on_local_channel1.tasks().clone();
+
+            drop(on_local_channel1);
+            drop(local_upgradable_channel1);
+
+            tokio::time::timeout(Duration::from_secs(1), tasks.join()).await
+                .expect("Must join the tasks once the channel is dropped.");
+
+            assert_eq!(
+                task_count.load(Ordering::SeqCst),
+                0,
+                "Must stop all the tasks before they are joined.",
+            );
+
+            drop(remote_channel1);
+        }
+
+        #[tokio::test]
+        async fn aborts_background_tasks() {
+            let (spawner, task_count) = TaskCounter::new();
+
+            let (local_channel1, _remote_channel1) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+            let (local_channel2, _remote_channel2) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+
+            let (on_local_channel1, _local_upgradable_channel1) = UpgradableChannel::builder()
+                .spawner(spawner)
+                .name("local")
+                .build(local_channel1);
+
+            // the upgrade task, the forwarder, and the substream
+            // reader and writer of the data and the control lanes
+            assert_eq!(
+                task_count.load(Ordering::SeqCst),
+                1 + 1 + 2 + 2,
+                "Must keep the handles of all the tasks.",
+            );
+
+            on_local_channel1.tasks().abort();
+
+            tokio::time::timeout(Duration::from_secs(1), on_local_channel1.tasks().join()).await
+                .expect("Must join the aborted tasks.");
+
+            assert_eq!(
+                task_count.load(Ordering::SeqCst),
+                0,
+                "Must stop all the tasks.",
+            );
+
+            assert_eq!(
+                on_local_channel1.upgrade(local_channel2).await,
+                Err(UpgradeError::ControlChannelClosed),
+                "Must fail the upgrade once the tasks are aborted.",
+            );
+        }
+
+        #[rstest]
+        #[case(UpgradableChannel::builder(), false)]
+        #[case(UpgradableChannel::builder().fallback(true).replay_buffer_size(1_024).resumable(true), false)]
+        #[case(UpgradableChannel::builder(), true)]
+        #[case(UpgradableChannel::builder().fallback(true).replay_buffer_size(1_024).resumable(true), true)]
+        #[tokio::test]
+        async fn notifies_remote_side_once_dropped(
+            #[case] builder: UpgradableChannelBuilder,
+            #[case] is_upgraded: bool,
+        ) {
+            let (local_channel1, remote_channel1) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+            let (local_channel2, remote_channel2) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+            let (_local_channel3, remote_channel3) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+
+            let (on_local_channel1, mut local_upgradable_channel1) = builder.clone().name("local").build(local_channel1);
+            let (on_remote_channel1, mut remote_upgradable_channel1) = builder.name("remote").build(remote_channel1);
+
+            let mut remote_state = remote_upgradable_channel1.upgrade_state();
+
+            // the remote side does not read before the drop, hence its reads
+            // are still on the first channel with the handover pending
+            let mut data = String::new();
+
+            if is_upgraded {
+                let early_data = random_str_rg(1..=1_024);
+
+                local_upgradable_channel1.write_all(early_data.as_bytes()).await
+                    .expect("Cannot write data.");
+
+                tokio::try_join!(
+                    on_local_channel1.upgrade(local_channel2),
+                    on_remote_channel1.upgrade(remote_channel2),
+                ).expect("Cannot upgrade to the new channel.");
+
+                data.push_str(&early_data);
+            }
+
+            // the remote upgrade waits for its counterpart that never comes
+            let remote_upgrade = tokio::spawn(async move {
+                return on_remote_channel1.upgrade(remote_channel3).await;
+            });
+
+            remote_state.wait_for(|state| { return state == &UpgradeState::SyncSent; }).await
+                .expect("Cannot get the upgrade state.");
+
+            // the remote side is told only once it is known to support it
+            local_upgradable_channel1.capabilities().wait_for(|capabilities| capabilities.is_some()).await
+                .expect("Cannot get the capabilities.");
+
+            let late_data = random_str_rg(1..=1_024);
+
+            local_upgradable_channel1.write_all(late_data.as_bytes()).await
+                .expect("Cannot write data.");
+
+            data.push_str(&late_data);
+
+            drop(on_local_channel1);
+            drop(local_upgradable_channel1);
+
+            let mut received_data = vec![];
+
+            remote_upgradable_channel1.read_to_end(&mut received_data).await
+                .expect("Cannot read data.");
+
+            assert_eq!(
+                received_data,
+                data.as_bytes(),
+                "Must receive the data written before the drop, then EOF.",
+            );
+
+            assert_eq!(
+                remote_upgrade.await.unwrap(),
+                Err(UpgradeError::Closed),
+                "Must fail the remote upgrade.",
+            );
+
+            assert_eq!(
+                *remote_state.wait_for(|state| matches!(state, UpgradeState::Failed(_))).await
+                    .expect("Cannot get the upgrade state."),
+                UpgradeState::Failed("Channel closed.".to_string()),
+                "Must not resume the session of the dropped channel.",
+            );
+        }
+    }
 }
@@ -0,0 +1,51 @@
+/// Protocol feature, both sides advertise theirs in the `Hello` message
+/// and use only the ones supported by both of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Capability {
+    /// `Reads` switch to a new channel at the announced stream offset, required.
+    OffsetHandover,
+    /// More than one upgrade over the lifetime of the channel.
+    RepeatedUpgrades,
+    /// Falling back to the main channel, see `UpgradableChannelOptions::fallback`.
+    Fallback,
+    /// Replaying the unacknowledged data, see `UpgradableChannelOptions::replay_buffer_size`.
+    Replay,
+    /// Resuming the session on a new transport, see `UpgradableChannelOptions::resumable`.
+    Resume,
+    /// Telling the remote side the channel is dropped, see `ChannelMessage::Close`.
+    Close,
+    /// Telling the remote side where the stream ends, see `ChannelMessage::EndOfStream`.
+    EndOfStream,
+}
+
+impl Capability {
+    /// Capabilities both sides must support.
+    pub const REQUIRED: [Capability; 1] = [Capability::OffsetHandover];
+
+    /// Name of the capability in the `Hello` message, the names unknown
+    /// to the receiving side are ignored to stay compatible with newer peers.
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Capability::OffsetHandover => "offset-handover",
+            Capability::RepeatedUpgrades => "repeated-upgrades",
+            Capability::Fallback => "fallback",
+            Capability::Replay => "replay",
+            Capability::Resume => "resume",
+            Capability::Close => "close",
+            Capability::EndOfStream => "end-of-stream",
+        };
+    }
+
+    pub fn from_name(name: impl AsRef<str>) -> Option<Capability> {
+        return match name.as_ref() {
+            "offset-handover" => Some(Capability::OffsetHandover),
+            "repeated-upgrades" => Some(Capability::RepeatedUpgrades),
+            "fallback" => Some(Capability::Fallback),
+            "replay" => Some(Capability::Replay),
+            "resume" => Some(Capability::Resume),
+            "close" => Some(Capability::Close),
+            "end-of-stream" => Some(Capability::EndOfStream),
+            _ => None,
+        };
+    }
+}
@@ -0,0 +1,9 @@
+/// Event of the `reads` or the `writes`, handled by the upgrade task.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChannelEvent {
+    /// The current channel has failed, see `ChannelReader::is_interrupted`.
+    Failure,
+    /// The `writes` are shut down at the stream offset, the remote side
+    /// gets the `ChannelMessage::EndOfStream` message.
+    Shutdown(usize),
+}
@@ -1,8 +1,38 @@
 use serde::{Serialize, Deserialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Version of the control protocol, sent in the `Hello` message.
+pub const PROTOCOL_VERSION: u16 = 1;
+/// Oldest version of the control protocol still supported, the peers
+/// that do not send the `Hello` message run the version `0`.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum ChannelMessage {
-    Sync(String),
+    // protocol version and capability names of the sender, the first message
+    // on the control channel, its format must stay the same across the versions
+    Hello {
+        version: u16,
+        capabilities: Vec<String>,
+    },
+    // session id of the sender, the first message on the control channel
+    Session(String),
+    // session ids of the receiver and the sender, the first message
+    // on the control channel of a transport the session is resumed on
+    Resume(String, String),
+    // sync id, the stream offset of the `writes` switch and the
+    // stream offset of the first byte written to the new channel
+    Sync(String, usize, usize),
     SyncAck(String, String),
-    Ack(String, String),
+    Reject(String),
+    // the sender calls its upgrade off unless the receiver has paired it
+    // already, the receiver answers with `Reject` in the former case
+    Abort(String),
+    // the current channel has failed, switch back to the main one
+    Fallback,
+    // stream offset of the data read so far
+    DataAck(usize),
+    // the sender is dropped, the session ends and cannot be resumed
+    Close,
+    // stream offset at which the sender has shut its `writes` down
+    EndOfStream(usize),
 }
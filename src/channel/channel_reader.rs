@@ -0,0 +1,369 @@
+use std::{task::{Context, Poll}, collections::VecDeque, io, cmp, mem};
+
+use futures::ready;
+use tokio::{sync::{watch, mpsc}, io::{AsyncRead, ReadBuf}};
+
+use crate::{types::TReadHalf, channel::{UpgradableChannelOptions, Capability, ChannelEvent}};
+
+/// Channel to switch the `reads` to, all offsets are stream offsets.
+struct Handover {
+    // offset at which the remote side has switched its `writes`
+    offset: usize,
+    // offset of the first byte on the `reader`, the data
+    // replayed by the remote side starts before the `offset`
+    start_offset: usize,
+    reader: TReadHalf,
+    // the current channel is lost for good, hence never kept as the fallback
+    is_resume: bool,
+}
+
+/// Reader half of the currently active channel, along with the channels
+/// to switch to once the remote side's data on the current one is consumed.
+pub struct ChannelReader {
+    pub reader: TReadHalf,
+    // stream offset of the data read so far, over all the channels
+    pub bytes_read: usize,
+    // data of the current channel that was already read from the previous one
+    bytes_to_skip: usize,
+    // stream offset of the data on the fallback channel, read before the data
+    fallback_header: Option<([u8; 8], usize)>,
+    handovers: VecDeque<Handover>,
+    // stream offset at which the remote side has shut its `writes` down
+    end_offset: Option<usize>,
+    // idle channel to read from if the current one fails
+    fallback: Option<TReadHalf>,
+    keep_fallback: bool,
+    // whether the remote side replays the unacknowledged data on a new channel
+    is_replay: bool,
+    // whether the session can be resumed on a new transport
+    is_resumable: bool,
+    // the current channel has failed, waiting for a channel to switch to
+    is_interrupted: bool,
+    read_offset: watch::Sender<usize>,
+    on_failure: mpsc::UnboundedSender<ChannelEvent>,
+}
+
+impl ChannelReader {
+    pub fn new(
+        reader: TReadHalf,
+        options: &UpgradableChannelOptions,
+        on_failure: mpsc::UnboundedSender<ChannelEvent>,
+    ) -> ChannelReader {
+        return ChannelReader {
+            reader,
+            bytes_read: 0,
+            bytes_to_skip: 0,
+            fallback_header: None,
+            handovers: VecDeque::new(),
+            end_offset: None,
+            fallback: None,
+            keep_fallback: options.is_fallback(),
+            is_replay: options.is_replay(),
+            is_resumable: options.is_resumable(),
+            is_interrupted: false,
+            read_offset: watch::channel(0).0,
+            on_failure,
+        };
+    }
+
+    /// Switch to the `reader` at the `offset` of the stream, the remote side
+    /// has started writing to the `reader` at the `start_offset`.
+    pub fn handover(
+        &mut self,
+        offset: usize,
+        start_offset: usize,
+        reader: TReadHalf,
+    ) {
+        self.handovers.push_back(Handover { offset, start_offset, reader, is_resume: false });
+    }
+
+    /// Switch to the `reader` of a new transport the session is resumed on, see `handover`.
+    /// The pending handovers and the fallback channel belong to the lost transport.
+    pub fn resume(
+        &mut self,
+        offset: usize,
+        start_offset: usize,
+        reader: TReadHalf,
+    ) {
+        self.handovers.clear();
+        self.fallback.take();
+        self.fallback_header.take();
+        self.handovers.push_back(Handover { offset, start_offset, reader, is_resume: true });
+    }
+
+    /// Switch back to the fallback channel, returns `false` if there is none.
+    pub fn fall_back(&mut self) -> bool {
+        let reader = match self.fallback.take() {
+            Some(reader) => reader,
+            None => return false,
+        };
+
+        // handovers are relative to the failed channel
+        self.handovers.clear();
+        self.reader = reader;
+        self.bytes_to_skip = 0;
+        self.fallback_header.replace(([0; 8], 0));
+        self.is_interrupted = false;
+
+        return true;
+    }
+
+    /// Disable the features the remote side does not support.
+    pub fn negotiate(
+        &mut self,
+        capabilities: &[Capability],
+    ) {
+        if !capabilities.contains(&Capability::Fallback) {
+            self.keep_fallback = false;
+            self.fallback.take();
+        }
+
+        if !capabilities.contains(&Capability::Replay) {
+            self.is_replay = false;
+        }
+
+        if !capabilities.contains(&Capability::Resume) {
+            self.is_resumable = false;
+        }
+    }
+
+    /// Stop waiting for a channel to switch to, the failed channel
+    /// returns an error instead.
+    pub fn abort_recovery(&mut self) {
+        self.handovers.clear();
+        self.fallback.take();
+        self.is_replay = false;
+        self.is_resumable = false;
+    }
+
+    /// The remote side has closed the channel, the pending handovers are kept so
+    /// the data written before that is read, the last channel gets EOF once its
+    /// data is read instead of waiting for a channel to switch to.
+    pub fn close(&mut self) {
+        self.keep_fallback = false;
+        self.fallback.take();
+        self.is_replay = false;
+        self.is_resumable = false;
+
+        // the failed channel is switched from right away if there is a channel to switch to
+        if self.handovers.is_empty() {
+            self.is_interrupted = false;
+        }
+    }
+
+    /// The remote side has shut its `writes` down at the `offset`, the `reads` get EOF
+    /// once all the data up to it is read, the EOF of the channel itself is not needed.
+    /// Returns `false` if the stream has ended at another offset already.
+    pub fn end_stream(
+        &mut self,
+        offset: usize,
+    ) -> bool {
+        return *self.end_offset.get_or_insert(offset) == offset;
+    }
+
+    /// Whether the current channel has failed and there is no channel to switch to yet.
+    pub fn is_interrupted(&self) -> bool {
+        return self.is_interrupted;
+    }
+
+    /// Stream offset of the data read so far, updated if the `replay` is enabled.
+    pub fn read_offset(&self) -> watch::Receiver<usize> {
+        return self.read_offset.subscribe();
+    }
+
+    fn switch(
+        &mut self,
+        reader: TReadHalf,
+        start_offset: usize,
+        is_resume: bool,
+    ) {
+        let previous_reader = mem::replace(&mut self.reader, reader);
+
+        if self.keep_fallback && self.fallback.is_none() && !is_resume {
+            self.fallback.replace(previous_reader);
+        }
+
+        // if the replay is disabled, the data not read from the failed channel is lost
+        self.bytes_to_skip = self.bytes_read.saturating_sub(start_offset);
+        self.is_interrupted = false;
+    }
+
+    fn is_recoverable(&self) -> bool {
+        return self.is_replay || self.is_resumable || self.fallback.is_some();
+    }
+
+    /// Wait for a channel to switch to if the current one fails, unless
+    /// there is no way to recover, returns `false` in the latter case.
+    ///
+    /// The remote side might have shut its `writes` down if the channel has reached EOF,
+    /// the reader waits for the end of the stream or the `Fallback` message then.
+    fn interrupt(
+        &mut self,
+        is_eof: bool,
+    ) -> bool {
+        if !self.is_recoverable() {
+            return false;
+        }
+
+        if !is_eof && self.fall_back() {
+            // the upgrade task switches the `writes` too
+            let _res = self.on_failure.send(ChannelEvent::Failure);
+
+            return true;
+        }
+
+        if !self.is_interrupted {
+            self.is_interrupted = true;
+
+            // the upgrade task might have stopped already
+            let _res = self.on_failure.send(ChannelEvent::Failure);
+        }
+
+        return true;
+    }
+
+    /// Read from the current channel, never reads past the handover offset.
+    pub fn poll_read(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        loop {
+            // the remote side has shut its `writes` down and all of its data is read
+            if self.end_offset == Some(self.bytes_read) {
+                return Poll::Ready(Ok(()));
+            }
+
+            // switch to the next channel once all the data of the current one is read,
+            // or right away if it has failed, the remote side replays the lost data
+            let is_switching = match self.handovers.front() {
+                Some(handover) => self.is_interrupted || handover.offset == self.bytes_read,
+                None => false,
+            };
+
+            if is_switching {
+                if let Some(handover) = self.handovers.pop_front() {
+                    self.switch(handover.reader, handover.start_offset, handover.is_resume);
+                }
+
+                continue;
+            }
+
+            if self.is_interrupted {
+                if !self.is_recoverable() {
+                    return Poll::Ready(Err(
+                        io::Error::new(io::ErrorKind::BrokenPipe, "Channel failed."),
+                    ));
+                }
+
+                return Poll::Pending;
+            }
+
+            // read the stream offset of the data on the fallback channel first
+            if let Some((mut header, mut header_bytes_read)) = self.fallback_header.take() {
+                let (result, bytes_read) = {
+                    let mut header_buf = ReadBuf::new(&mut header[header_bytes_read..]);
+                    let result = self.reader.as_mut().poll_read(cx, &mut header_buf);
+
+                    (result, header_buf.filled().len())
+                };
+
+                match result {
+                    Poll::Pending => {
+                        self.fallback_header.replace((header, header_bytes_read));
+
+                        return Poll::Pending;
+                    },
+                    Poll::Ready(Err(error)) => return Poll::Ready(Err(error)),
+                    Poll::Ready(Ok(_)) if bytes_read == 0 => {
+                        return Poll::Ready(Err(
+                            io::Error::new(io::ErrorKind::UnexpectedEof, "Fallback channel closed."),
+                        ));
+                    },
+                    Poll::Ready(Ok(_)) => {
+                        header_bytes_read += bytes_read;
+
+                        if header_bytes_read < header.len() {
+                            self.fallback_header.replace((header, header_bytes_read));
+                        } else {
+                            let start_offset = u64::from_be_bytes(header) as usize;
+
+                            self.bytes_to_skip = self.bytes_read.saturating_sub(start_offset);
+                        }
+                    },
+                };
+
+                continue;
+            }
+
+            let bytes_left = match self.handovers.front() {
+                Some(handover) => handover.offset.checked_sub(self.bytes_read)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Read past the handover offset."))?,
+                None => usize::MAX,
+            };
+
+            let bytes_left = match self.end_offset {
+                Some(end_offset) => cmp::min(
+                    bytes_left,
+                    end_offset.checked_sub(self.bytes_read)
+                        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Read past the end of the stream."))?,
+                ),
+                None => bytes_left,
+            };
+
+            // skip the data that was already read from the previous channel
+            let bytes_to_read = if self.bytes_to_skip > 0 {
+                cmp::min(self.bytes_to_skip, buf.remaining())
+            } else {
+                cmp::min(bytes_left, buf.remaining())
+            };
+
+            let mut limited_buf = ReadBuf::new(buf.initialize_unfilled_to(bytes_to_read));
+
+            let bytes_read = match ready!(self.reader.as_mut().poll_read(cx, &mut limited_buf)) {
+                Ok(_) => limited_buf.filled().len(),
+                Err(error) => {
+                    if self.interrupt(false) {
+                        continue;
+                    }
+
+                    return Poll::Ready(Err(error));
+                },
+            };
+
+            // EOF of the current channel
+            if bytes_read == 0 {
+                if self.interrupt(true) {
+                    continue;
+                }
+
+                if bytes_left != usize::MAX {
+                    return Poll::Ready(Err(
+                        io::Error::new(io::ErrorKind::UnexpectedEof, "Channel closed before the announced offset."),
+                    ));
+                }
+
+                return Poll::Ready(Ok(()));
+            }
+
+            if self.bytes_to_skip > 0 {
+                self.bytes_to_skip -= bytes_read;
+
+                continue;
+            }
+
+            buf.advance(bytes_read);
+            self.bytes_read += bytes_read;
+
+            if self.is_replay {
+                self.read_offset.send_replace(self.bytes_read);
+            }
+
+            return Poll::Ready(Ok(()));
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use std::{io, sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}}, time::Duration};
+
+use cs_utils::random_str;
+use tokio::sync::{watch, mpsc};
+
+use crate::{types::{TReadHalf, TWriteHalf}, channel::{ChannelEvent, ChannelReader, ChannelWriter, SharedHalf, UpgradeState, UpgradableChannelOptions, Capability}, MultiplexerOptions, TraceSink};
+
+/// State shared between the `UpgradableChannel` and the background upgrade task.
+#[derive(Clone)]
+pub struct ChannelState {
+    pub reader: Arc<SharedHalf<ChannelReader>>,
+    pub writer: Arc<SharedHalf<ChannelWriter>>,
+    // completed upgrades count and the currently active channel `(id, label)`
+    pub upgrade_count: Arc<AtomicUsize>,
+    pub transport: Arc<Mutex<(u16, String)>>,
+    pub upgrade_state: Arc<watch::Sender<UpgradeState>>,
+    // `(id, label)` of the main channel, used as the fallback
+    main_transport: Arc<Mutex<(u16, String)>>,
+    // ids of the local and the remote sides of the session, the latter
+    // is received once the control channel is set up
+    pub session_id: String,
+    pub remote_session_id: Arc<watch::Sender<Option<String>>>,
+    pub upgrade_timeout: Option<Duration>,
+    // how long the remote side has to agree on the protocol
+    pub handshake_timeout: Duration,
+    // how long the dropped channel waits for the remote side to read
+    pub close_timeout: Duration,
+    // the data and the control lanes multiplexed over the main
+    // channel, same for the transports the session is resumed on
+    pub lanes: MultiplexerOptions,
+    // capabilities advertised to the remote side, and the ones
+    // supported by both sides once the `Hello` messages are exchanged
+    pub local_capabilities: Vec<Capability>,
+    pub capabilities: Arc<watch::Sender<Option<Vec<Capability>>>>,
+    // trace of the channel, the upgrades are traced under it
+    pub trace: TraceSink,
+}
+
+impl ChannelState {
+    pub fn new(
+        reader: TReadHalf,
+        writer: TWriteHalf,
+        transport_id: u16,
+        transport_label: impl AsRef<str> + ToString,
+        options: &UpgradableChannelOptions,
+        lanes: MultiplexerOptions,
+        on_event: mpsc::UnboundedSender<ChannelEvent>,
+    ) -> ChannelState {
+        // the lanes are traced under the channel
+        let trace = lanes.get_trace().clone();
+
+        return ChannelState {
+            reader: Arc::new(SharedHalf::new(ChannelReader::new(reader, options, on_event.clone()))),
+            writer: Arc::new(SharedHalf::new(ChannelWriter::new(writer, options, on_event))),
+            upgrade_count: Arc::new(AtomicUsize::new(0)),
+            transport: Arc::new(Mutex::new((transport_id, transport_label.to_string()))),
+            upgrade_state: Arc::new(watch::channel(UpgradeState::Idle).0),
+            main_transport: Arc::new(Mutex::new((transport_id, transport_label.to_string()))),
+            session_id: random_str(32),
+            remote_session_id: Arc::new(watch::channel(None).0),
+            upgrade_timeout: options.get_upgrade_timeout(),
+            handshake_timeout: options.get_handshake_timeout(),
+            close_timeout: options.get_close_timeout(),
+            lanes,
+            local_capabilities: options.get_capabilities(),
+            capabilities: Arc::new(watch::channel(None).0),
+            trace,
+        };
+    }
+
+    /// Record a completed upgrade to the `(id, label)` channel.
+    pub fn set_upgraded(
+        &self,
+        transport_id: u16,
+        transport_label: impl AsRef<str> + ToString,
+    ) {
+        *self.transport.lock().unwrap() = (transport_id, transport_label.to_string());
+
+        self.upgrade_count.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Record a resumption on the `(id, label)` transport, it replaces the main channel.
+    pub fn set_resumed(
+        &self,
+        transport_id: u16,
+        transport_label: impl AsRef<str> + ToString,
+    ) {
+        *self.transport.lock().unwrap() = (transport_id, transport_label.to_string());
+        *self.main_transport.lock().unwrap() = (transport_id, transport_label.to_string());
+    }
+
+    /// Record a fallback to the main channel.
+    pub fn set_fallen_back(&self) {
+        *self.transport.lock().unwrap() = self.main_transport.lock().unwrap().clone();
+    }
+
+    /// Whether both sides support the `capability`, `false` until the `Hello` messages are exchanged.
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        return self.capabilities.borrow().as_ref()
+            .map(|capabilities| { return capabilities.contains(&capability); })
+            .unwrap_or(false);
+    }
+
+    pub fn get_remote_session_id(&self) -> Option<String> {
+        return self.remote_session_id.borrow().clone();
+    }
+
+    /// Whether `reads` or `writes` wait for a new channel to switch to.
+    pub async fn is_interrupted(&self) -> io::Result<bool> {
+        let is_reader_interrupted = self.reader.lock().await?.is_interrupted();
+
+        return Ok(is_reader_interrupted || self.writer.lock().await?.is_interrupted());
+    }
+
+    /// Move the current upgrade to the next phase.
+    pub fn set_upgrade_state(&self, upgrade_state: UpgradeState) {
+        self.upgrade_state.send_replace(upgrade_state);
+    }
+
+    /// Wake up a `poll_read` that is waiting for the active channel.
+    pub fn wake_reader(&self) {
+        self.reader.wake();
+    }
+
+    /// Wake up a `poll_write` that is waiting for the active channel.
+    pub fn wake_writer(&self) {
+        self.writer.wake();
+    }
+}
@@ -0,0 +1,415 @@
+use std::{task::{Context, Poll}, collections::VecDeque, io, cmp, mem};
+
+use futures::ready;
+use tokio::{sync::mpsc, io::AsyncWrite};
+
+use crate::{types::TWriteHalf, channel::{UpgradableChannelOptions, Capability, ChannelEvent}};
+
+/// Writer half of the currently active channel, along with the data
+/// written to it that the remote side has not acknowledged yet.
+pub struct ChannelWriter {
+    pub writer: TWriteHalf,
+    // stream offset of the data written so far, over all the channels
+    pub bytes_written: usize,
+    // data not acknowledged by the remote side, starts at the `bytes_acked` offset
+    unacked: VecDeque<u8>,
+    bytes_acked: usize,
+    // data to write to the current channel before any new data
+    replay: VecDeque<u8>,
+    // data written to the new channel of an upgrade the remote side has not
+    // paired yet, written to the previous channel if the upgrade is called off
+    unconfirmed: Option<VecDeque<u8>>,
+    // the `writes` wait for the upgrade to be paired once there is that much data
+    max_unconfirmed_size: usize,
+    // maximum size of the unacknowledged data, `0` if the replay is disabled
+    replay_buffer_size: usize,
+    // idle channel to write to if the current one fails
+    fallback: Option<TWriteHalf>,
+    keep_fallback: bool,
+    // whether the session can be resumed on a new transport
+    is_resumable: bool,
+    // the remote side does not read the new transport yet, the data
+    // written to it could hold back the control messages on the transport
+    is_resuming: bool,
+    // the current channel has failed, waiting for a channel to switch to
+    is_interrupted: bool,
+    // whether the remote side is told where the stream ends instead of getting
+    // EOF of the channel, unknown until the capabilities are negotiated
+    is_end_marked: Option<bool>,
+    is_shut_down: bool,
+    on_event: mpsc::UnboundedSender<ChannelEvent>,
+}
+
+impl ChannelWriter {
+    pub fn new(
+        writer: TWriteHalf,
+        options: &UpgradableChannelOptions,
+        on_event: mpsc::UnboundedSender<ChannelEvent>,
+    ) -> ChannelWriter {
+        return ChannelWriter {
+            writer,
+            bytes_written: 0,
+            unacked: VecDeque::new(),
+            bytes_acked: 0,
+            replay: VecDeque::new(),
+            unconfirmed: None,
+            max_unconfirmed_size: options.get_buffers().get_max_unconfirmed_size(),
+            replay_buffer_size: options.get_replay_buffer_size(),
+            fallback: None,
+            keep_fallback: options.is_fallback(),
+            is_resumable: options.is_resumable(),
+            is_resuming: false,
+            is_interrupted: false,
+            is_end_marked: None,
+            is_shut_down: false,
+            on_event,
+        };
+    }
+
+    /// Switch to the `writer`, the unacknowledged data is replayed on it first.
+    ///
+    /// Returns the previous writer, the stream offset of the switch and
+    /// the stream offset of the first byte written to the `writer`.
+    pub fn switch(
+        &mut self,
+        writer: TWriteHalf,
+    ) -> (TWriteHalf, usize, usize) {
+        let previous_writer = mem::replace(&mut self.writer, writer);
+
+        self.replay = self.unacked.clone();
+        self.is_interrupted = false;
+
+        return (previous_writer, self.bytes_written, self.start_offset());
+    }
+
+    /// Switch to the `writer` of a new transport the session is resumed on, see `switch`.
+    /// The fallback channel belongs to the lost transport. No data is written to the
+    /// `writer` until the remote side confirms the resumption with `confirm_resume`.
+    pub fn resume(
+        &mut self,
+        writer: TWriteHalf,
+    ) -> (TWriteHalf, usize, usize) {
+        self.fallback.take();
+        self.unconfirmed.take();
+        self.is_resuming = true;
+
+        return self.switch(writer);
+    }
+
+    /// Start writing to the transport the session is resumed on.
+    pub fn confirm_resume(&mut self) {
+        self.is_resuming = false;
+    }
+
+    /// Switch to the `writer` of a new upgrade, see `switch`. The data written
+    /// to it is kept until the remote side pairs the upgrade with `confirm_upgrade`.
+    ///
+    /// The unacknowledged data is replayed only if the current channel has failed,
+    /// `is_failed` tells if the `reads` have found it failed, otherwise the remote
+    /// side reads all of it from the current channel.
+    pub fn upgrade(
+        &mut self,
+        writer: TWriteHalf,
+        is_failed: bool,
+    ) -> (TWriteHalf, usize, usize) {
+        self.unconfirmed.replace(VecDeque::new());
+
+        // the data to replay has not reached the remote side either
+        if is_failed || self.is_interrupted || !self.replay.is_empty() {
+            return self.switch(writer);
+        }
+
+        let previous_writer = mem::replace(&mut self.writer, writer);
+
+        return (previous_writer, self.bytes_written, self.bytes_written);
+    }
+
+    /// The remote side reads the new channel of the upgrade, the upgrade cannot be called off anymore.
+    pub fn confirm_upgrade(&mut self) {
+        self.unconfirmed.take();
+    }
+
+    /// Switch back to the `writer` of a rejected or aborted upgrade.
+    pub fn restore(
+        &mut self,
+        writer: TWriteHalf,
+    ) {
+        self.writer = writer;
+        self.is_resuming = false;
+
+        // the remote side never reads the new channel, hence the data
+        // written to it goes to the previous channel instead
+        self.replay = self.unconfirmed.take().unwrap_or_default();
+    }
+
+    /// Keep the `writer` of a replaced channel as the fallback
+    /// if enabled and there is none yet, drop it otherwise.
+    pub fn retire(
+        &mut self,
+        writer: TWriteHalf,
+    ) {
+        if self.keep_fallback && self.fallback.is_none() {
+            self.fallback.replace(writer);
+        }
+    }
+
+    /// Switch back to the fallback channel, the unacknowledged data is replayed on it first,
+    /// returns `false` if there is no fallback channel.
+    pub fn fall_back(&mut self) -> bool {
+        let writer = match self.fallback.take() {
+            Some(writer) => writer,
+            None => return false,
+        };
+
+        let (_failed_writer, _offset, start_offset) = self.switch(writer);
+
+        // the remote side can switch before getting our `Fallback` message,
+        // hence the stream offset of the data on the fallback channel goes first
+        for byte in (start_offset as u64).to_be_bytes().iter().rev() {
+            self.replay.push_front(*byte);
+        }
+
+        return true;
+    }
+
+    /// Disable the features the remote side does not support.
+    pub fn negotiate(
+        &mut self,
+        capabilities: &[Capability],
+    ) {
+        if !capabilities.contains(&Capability::Fallback) {
+            self.keep_fallback = false;
+            self.fallback.take();
+        }
+
+        // the remote side never acknowledges the data
+        if !capabilities.contains(&Capability::Replay) {
+            self.replay_buffer_size = 0;
+            self.unacked.clear();
+        }
+
+        if !capabilities.contains(&Capability::Resume) {
+            self.is_resumable = false;
+        }
+
+        self.is_end_marked.replace(capabilities.contains(&Capability::EndOfStream));
+    }
+
+    /// Stop waiting for a channel to switch to, the failed channel
+    /// returns an error instead.
+    pub fn abort_recovery(&mut self) {
+        self.fallback.take();
+        self.replay_buffer_size = 0;
+        self.is_resumable = false;
+        self.is_resuming = false;
+
+        // nobody can call the upgrade off anymore
+        self.unconfirmed.take();
+
+        // nobody tells the remote side where the stream ends anymore
+        if self.is_end_marked.is_none() {
+            self.is_end_marked.replace(false);
+        }
+    }
+
+    /// Whether the current channel has failed and there is no channel to switch to yet.
+    pub fn is_interrupted(&self) -> bool {
+        return self.is_interrupted;
+    }
+
+    /// Drop the data the remote side has read up to the `offset`.
+    pub fn acknowledge(
+        &mut self,
+        offset: usize,
+    ) {
+        let bytes_acked = cmp::min(
+            offset.saturating_sub(self.bytes_acked),
+            self.unacked.len(),
+        );
+
+        self.unacked.drain(..bytes_acked);
+        self.bytes_acked += bytes_acked;
+    }
+
+    pub fn has_replay(&self) -> bool {
+        return !self.replay.is_empty();
+    }
+
+    fn is_replay(&self) -> bool {
+        return self.replay_buffer_size > 0;
+    }
+
+    fn start_offset(&self) -> usize {
+        if self.is_replay() {
+            return self.bytes_acked;
+        }
+
+        return self.bytes_written;
+    }
+
+    fn is_recoverable(&self) -> bool {
+        return self.is_replay() || self.is_resumable || self.fallback.is_some();
+    }
+
+    /// Wait for a channel to switch to if the current one fails, unless
+    /// there is no way to recover, returns the `error` in the latter case.
+    fn interrupt<T>(
+        &mut self,
+        error: io::Error,
+    ) -> Poll<io::Result<T>> {
+        if !self.is_recoverable() {
+            return Poll::Ready(Err(error));
+        }
+
+        if !self.is_interrupted {
+            self.is_interrupted = true;
+
+            // the upgrade task might have stopped already
+            let _res = self.on_event.send(ChannelEvent::Failure);
+        }
+
+        return Poll::Pending;
+    }
+
+    /// Write the data to replay to the current channel.
+    pub fn poll_replay(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.is_interrupted {
+            return self.interrupt(io::Error::new(io::ErrorKind::BrokenPipe, "Channel failed."));
+        }
+
+        // the upgrade task wakes the writer up once the resumption is confirmed
+        if self.is_resuming {
+            return Poll::Pending;
+        }
+
+        while !self.replay.is_empty() {
+            let (data, _) = self.replay.as_slices();
+
+            match ready!(self.writer.as_mut().poll_write(cx, data)) {
+                Ok(bytes_written) => {
+                    self.replay.drain(..bytes_written);
+                },
+                Err(error) => return self.interrupt(error),
+            };
+        }
+
+        return Poll::Ready(Ok(()));
+    }
+
+    pub fn poll_write(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.is_shut_down {
+            return Poll::Ready(Err(
+                io::Error::new(io::ErrorKind::BrokenPipe, "Channel is shut down."),
+            ));
+        }
+
+        ready!(self.poll_replay(cx))?;
+
+        // wait for the remote side to acknowledge some data if the buffer is full,
+        // the upgrade task wakes the writer up once the acknowledgement is received
+        let bytes_to_write = if self.is_replay() {
+            let buffer_space = self.replay_buffer_size.saturating_sub(self.unacked.len());
+
+            if buffer_space == 0 {
+                return Poll::Pending;
+            }
+
+            cmp::min(buffer_space, buf.len())
+        } else {
+            buf.len()
+        };
+
+        // same for the data kept until the remote side pairs the upgrade,
+        // the upgrade task wakes the writer up once it is paired or called off
+        let bytes_to_write = match self.unconfirmed.as_ref() {
+            Some(unconfirmed) => {
+                let buffer_space = self.max_unconfirmed_size.saturating_sub(unconfirmed.len());
+
+                if buffer_space == 0 {
+                    return Poll::Pending;
+                }
+
+                cmp::min(buffer_space, bytes_to_write)
+            },
+            None => bytes_to_write,
+        };
+
+        let bytes_written = match ready!(self.writer.as_mut().poll_write(cx, &buf[..bytes_to_write])) {
+            Ok(bytes_written) => bytes_written,
+            Err(error) => return self.interrupt(error),
+        };
+
+        self.bytes_written += bytes_written;
+
+        if self.is_replay() {
+            self.unacked.extend(&buf[..bytes_written]);
+        }
+
+        if let Some(unconfirmed) = self.unconfirmed.as_mut() {
+            unconfirmed.extend(&buf[..bytes_written]);
+        }
+
+        return Poll::Ready(Ok(bytes_written));
+    }
+
+    pub fn poll_flush(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        ready!(self.poll_replay(cx))?;
+
+        return match ready!(self.writer.as_mut().poll_flush(cx)) {
+            Ok(_) => Poll::Ready(Ok(())),
+            Err(error) => self.interrupt(error),
+        };
+    }
+
+    /// Shut the `writes` down, the current channel stays open and the upgrade task tells the
+    /// remote side the stream offset the stream ends at, the remote side would take EOF of
+    /// the channel for a failure if it can recover from one. The channel itself is shut down
+    /// if the remote side does not support it.
+    pub fn poll_shutdown(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        ready!(self.poll_flush(cx))?;
+
+        // the upgrade task wakes the writer up once the capabilities are negotiated
+        let is_end_marked = match self.is_end_marked {
+            Some(is_end_marked) => is_end_marked,
+            None => return Poll::Pending,
+        };
+
+        // the upgrade task might have stopped already
+        if is_end_marked && (self.is_shut_down || self.on_event.send(ChannelEvent::Shutdown(self.bytes_written)).is_ok()) {
+            self.is_shut_down = true;
+
+            return Poll::Ready(Ok(()));
+        }
+
+        self.is_shut_down = true;
+
+        return self.writer.as_mut().poll_shutdown(cx);
+    }
+
+    /// Shut the current channel down once the data to replay is written, returns
+    /// the stream offset the stream ends at. The channel is not used anymore.
+    pub fn poll_close(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.poll_replay(cx))?;
+        ready!(self.writer.as_mut().poll_shutdown(cx))?;
+
+        self.is_shut_down = true;
+
+        return Poll::Ready(Ok(self.bytes_written));
+    }
+}
@@ -0,0 +1,874 @@
+use std::{task::Poll, sync::atomic::Ordering, time::Duration, collections::VecDeque};
+
+use cs_utils::random_str;
+use futures::{SinkExt, StreamExt, select, FutureExt, future, ready};
+use connection_utils::Channel;
+use tokio::{io::{split, AsyncWriteExt}, sync::{mpsc::UnboundedReceiver, oneshot}, time::{Instant, sleep_until, timeout}};
+
+use crate::{channel::{ChannelMessage, ChannelEvent, ChannelState, UpgradeReport, UpgradeRequest, HandleRequest, TUpgradeResult, UpgradeState, Capability, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION}, types::{TReadHalf, TWriteHalf}, errors::UpgradeError, codec::{MessageCodec, TMessageChannel}, interleaved_channel::divide_channel, TraceSink};
+
+/// State of a single upgrade to a new channel.
+struct Upgrade {
+    our_sync_id: String,
+    channel_id: u16,
+    channel_label: String,
+    // new channel halves, present until the `reads`/`writes` are upgraded
+    reader: Option<TReadHalf>,
+    writer: Option<TWriteHalf>,
+    // the current channel writer, restored if the remote side rejects
+    // the upgrade, or kept as the fallback once the upgrade completes
+    previous_writer: Option<TWriteHalf>,
+    handover_offset: usize,
+    is_sync_ack_received: bool,
+    // the session is resumed on a new transport, the previous one is lost
+    is_resume: bool,
+    // the upgrade is called off if the remote side does not pair it by then
+    deadline: Option<Instant>,
+    // the upgrade is being called off, it fails with the reason once the remote side confirms
+    abort_reason: Option<UpgradeError>,
+    on_complete: oneshot::Sender<TUpgradeResult>,
+    trace: TraceSink,
+}
+
+impl Upgrade {
+    fn new(
+        request: UpgradeRequest,
+        is_resume: bool,
+        deadline: Option<Instant>,
+        trace: &TraceSink,
+    ) -> Upgrade {
+        let UpgradeRequest { channel, on_complete } = request;
+
+        let our_sync_id = random_str(32);
+        let channel_id = channel.id();
+        let channel_label = channel.label().clone();
+        let (rx, tx) = split(channel);
+
+        let trace = child_trace!(
+            trace,
+            "upgrade",
+            sync_id = %our_sync_id,
+            channel_id = channel_id,
+            channel_label = %channel_label,
+            is_resume = is_resume,
+        );
+
+        return Upgrade {
+            our_sync_id,
+            channel_id,
+            channel_label,
+            reader: Some(Box::pin(rx)),
+            writer: Some(Box::pin(tx)),
+            previous_writer: None,
+            handover_offset: 0,
+            is_sync_ack_received: false,
+            is_resume,
+            deadline,
+            abort_reason: None,
+            on_complete,
+            trace,
+        };
+    }
+
+    /// Whether the remote side has paired the upgrade with its own,
+    /// a paired upgrade cannot be called off anymore.
+    fn is_paired(&self) -> bool {
+        return self.reader.is_none();
+    }
+
+    fn is_completed(&self) -> bool {
+        return self.reader.is_none() && self.writer.is_none() && self.is_sync_ack_received;
+    }
+
+    fn complete(self, their_sync_id: String) {
+        let report = UpgradeReport::new(
+            self.channel_id,
+            self.channel_label,
+            self.our_sync_id,
+            their_sync_id,
+            self.handover_offset,
+        );
+
+        // the caller might not be interested in the result anymore
+        let _res = self.on_complete.send(Ok(report));
+    }
+
+    fn fail(self, error: UpgradeError) {
+        let _res = self.on_complete.send(Err(error));
+    }
+}
+
+/// Receivers of the requests made with the `UpgradeHandle`.
+struct UpgradeRequests {
+    on_request: UnboundedReceiver<HandleRequest>,
+    // upgrades requested while another one is in progress or before both
+    // sides agree on the protocol, started in order, along with their deadlines
+    queued: VecDeque<(UpgradeRequest, Option<Instant>)>,
+}
+
+impl UpgradeRequests {
+    /// Queue the upgrade requested while the session waits to be resumed,
+    /// or call the next queued one off.
+    fn push(
+        &mut self,
+        request: HandleRequest,
+        upgrade_timeout: Option<Duration>,
+    ) {
+        match request {
+            HandleRequest::Upgrade(request) => {
+                let deadline = upgrade_timeout.map(|timeout| { return Instant::now() + timeout; });
+
+                self.queued.push_back((request, deadline));
+            },
+            HandleRequest::Cancel => {
+                if let Some((request, _)) = self.queued.pop_front() {
+                    let _res = request.on_complete.send(Err(UpgradeError::Cancelled));
+                }
+            },
+        };
+    }
+
+    /// Fail the queued upgrades and the ones requested from now on with the `error`.
+    fn fail(&mut self, error: &UpgradeError) {
+        self.on_request.close();
+
+        while let Ok(request) = self.on_request.try_recv() {
+            if let HandleRequest::Upgrade(request) = request {
+                self.queued.push_back((request, None));
+            }
+        }
+
+        for (request, _) in self.queued.drain(..) {
+            let _res = request.on_complete.send(Err(error.clone()));
+        }
+    }
+
+    /// Fail the queued upgrades that were not started by their deadlines with the `error`.
+    fn fail_expired(&mut self, now: Instant, error: &UpgradeError) {
+        let (expired, queued): (VecDeque<_>, VecDeque<_>) = self.queued.drain(..)
+            .partition(|(_, deadline)| { return deadline.is_some_and(|deadline| deadline <= now); });
+
+        self.queued = queued;
+
+        for (request, _) in expired {
+            let _res = request.on_complete.send(Err(error.clone()));
+        }
+    }
+}
+
+/// Agree on the protocol version with the remote side and get
+/// the capabilities supported by both sides.
+fn negotiate(
+    version: u16,
+    their_capabilities: &[String],
+    our_capabilities: &[Capability],
+) -> Result<Vec<Capability>, UpgradeError> {
+    // a newer remote side speaks our version of the protocol
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(UpgradeError::UnsupportedVersion(version));
+    }
+
+    let their_capabilities: Vec<Capability> = their_capabilities.iter()
+        .filter_map(Capability::from_name)
+        .collect();
+
+    for capability in Capability::REQUIRED {
+        if !their_capabilities.contains(&capability) {
+            return Err(UpgradeError::UnsupportedCapability(capability.name().to_string()));
+        }
+    }
+
+    return Ok(
+        our_capabilities.iter()
+            .filter(|capability| { return their_capabilities.contains(capability); })
+            .copied()
+            .collect(),
+    );
+}
+
+/// Get the next event of the `reads` or `writes`, never resolves if no more events can be received.
+async fn next_event(
+    on_event: &mut UnboundedReceiver<ChannelEvent>,
+) -> ChannelEvent {
+    return match on_event.recv().await {
+        Some(event) => event,
+        None => future::pending().await,
+    };
+}
+
+/// Resolves at the `deadline`, never if there is none.
+async fn next_deadline(
+    deadline: Option<Instant>,
+) {
+    match deadline {
+        Some(deadline) => sleep_until(deadline).await,
+        None => future::pending().await,
+    };
+}
+
+/// Call the current upgrade off unless the remote side has paired it already, the upgrade
+/// fails with the `reason` once the remote side confirms with a `Reject` message.
+async fn abort_upgrade(
+    upgrade: &mut Option<Upgrade>,
+    reason: UpgradeError,
+    control_channel: &mut TMessageChannel<ChannelMessage>,
+) -> Result<(), UpgradeError> {
+    let current_upgrade = match upgrade.as_mut() {
+        Some(upgrade) => upgrade,
+        None => return Ok(()),
+    };
+
+    // the transport is lost, there is no channel to go back to
+    if current_upgrade.is_resume || current_upgrade.is_paired() || current_upgrade.abort_reason.is_some() {
+        return Ok(());
+    }
+
+    current_upgrade.trace.debug(&format!("aborting upgrade: {}", reason));
+
+    current_upgrade.abort_reason.replace(reason);
+
+    control_channel.send(ChannelMessage::Abort(current_upgrade.our_sync_id.clone())).await?;
+
+    return Ok(());
+}
+
+/// Shut the `writes` down and let the remote side know the channel is dropped,
+/// gives up after the close timeout if the remote side does not read.
+async fn close(
+    state: &ChannelState,
+    control_channel: &mut TMessageChannel<ChannelMessage>,
+) {
+    let result = timeout(state.close_timeout, async {
+        let end_offset = future::poll_fn(|cx| { return ready!(state.writer.poll_switch_lock(cx))?.poll_close(cx); }).await?;
+
+        // the remote side can get EOF of the channel first, it is not a failure then
+        if state.has_capability(Capability::EndOfStream) {
+            control_channel.send(ChannelMessage::EndOfStream(end_offset)).await?;
+        }
+
+        if state.has_capability(Capability::Close) {
+            control_channel.send(ChannelMessage::Close).await?;
+        }
+
+        return Ok::<(), UpgradeError>(());
+    }).await;
+
+    match result {
+        Ok(Ok(_)) => state.trace.debug("channel closed"),
+        Ok(Err(error)) => state.trace.debug(&format!("cannot close the channel: {}", error)),
+        Err(_) => state.trace.debug("channel close timed out"),
+    };
+}
+
+/// Switch the `writes` to the new channel of the `upgrade` and notify the remote side.
+async fn start_upgrade(
+    upgrade: &mut Upgrade,
+    control_channel: &mut TMessageChannel<ChannelMessage>,
+    state: &ChannelState,
+) -> Result<(), UpgradeError> {
+    upgrade.trace.debug("got new channel");
+
+    state.set_upgrade_state(UpgradeState::ChannelReceived);
+
+    // upgrade for `writes` right away, the remote side reads
+    // the current channel up to the announced offset only
+    let (offset, start_offset) = match upgrade.writer.take() {
+        Some(new_writer) => {
+            let (mut previous_writer, offset, start_offset) = if upgrade.is_resume {
+                state.writer.lock().await?.resume(new_writer)
+            } else {
+                let is_failed = state.reader.lock().await?.is_interrupted();
+
+                state.writer.lock().await?.upgrade(new_writer, is_failed)
+            };
+
+            // the previous channel might have failed already
+            let _res = previous_writer.flush().await;
+
+            upgrade.previous_writer.replace(previous_writer);
+
+            (offset, start_offset)
+        },
+        None => (0, 0),
+    };
+
+    state.wake_writer();
+    state.set_upgrade_state(UpgradeState::WritesUpgraded);
+
+    upgrade.trace.debug(&format!("upgraded for writes at offset {}", offset));
+
+    control_channel.send(ChannelMessage::Sync(upgrade.our_sync_id.clone(), offset, start_offset)).await?;
+
+    state.set_upgrade_state(UpgradeState::SyncSent);
+
+    return Ok(());
+}
+
+/// Switch the `writes` back to the main channel and notify the remote side,
+/// returns `false` if there is no fallback channel.
+async fn fall_back(
+    state: &ChannelState,
+    control_channel: &mut TMessageChannel<ChannelMessage>,
+) -> Result<bool, UpgradeError> {
+    if !state.writer.lock().await?.fall_back() {
+        return Ok(false);
+    }
+
+    state.wake_writer();
+    state.set_fallen_back();
+
+    control_channel.send(ChannelMessage::Fallback).await?;
+
+    return Ok(true);
+}
+
+/// Write the unacknowledged data to the channel that replaced a failed one,
+/// never resolves if there is nothing to replay. The `writes` do the same,
+/// this makes sure the data is replayed even if nothing is written.
+async fn write_replay(
+    state: &ChannelState,
+) -> Result<(), UpgradeError> {
+    future::poll_fn(|cx| {
+        let mut writer = ready!(state.writer.poll_switch_lock(cx))?;
+
+        // recreated by the control loop once a new channel replaces the failed one
+        if !writer.has_replay() {
+            return Poll::Pending;
+        }
+
+        return writer.poll_replay(cx);
+    }).await?;
+
+    // the `writes` might wait for the replay too
+    state.wake_writer();
+
+    return Ok(());
+}
+
+async fn handle_control_message(
+    requests: &mut UpgradeRequests,
+    on_channel_event: &mut UnboundedReceiver<ChannelEvent>,
+    mut control_channel: TMessageChannel<ChannelMessage>,
+    state: ChannelState,
+    upgrade: &mut Option<Upgrade>,
+    resume_request: Option<UpgradeRequest>,
+) -> Result<(), UpgradeError> {
+    // on a new transport, the remote side must prove it owns the session first
+    let mut is_session_verified = resume_request.is_none();
+
+    match resume_request {
+        Some(request) => {
+            let their_session_id = state.get_remote_session_id().unwrap_or_default();
+
+            control_channel.send(ChannelMessage::Resume(their_session_id, state.session_id.clone())).await?;
+
+            let new_upgrade = upgrade.insert(Upgrade::new(request, true, None, &state.trace));
+
+            start_upgrade(new_upgrade, &mut control_channel, &state).await?;
+        },
+        None => {
+            let capabilities = state.local_capabilities.iter()
+                .map(|capability| { return capability.name().to_string(); })
+                .collect();
+
+            control_channel.send(ChannelMessage::Hello { version: PROTOCOL_VERSION, capabilities }).await?;
+            control_channel.send(ChannelMessage::Session(state.session_id.clone())).await?;
+        },
+    };
+
+    // sync id and the stream offsets of the remote side for the current upgrade,
+    // the remote `Sync` message can arrive before we receive a new channel
+    let mut their_sync: Option<(String, usize, usize)> = None;
+    // stream offset of the data read so far, acknowledged to the remote side
+    let mut read_offset = state.reader.lock().await?.read_offset();
+    // sync id of the last completed or rejected remote upgrade, a `Sync` message reusing it is stale
+    let mut their_previous_sync_id: Option<String> = None;
+    // the remote side must agree on the protocol in time, otherwise it is
+    // not an upgradable channel and the upgrades are rejected
+    let handshake_deadline = Instant::now() + state.handshake_timeout;
+    let mut is_handshake_expired = false;
+
+    loop {
+        // no upgrades until both sides agree on the protocol
+        let is_negotiated = state.capabilities.borrow().is_some();
+
+        // start the next queued upgrade once the previous one is done
+        if upgrade.is_none() && is_negotiated {
+            if let Some((request, deadline)) = requests.queued.pop_front() {
+                // the remote side supports only the first upgrade
+                if state.upgrade_count.load(Ordering::SeqCst) > 0 && !state.has_capability(Capability::RepeatedUpgrades) {
+                    let _res = request.on_complete.send(Err(
+                        UpgradeError::UnsupportedCapability(Capability::RepeatedUpgrades.name().to_string()),
+                    ));
+
+                    continue;
+                }
+
+                let new_upgrade = upgrade.insert(Upgrade::new(request, false, deadline, &state.trace));
+
+                start_upgrade(new_upgrade, &mut control_channel, &state).await?;
+            }
+        }
+
+        // the earliest of the deadlines of the current and the queued upgrades
+        let deadline = upgrade.as_ref()
+            .filter(|upgrade| { return !upgrade.is_paired() && upgrade.abort_reason.is_none(); })
+            .and_then(|upgrade| upgrade.deadline)
+            .into_iter()
+            .chain(requests.queued.iter().filter_map(|(_, deadline)| { return *deadline; }))
+            .min();
+        let handshake_deadline = Some(handshake_deadline)
+            .filter(|_| { return !is_negotiated && !is_handshake_expired; });
+
+        select! {
+            // the upgrades and the cancellations are handled in the order they are requested
+            maybe_request = requests.on_request.recv().fuse() => match maybe_request {
+                Some(HandleRequest::Upgrade(request)) => {
+                    if is_handshake_expired && !is_negotiated {
+                        let _res = request.on_complete.send(Err(UpgradeError::HandshakeTimeout));
+
+                        continue;
+                    }
+
+                    // the remote side must pair the upgrade in time, even if it waits in the queue
+                    let deadline = state.upgrade_timeout.map(|timeout| { return Instant::now() + timeout; });
+
+                    requests.queued.push_back((request, deadline));
+                },
+                Some(HandleRequest::Cancel) => {
+                    if upgrade.is_some() {
+                        abort_upgrade(upgrade, UpgradeError::Cancelled, &mut control_channel).await?;
+                    } else if let Some((request, _)) = requests.queued.pop_front() {
+                        let _res = request.on_complete.send(Err(UpgradeError::Cancelled));
+                    }
+                },
+                // the channel and its `UpgradeHandle`s are dropped
+                None => {
+                    close(&state, &mut control_channel).await;
+
+                    return Err(UpgradeError::Closed);
+                },
+            },
+            // the remote side has not supplied its counterpart of the new channel in time
+            _ = next_deadline(deadline).fuse() => {
+                let now = Instant::now();
+
+                if upgrade.as_ref().and_then(|upgrade| upgrade.deadline).is_some_and(|deadline| deadline <= now) {
+                    abort_upgrade(upgrade, UpgradeError::Timeout, &mut control_channel).await?;
+                }
+
+                // the remote side might never agree on the protocol
+                let error = if is_negotiated { UpgradeError::Timeout } else { UpgradeError::HandshakeTimeout };
+
+                requests.fail_expired(now, &error);
+            },
+            // the remote side has not sent the `Hello` message in time
+            _ = next_deadline(handshake_deadline).fuse() => {
+                state.trace.info("remote side has not agreed on the protocol in time");
+
+                is_handshake_expired = true;
+
+                for (request, _) in requests.queued.drain(..) {
+                    let _res = request.on_complete.send(Err(UpgradeError::HandshakeTimeout));
+                }
+            },
+            event = next_event(on_channel_event).fuse() => match event {
+                // the current channel has failed for `reads` or `writes`
+                ChannelEvent::Failure => {
+                    if fall_back(&state, &mut control_channel).await? {
+                        state.trace.info("channel failed, fell back to the main channel");
+                    } else if upgrade.is_none() && state.is_interrupted().await? {
+                        state.set_upgrade_state(UpgradeState::Interrupted);
+                    }
+                },
+                // the `writes` are shut down, the channel stays open for the data in flight
+                ChannelEvent::Shutdown(offset) => {
+                    state.trace.debug(&format!("writes shut down at offset {}", offset));
+
+                    control_channel.send(ChannelMessage::EndOfStream(offset)).await?;
+                },
+            },
+            // let the remote side drop the data we have read
+            _ = read_offset.changed().fuse() => {
+                let offset = *read_offset.borrow_and_update();
+
+                control_channel.send(ChannelMessage::DataAck(offset)).await?;
+            },
+            replay_result = write_replay(&state).fuse() => {
+                replay_result?;
+            },
+            message = control_channel.next().fuse() => {
+                // get next message
+                let message = match message {
+                    Some(result) => result,
+                    None => return Err(UpgradeError::ControlChannelClosed),
+                }?;
+
+                if !state.trace.is_silent() {
+                    state.trace.trace(&format!("got control message: {:?}", message));
+                }
+
+                // peers that do not support the `Hello` message send other messages first
+                if !is_negotiated && !matches!(message, ChannelMessage::Hello { .. }) {
+                    return Err(UpgradeError::UnsupportedVersion(0));
+                }
+
+                if !is_session_verified && !matches!(message, ChannelMessage::Resume(..)) {
+                    return Err(UpgradeError::SessionMismatch);
+                }
+
+                match message {
+                    // if message is `Hello`, use only the features both sides support
+                    ChannelMessage::Hello { version, capabilities } => {
+                        if is_negotiated {
+                            return Err(UpgradeError::UnexpectedMessage("Hello".to_string()));
+                        }
+
+                        let capabilities = negotiate(version, &capabilities, &state.local_capabilities)?;
+
+                        state.trace.debug(&format!("negotiated protocol version {} with capabilities {:?}", version, capabilities));
+
+                        state.reader.lock().await?.negotiate(&capabilities);
+                        state.writer.lock().await?.negotiate(&capabilities);
+
+                        // the `writes` might wait for the data acknowledgement
+                        state.wake_reader();
+                        state.wake_writer();
+
+                        state.capabilities.send_replace(Some(capabilities));
+                    },
+                    // if message is `Session`, remember the remote side to verify it on resumption
+                    ChannelMessage::Session(session_id) => {
+                        if state.get_remote_session_id().is_some() {
+                            return Err(UpgradeError::UnexpectedMessage("Session".to_string()));
+                        }
+
+                        state.remote_session_id.send_replace(Some(session_id));
+                    },
+                    // if message is `Resume`, the remote side resumes the session on this transport
+                    ChannelMessage::Resume(session_id, their_session_id) => {
+                        if is_session_verified {
+                            return Err(UpgradeError::UnexpectedMessage("Resume".to_string()));
+                        }
+
+                        if session_id != state.session_id || Some(their_session_id) != state.get_remote_session_id() {
+                            return Err(UpgradeError::SessionMismatch);
+                        }
+
+                        is_session_verified = true;
+                    },
+                    // if message is `Sync`, the remote side has switched its `writes` to a new
+                    // channel after writing `offset` bytes, once we have the new channel
+                    // too, we can upgrade for `reads`; if both sides initiate the upgrade
+                    // at the same time, the `Sync` messages cross and each side pairs the
+                    // remote one with its own, both sides end up with the same pair of ids
+                    ChannelMessage::Sync(sync_id, offset, start_offset) => {
+                        if their_sync.is_some() {
+                            return Err(UpgradeError::UnexpectedMessage("Sync".to_string()));
+                        }
+
+                        // a reflected or a replayed `Sync` message cannot belong to the current upgrade
+                        let is_our_sync_id = upgrade.as_ref()
+                            .map(|upgrade| upgrade.our_sync_id == sync_id)
+                            .unwrap_or(false);
+
+                        if sync_id.is_empty() || is_our_sync_id || Some(&sync_id) == their_previous_sync_id.as_ref() {
+                            return Err(UpgradeError::IdMismatch("Sync".to_string()));
+                        }
+
+                        their_sync.replace((sync_id, offset, start_offset));
+                    },
+                    // if message is `SyncAck`, the remote side will switch its
+                    // `reads` to the new channel, hence fully upgraded
+                    ChannelMessage::SyncAck(sync_id, sync_id2) => {
+                        let current_upgrade = match upgrade.as_mut() {
+                            Some(upgrade) => upgrade,
+                            None => return Err(UpgradeError::UnexpectedMessage("SyncAck".to_string())),
+                        };
+
+                        let their_sync_id = their_sync.as_ref().map(|(sync_id, _, _)| sync_id);
+
+                        if sync_id != current_upgrade.our_sync_id || Some(&sync_id2) != their_sync_id {
+                            return Err(UpgradeError::IdMismatch("SyncAck".to_string()));
+                        }
+
+                        current_upgrade.is_sync_ack_received = true;
+
+                        // the remote side reads the transport the session is resumed on
+                        if current_upgrade.is_resume {
+                            state.writer.lock().await?.confirm_resume();
+                            state.wake_writer();
+                        }
+                    },
+                    // if message is `Reject`, the remote side won't upgrade to a new channel,
+                    // either it cannot supply one or it confirms our `Abort` message
+                    ChannelMessage::Reject(sync_id) => {
+                        let is_current_upgrade = upgrade.as_ref()
+                            .map(|upgrade| upgrade.our_sync_id == sync_id)
+                            .unwrap_or(false);
+
+                        if !is_current_upgrade {
+                            return Err(UpgradeError::IdMismatch("Reject".to_string()));
+                        }
+
+                        if let Some(mut rejected_upgrade) = upgrade.take() {
+                            rejected_upgrade.trace.debug("upgrade rejected");
+
+                            // the remote side has no new channel, hence won't read the data
+                            // written to it, continue writing to the current channel
+                            if let Some(previous_writer) = rejected_upgrade.previous_writer.take() {
+                                state.writer.lock().await?.restore(previous_writer);
+                                state.wake_writer();
+                            }
+
+                            state.set_upgrade_state(UpgradeState::Idle);
+
+                            let reason = rejected_upgrade.abort_reason.take()
+                                .unwrap_or(UpgradeError::Rejected);
+
+                            rejected_upgrade.fail(reason);
+                        }
+                    },
+                    // if message is `Abort`, the remote side calls its upgrade off, unless we have
+                    // paired it already, it completes the upgrade once it gets our `Sync` message then
+                    ChannelMessage::Abort(sync_id) => {
+                        let is_their_upgrade = their_sync.as_ref()
+                            .map(|(their_sync_id, _, _)| their_sync_id == &sync_id)
+                            .unwrap_or(false);
+
+                        // the upgrade might have been rejected already
+                        if Some(&sync_id) == their_previous_sync_id.as_ref() {
+                            continue;
+                        }
+
+                        if !is_their_upgrade {
+                            return Err(UpgradeError::IdMismatch("Abort".to_string()));
+                        }
+
+                        let is_paired = upgrade.as_ref()
+                            .map(|upgrade| upgrade.is_paired())
+                            .unwrap_or(false);
+
+                        if !is_paired {
+                            state.trace.debug(&format!("remote upgrade {} aborted", sync_id));
+
+                            their_sync.take();
+                            their_previous_sync_id.replace(sync_id.clone());
+
+                            control_channel.send(ChannelMessage::Reject(sync_id)).await?;
+                        }
+                    },
+                    // if message is `Fallback`, the remote side has switched its `writes`
+                    // back to the main channel, switch the `reads` and `writes` too
+                    ChannelMessage::Fallback => {
+                        if state.reader.lock().await?.fall_back() {
+                            state.wake_reader();
+                            state.set_fallen_back();
+                        }
+
+                        if fall_back(&state, &mut control_channel).await? {
+                            state.trace.info("fell back to the main channel on remote request");
+                        }
+                    },
+                    // if message is `DataAck`, the remote side has read the data up to the offset
+                    ChannelMessage::DataAck(offset) => {
+                        state.writer.lock().await?.acknowledge(offset);
+                        state.wake_writer();
+                    },
+                    // if message is `Close`, the remote side is dropped, the `reads` get
+                    // the data sent so far and EOF, the session cannot be resumed
+                    ChannelMessage::Close => {
+                        state.trace.info("remote side closed the channel");
+
+                        state.reader.lock().await?.close();
+                        state.wake_reader();
+
+                        return Err(UpgradeError::Closed);
+                    },
+                    // if message is `EndOfStream`, the remote side has shut its `writes` down,
+                    // the `reads` get EOF once the data up to the offset is read
+                    ChannelMessage::EndOfStream(offset) => {
+                        if !state.reader.lock().await?.end_stream(offset) {
+                            return Err(UpgradeError::UnexpectedMessage("EndOfStream".to_string()));
+                        }
+
+                        state.wake_reader();
+                    },
+                };
+            }
+        }
+
+        let (current_upgrade, current_their_sync_id, their_offset, their_start_offset) = match (upgrade.as_mut(), their_sync.as_ref()) {
+            (Some(upgrade), Some((sync_id, offset, start_offset))) => (upgrade, sync_id.clone(), *offset, *start_offset),
+            _ => continue,
+        };
+
+        // upgrade for `reads`, the reader switches to the new channel once all
+        // the remote data on the current channel is read, or once it fails
+        if let Some(new_reader) = current_upgrade.reader.take() {
+            if current_upgrade.is_resume {
+                state.reader.lock().await?.resume(their_offset, their_start_offset, new_reader);
+            } else {
+                state.reader.lock().await?.handover(their_offset, their_start_offset, new_reader);
+            }
+
+            // the remote side reads the new channel, our `Abort` message is ignored if any
+            state.writer.lock().await?.confirm_upgrade();
+            current_upgrade.abort_reason.take();
+
+            state.wake_reader();
+            state.wake_writer();
+
+            current_upgrade.handover_offset = their_offset;
+
+            current_upgrade.trace.debug(&format!("upgraded for reads at offset {}, paired with remote upgrade {}", their_offset, current_their_sync_id));
+
+            control_channel.send(ChannelMessage::SyncAck(current_their_sync_id.clone(), current_upgrade.our_sync_id.clone())).await?;
+
+            state.set_upgrade_state(UpgradeState::ReadsUpgraded);
+        }
+
+        // fully upgraded, ready for the next channel
+        if current_upgrade.is_completed() {
+            current_upgrade.trace.info("upgrade completed");
+
+            if let Some(mut completed_upgrade) = upgrade.take() {
+                // the lost transport is not kept as the fallback
+                if completed_upgrade.is_resume {
+                    state.set_resumed(completed_upgrade.channel_id, &completed_upgrade.channel_label);
+                } else {
+                    if let Some(previous_writer) = completed_upgrade.previous_writer.take() {
+                        state.writer.lock().await?.retire(previous_writer);
+                    }
+
+                    state.set_upgraded(completed_upgrade.channel_id, &completed_upgrade.channel_label);
+                }
+                state.set_upgrade_state(UpgradeState::Completed);
+
+                completed_upgrade.complete(current_their_sync_id.clone());
+            }
+
+            their_sync.take();
+            their_previous_sync_id.replace(current_their_sync_id);
+        }
+    }
+}
+
+/// Whether the session can be resumed after the `error`, that is the transport
+/// is lost or the remote side has failed to prove it owns the session.
+fn is_resumable_error(error: &UpgradeError) -> bool {
+    return matches!(
+        error,
+        UpgradeError::ControlChannelClosed | UpgradeError::SessionMismatch | UpgradeError::Transport(..),
+    );
+}
+
+pub async fn handle_upgrade(
+    on_request: UnboundedReceiver<HandleRequest>,
+    mut on_resume: UnboundedReceiver<HandleRequest>,
+    mut on_channel_event: UnboundedReceiver<ChannelEvent>,
+    mut control_channel: Box<dyn Channel>,
+    state: ChannelState,
+) -> Result<(), UpgradeError> {
+    let mut requests = UpgradeRequests { on_request, queued: VecDeque::new() };
+    let mut upgrade = None;
+    let mut resume_request = None;
+
+    let error = loop {
+        // create control message channel stream
+        let framed_control_channel = MessageCodec::<ChannelMessage>::framed(control_channel);
+
+        let result = handle_control_message(
+            &mut requests,
+            &mut on_channel_event,
+            framed_control_channel,
+            state.clone(),
+            &mut upgrade,
+            resume_request.take(),
+        ).await;
+
+        let error = match result {
+            Ok(_) => UpgradeError::ControlChannelClosed,
+            Err(error) => error,
+        };
+
+        state.trace.debug(&format!("control channel stopped: {}", error));
+
+        let is_resumable = state.has_capability(Capability::Resume) && is_resumable_error(&error);
+
+        // the current upgrade or resumption is lost along with the control channel
+        if let Some(failed_upgrade) = upgrade.take() {
+            failed_upgrade.fail(error.clone());
+        }
+
+        if !is_resumable {
+            break error;
+        }
+
+        state.trace.info("transport lost, waiting for a new one to resume on");
+
+        state.set_upgrade_state(UpgradeState::Disconnected);
+
+        // the `reads` and `writes` wait for the new transport, unless
+        // the channel and its `UpgradeHandle`s are dropped
+        let maybe_request = loop {
+            select! {
+                maybe_request = on_resume.recv().fuse() => match maybe_request {
+                    Some(HandleRequest::Upgrade(request)) => break Ok(request),
+                    // the resumption cannot be called off
+                    Some(HandleRequest::Cancel) => continue,
+                    None => break Err(error),
+                },
+                // the upgrades wait for the session to be resumed
+                maybe_request = requests.on_request.recv().fuse() => match maybe_request {
+                    Some(request) => requests.push(request, state.upgrade_timeout),
+                    None => break Err(UpgradeError::Closed),
+                },
+            };
+        };
+
+        let request = match maybe_request {
+            Ok(request) => request,
+            Err(error) => break error,
+        };
+
+        let UpgradeRequest { channel, on_complete } = request;
+        let (data_channel, new_control_channel) = divide_channel(channel, state.lanes.clone());
+
+        control_channel = new_control_channel;
+        resume_request.replace(UpgradeRequest { channel: data_channel, on_complete });
+    };
+
+    // no way to switch to a new channel anymore
+    // a poisoned half fails the `reads` or `writes` already
+    if let Ok(mut reader) = state.reader.lock().await {
+        reader.abort_recovery();
+    }
+
+    if let Ok(mut writer) = state.writer.lock().await {
+        writer.abort_recovery();
+    }
+
+    state.wake_reader();
+    state.wake_writer();
+
+    if error == UpgradeError::Closed {
+        state.trace.debug("channel closed, no more upgrades");
+    } else {
+        state.trace.warn(&format!("upgrades failed: {}", error));
+    }
+
+    state.set_upgrade_state(UpgradeState::Failed(error.to_string()));
+
+    // fail all pending upgrades and resumptions
+    requests.fail(&error);
+    on_resume.close();
+
+    while let Ok(request) = on_resume.try_recv() {
+        if let HandleRequest::Upgrade(request) = request {
+            let _res = request.on_complete.send(Err(error.clone()));
+        }
+    }
+
+    return Err(error);
+}
@@ -2,4 +2,3 @@ mod async_read_impl;
 mod async_write_impl;
 mod channel_impl;
 mod upgradable_channel_impl;
-pub use upgradable_channel_impl::handle_upgrade;
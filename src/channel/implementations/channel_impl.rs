@@ -3,13 +3,13 @@ use connection_utils::Channel;
 use crate::channel::UpgradableChannel;
 
 impl Channel for UpgradableChannel {
+    // the id and label stay the same across upgrades, see
+    // `TUpgradableChannel` for the currently active channel ones
     fn id(&self) -> u16 {
-        // TODO: should be dependend on channel2 too?
         return self.id;
     }
 
     fn label(&self) ->  &String {
-        // TODO: should be dependend on channel2 too?
         return &self.label;
     }
 }
@@ -0,0 +1,37 @@
+use connection_utils::Channel;
+
+use crate::channel::{UpgradeHandle, TUpgradeResult};
+
+/// Token to resume an `UpgradableChannel` session on a new transport
+/// after both the active and the control channels are lost.
+#[derive(Clone)]
+pub struct ResumptionToken {
+    session_id: String,
+    resume_handle: UpgradeHandle,
+}
+
+impl ResumptionToken {
+    pub fn new(
+        session_id: impl AsRef<str> + ToString,
+        resume_handle: UpgradeHandle,
+    ) -> ResumptionToken {
+        return ResumptionToken {
+            session_id: session_id.to_string(),
+            resume_handle,
+        };
+    }
+
+    /// Id of the local side of the session, exchanged with the remote side
+    /// once the control channel is set up.
+    pub fn session_id(&self) -> &String {
+        return &self.session_id;
+    }
+
+    /// Resume the session on the `new_channel`, see `UpgradableChannel::resume`.
+    pub async fn resume(
+        &self,
+        new_channel: Box<dyn Channel>,
+    ) -> TUpgradeResult {
+        return self.resume_handle.upgrade(new_channel).await;
+    }
+}
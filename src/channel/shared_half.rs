@@ -0,0 +1,341 @@
+use std::{cell::UnsafeCell, future, io, marker::PhantomData, ops::{Deref, DerefMut}, sync::atomic::{AtomicBool, AtomicU8, Ordering}, task::{Context, Poll}, thread};
+
+use futures::task::AtomicWaker;
+
+// nobody holds the half
+const IDLE: u8 = 0;
+// a poll of the `UpgradableChannel` holds the half
+const POLLED: u8 = 1;
+// the upgrade task holds the half to switch the channels
+const SWITCHING: u8 = 2;
+// a holder has panicked, the half might be left inconsistent
+const POISONED: u8 = 3;
+
+/// Reader or writer half of the active channel, owned by the `SharedHalf` and lent
+/// to the `UpgradableChannel` polls and to the upgrade task in turn, the atomic `state`
+/// tells who holds it. The polls take it with a single compare-and-swap and never block,
+/// the upgrade task holds it only to switch the channels. A side that finds the half
+/// taken is woken up once the other one returns it, neither holds it across an `await`.
+pub struct SharedHalf<T> {
+    half: UnsafeCell<T>,
+    state: AtomicU8,
+    // the last poll of the half, woken up once the active channel changes
+    poll_waker: AtomicWaker,
+    // the upgrade task waiting for a poll to return the half
+    switch_waker: AtomicWaker,
+    // the polls or the upgrade task have found the half taken by the other side
+    is_poll_waiting: AtomicBool,
+    is_switch_waiting: AtomicBool,
+}
+
+// the `state` lends the half to a single holder at a time, same as a lock
+unsafe impl<T: Send> Sync for SharedHalf<T> {}
+
+impl<T> SharedHalf<T> {
+    pub fn new(half: T) -> SharedHalf<T> {
+        return SharedHalf {
+            half: UnsafeCell::new(half),
+            state: AtomicU8::new(IDLE),
+            poll_waker: AtomicWaker::new(),
+            switch_waker: AtomicWaker::new(),
+            is_poll_waiting: AtomicBool::new(false),
+            is_switch_waiting: AtomicBool::new(false),
+        };
+    }
+
+    /// Take the half from a poll, pending while the upgrade task holds it.
+    /// Fails if a holder has panicked while holding it.
+    pub fn poll_lock(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<SharedHalfGuard<'_, T>>> {
+        // register before taking the half, the upgrade
+        // task might return it right after the attempt
+        self.poll_waker.register(cx.waker());
+
+        return self.poll_take(POLLED, &self.is_poll_waiting);
+    }
+
+    /// Take the half from the upgrade task, pending while a poll holds it.
+    pub fn poll_switch_lock(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<SharedHalfGuard<'_, T>>> {
+        self.switch_waker.register(cx.waker());
+
+        return self.poll_take(SWITCHING, &self.is_switch_waiting);
+    }
+
+    /// Take the half from the upgrade task, the polls hold it only while polling.
+    /// Fails if a holder has panicked while holding it.
+    pub async fn lock(&self) -> io::Result<SharedHalfGuard<'_, T>> {
+        return future::poll_fn(|cx| { return self.poll_switch_lock(cx); }).await;
+    }
+
+    /// Wake up the last poll of the half.
+    pub fn wake(&self) {
+        self.poll_waker.wake();
+    }
+
+    /// Take the half for the `holder`, flags it as waiting if the other side holds it.
+    fn poll_take(
+        &self,
+        holder: u8,
+        is_waiting: &AtomicBool,
+    ) -> Poll<io::Result<SharedHalfGuard<'_, T>>> {
+        if let Some(result) = self.try_take(holder) {
+            return Poll::Ready(result);
+        }
+
+        is_waiting.store(true, Ordering::SeqCst);
+
+        // the half might have been returned before the flag was set
+        return match self.try_take(holder) {
+            Some(result) => Poll::Ready(result),
+            None => Poll::Pending,
+        };
+    }
+
+    /// Take the half for the `holder`, `None` if the other side holds it.
+    fn try_take(
+        &self,
+        holder: u8,
+    ) -> Option<io::Result<SharedHalfGuard<'_, T>>> {
+        return match self.state.compare_exchange(IDLE, holder, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => Some(Ok(SharedHalfGuard { shared_half: self, _half: PhantomData })),
+            Err(POISONED) => Some(Err(io::Error::other("Channel half poisoned by a panic."))),
+            Err(_) => None,
+        };
+    }
+}
+
+/// The half lent to a poll or to the upgrade task, returned once dropped.
+pub struct SharedHalfGuard<'a, T> {
+    shared_half: &'a SharedHalf<T>,
+    // shared between threads only if the half is, same as a `MutexGuard`
+    _half: PhantomData<&'a mut T>,
+}
+
+impl<'a, T> Deref for SharedHalfGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // the `state` lends the half to this guard only
+        return unsafe { &*self.shared_half.half.get() };
+    }
+}
+
+impl<'a, T> DerefMut for SharedHalfGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // the `state` lends the half to this guard only
+        return unsafe { &mut *self.shared_half.half.get() };
+    }
+}
+
+impl<'a, T> Drop for SharedHalfGuard<'a, T> {
+    fn drop(&mut self) {
+        let shared_half = self.shared_half;
+
+        // return the half first, the woken up side takes it right away
+        let state = if thread::panicking() { POISONED } else { IDLE };
+
+        shared_half.state.store(state, Ordering::SeqCst);
+
+        // wake up both sides, the switches of the upgrade task
+        // might be waiting for each other as well
+        wake_waiting(&shared_half.is_switch_waiting, &shared_half.switch_waker);
+        wake_waiting(&shared_half.is_poll_waiting, &shared_half.poll_waker);
+    }
+}
+
+/// Wake up the side that has found the half taken, if any. The flag is
+/// loaded first, nobody is waiting on the hot path of the polls.
+fn wake_waiting(
+    is_waiting: &AtomicBool,
+    waker: &AtomicWaker,
+) {
+    if is_waiting.load(Ordering::SeqCst) && is_waiting.swap(false, Ordering::SeqCst) {
+        waker.wake();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, thread, sync::{Arc, atomic::{AtomicUsize, Ordering}}, task::{Context, Poll}};
+
+    use futures::{executor, future, task::{waker, ArcWake}};
+
+    use super::SharedHalf;
+
+    struct WakeCounter(AtomicUsize);
+
+    impl ArcWake for WakeCounter {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn wakes_poll_up_once_unlocked() {
+        let shared_half = SharedHalf::new(0);
+        let wake_counter = Arc::new(WakeCounter(AtomicUsize::new(0)));
+        let waker = waker(wake_counter.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut half = executor::block_on(shared_half.lock())
+            .expect("Cannot lock.");
+
+        assert!(
+            shared_half.poll_lock(&mut cx).is_pending(),
+            "Must be pending while the upgrade task holds the lock.",
+        );
+
+        *half += 1;
+
+        drop(half);
+
+        assert_eq!(
+            wake_counter.0.load(Ordering::SeqCst),
+            1,
+            "Must wake the poll up once unlocked.",
+        );
+
+        match shared_half.poll_lock(&mut cx) {
+            Poll::Ready(Ok(half)) => {
+                assert_eq!(
+                    *half,
+                    1,
+                    "Must see the changes of the upgrade task.",
+                );
+            },
+            _ => panic!("Must lock once unlocked."),
+        };
+
+        // nobody is waiting for the lock anymore
+        drop(executor::block_on(shared_half.lock()));
+
+        assert_eq!(
+            wake_counter.0.load(Ordering::SeqCst),
+            1,
+            "Must not wake the poll up if it has not waited.",
+        );
+    }
+
+    #[test]
+    fn fails_poll_on_poisoned_lock() {
+        let shared_half = Arc::new(SharedHalf::new(0));
+        let waker = waker(Arc::new(WakeCounter(AtomicUsize::new(0))));
+        let mut cx = Context::from_waker(&waker);
+
+        let panicked_half = shared_half.clone();
+
+        // the upgrade task panics while holding the lock
+        let result = thread::spawn(move || {
+            let _half = executor::block_on(panicked_half.lock());
+
+            panic!("upgrade task panicked");
+        }).join();
+
+        assert!(
+            result.is_err(),
+            "Must panic while holding the lock.",
+        );
+
+        assert!(
+            matches!(shared_half.poll_lock(&mut cx), Poll::Ready(Err(error)) if error.kind() == io::ErrorKind::Other),
+            "Must fail the poll instead of panicking.",
+        );
+
+        assert!(
+            matches!(executor::block_on(shared_half.lock()), Err(error) if error.kind() == io::ErrorKind::Other),
+            "Must fail the upgrade task instead of panicking.",
+        );
+    }
+
+    #[test]
+    fn wakes_upgrade_task_up_once_polled() {
+        let shared_half = SharedHalf::new(0);
+        let wake_counter = Arc::new(WakeCounter(AtomicUsize::new(0)));
+        let waker = waker(wake_counter.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let half = match shared_half.poll_lock(&mut cx) {
+            Poll::Ready(Ok(half)) => half,
+            _ => panic!("Must lock if nobody holds the half."),
+        };
+
+        assert!(
+            shared_half.poll_switch_lock(&mut cx).is_pending(),
+            "Must be pending while a poll holds the half.",
+        );
+
+        drop(half);
+
+        assert_eq!(
+            wake_counter.0.load(Ordering::SeqCst),
+            1,
+            "Must wake the upgrade task up once the poll returns the half.",
+        );
+
+        assert!(
+            matches!(shared_half.poll_switch_lock(&mut cx), Poll::Ready(Ok(_))),
+            "Must lock once the poll returns the half.",
+        );
+    }
+
+    #[test]
+    fn lends_half_to_one_holder_at_a_time() {
+        const ITERATIONS: usize = 10_000;
+
+        // the value and whether a holder is in the middle of changing it
+        let shared_half = Arc::new(SharedHalf::new((0, false)));
+
+        let take_turn = |half: &mut (usize, bool)| {
+            assert!(
+                !half.1,
+                "Must not lend the half to both sides at once.",
+            );
+
+            half.1 = true;
+
+            let value = half.0;
+
+            // let the other side try to take the half meanwhile
+            thread::yield_now();
+
+            half.0 = value + 1;
+            half.1 = false;
+        };
+
+        let polled_half = shared_half.clone();
+
+        // a poll and the upgrade task take the half in turn, from different threads
+        let polls = thread::spawn(move || {
+            for _ in 0..ITERATIONS {
+                let mut half = executor::block_on(future::poll_fn(|cx| { return polled_half.poll_lock(cx); }))
+                    .expect("Cannot lock from the poll.");
+
+                take_turn(&mut half);
+            }
+        });
+
+        for _ in 0..ITERATIONS {
+            let mut half = executor::block_on(shared_half.lock())
+                .expect("Cannot lock from the upgrade task.");
+
+            take_turn(&mut half);
+        }
+
+        polls.join()
+            .expect("Poll thread panicked.");
+
+        let half = executor::block_on(shared_half.lock())
+            .expect("Cannot lock.");
+
+        assert_eq!(
+            half.0,
+            2 * ITERATIONS,
+            "Must keep the changes of both sides.",
+        );
+    }
+}
@@ -0,0 +1,209 @@
+use std::time::Duration;
+
+use connection_utils::Channel;
+
+use crate::{channel::{UpgradableChannel, UpgradableChannelOptions, UpgradeHandle}, BufferOptions, Spawner, TraceSink, TUpgradableChannel};
+
+/// Builder of an `UpgradableChannel`, see `UpgradableChannel::builder`.
+#[derive(Debug, Clone, Default)]
+pub struct UpgradableChannelBuilder {
+    // the label of the main channel if not set
+    name: Option<String>,
+    options: UpgradableChannelOptions,
+}
+
+impl UpgradableChannelBuilder {
+    /// Name of the channel in the traces, the label of the main channel by default.
+    pub fn name(
+        self,
+        name: impl AsRef<str> + ToString,
+    ) -> UpgradableChannelBuilder {
+        return UpgradableChannelBuilder {
+            name: Some(name.to_string()),
+            ..self
+        };
+    }
+
+    /// Replace all the options set so far with the `options`.
+    pub fn options(
+        self,
+        options: UpgradableChannelOptions,
+    ) -> UpgradableChannelBuilder {
+        return UpgradableChannelBuilder {
+            options,
+            ..self
+        };
+    }
+
+    /// See `UpgradableChannelOptions::fallback`.
+    pub fn fallback(
+        self,
+        fallback: bool,
+    ) -> UpgradableChannelBuilder {
+        return UpgradableChannelBuilder {
+            options: self.options.fallback(fallback),
+            ..self
+        };
+    }
+
+    /// See `UpgradableChannelOptions::replay_buffer_size`.
+    pub fn replay_buffer_size(
+        self,
+        replay_buffer_size: usize,
+    ) -> UpgradableChannelBuilder {
+        return UpgradableChannelBuilder {
+            options: self.options.replay_buffer_size(replay_buffer_size),
+            ..self
+        };
+    }
+
+    /// See `UpgradableChannelOptions::resumable`.
+    pub fn resumable(
+        self,
+        resumable: bool,
+    ) -> UpgradableChannelBuilder {
+        return UpgradableChannelBuilder {
+            options: self.options.resumable(resumable),
+            ..self
+        };
+    }
+
+    /// See `UpgradableChannelOptions::upgrade_timeout`.
+    pub fn upgrade_timeout(
+        self,
+        upgrade_timeout: Duration,
+    ) -> UpgradableChannelBuilder {
+        return UpgradableChannelBuilder {
+            options: self.options.upgrade_timeout(upgrade_timeout),
+            ..self
+        };
+    }
+
+    /// See `UpgradableChannelOptions::handshake_timeout`.
+    pub fn handshake_timeout(
+        self,
+        handshake_timeout: Duration,
+    ) -> UpgradableChannelBuilder {
+        return UpgradableChannelBuilder {
+            options: self.options.handshake_timeout(handshake_timeout),
+            ..self
+        };
+    }
+
+    /// See `UpgradableChannelOptions::close_timeout`.
+    pub fn close_timeout(
+        self,
+        close_timeout: Duration,
+    ) -> UpgradableChannelBuilder {
+        return UpgradableChannelBuilder {
+            options: self.options.close_timeout(close_timeout),
+            ..self
+        };
+    }
+
+    /// See `UpgradableChannelOptions::buffers`.
+    pub fn buffers(
+        self,
+        buffers: BufferOptions,
+    ) -> UpgradableChannelBuilder {
+        return UpgradableChannelBuilder {
+            options: self.options.buffers(buffers),
+            ..self
+        };
+    }
+
+    /// See `UpgradableChannelOptions::control_window_size`.
+    pub fn control_window_size(
+        self,
+        control_window_size: usize,
+    ) -> UpgradableChannelBuilder {
+        return UpgradableChannelBuilder {
+            options: self.options.control_window_size(control_window_size),
+            ..self
+        };
+    }
+
+    /// See `UpgradableChannelOptions::trace`.
+    pub fn trace(
+        self,
+        trace: TraceSink,
+    ) -> UpgradableChannelBuilder {
+        return UpgradableChannelBuilder {
+            options: self.options.trace(trace),
+            ..self
+        };
+    }
+
+    /// See `UpgradableChannelOptions::spawner`.
+    pub fn spawner(
+        self,
+        spawner: Spawner,
+    ) -> UpgradableChannelBuilder {
+        return UpgradableChannelBuilder {
+            options: self.options.spawner(spawner),
+            ..self
+        };
+    }
+
+    pub fn get_name(&self) -> Option<&String> {
+        return self.name.as_ref();
+    }
+
+    pub fn get_options(&self) -> &UpgradableChannelOptions {
+        return &self.options;
+    }
+
+    /// Create the channel on top of the `main_channel`, see `UpgradableChannel::new`.
+    pub fn build(
+        self,
+        main_channel: Box<dyn Channel>,
+    ) -> (UpgradeHandle, Box<dyn TUpgradableChannel>) {
+        let name = self.name
+            .unwrap_or_else(|| { return main_channel.label().clone(); });
+
+        return UpgradableChannel::new_with_options(name, main_channel, self.options);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{channel::UpgradableChannelOptions, BufferOptions};
+
+    use super::UpgradableChannelBuilder;
+
+    #[test]
+    fn sets_options() {
+        let builder = UpgradableChannelBuilder::default()
+            .name("channel")
+            .fallback(true)
+            .upgrade_timeout(Duration::from_secs(5))
+            .handshake_timeout(Duration::from_secs(1))
+            .buffers(BufferOptions::default().max_buffered_size(1_024))
+            .control_window_size(256);
+
+        assert_eq!(
+            builder.get_name(),
+            Some(&"channel".to_string()),
+            "Must set the name.",
+        );
+
+        assert_eq!(
+            builder.get_options(),
+            &UpgradableChannelOptions::default()
+                .fallback(true)
+                .upgrade_timeout(Duration::from_secs(5))
+                .handshake_timeout(Duration::from_secs(1))
+                .buffers(BufferOptions::default().max_buffered_size(1_024))
+                .control_window_size(256),
+            "Must set the options.",
+        );
+
+        assert_eq!(
+            builder.options(UpgradableChannelOptions::default()).get_options(),
+            &UpgradableChannelOptions::default(),
+            "Must replace the options.",
+        );
+    }
+}
@@ -0,0 +1,220 @@
+use std::{cmp, time::Duration};
+
+use crate::{channel::Capability, BufferOptions, Spawner, TraceSink};
+
+/// How long a dropped channel waits for the remote side to read
+/// the data written so far unless configured otherwise.
+pub(crate) const DEFAULT_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the remote side has to agree on the protocol unless configured otherwise.
+pub(crate) const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Options of an `UpgradableChannel`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct UpgradableChannelOptions {
+    fallback: bool,
+    replay_buffer_size: usize,
+    resumable: bool,
+    upgrade_timeout: Option<Duration>,
+    handshake_timeout: Option<Duration>,
+    close_timeout: Option<Duration>,
+    buffers: BufferOptions,
+    // receive window of the control lane, the one of the `buffers` if not set
+    control_window_size: Option<usize>,
+    trace: TraceSink,
+    spawner: Spawner,
+}
+
+impl UpgradableChannelOptions {
+    /// Keep the main channel open and idle after an upgrade, and fall back
+    /// to it once the upgraded channel fails with an I/O error or EOF.
+    pub fn fallback(
+        self,
+        fallback: bool,
+    ) -> UpgradableChannelOptions {
+        return UpgradableChannelOptions {
+            fallback,
+            ..self
+        };
+    }
+
+    /// Keep up to `replay_buffer_size` bytes of the written data until the remote
+    /// side acknowledges it, the unacknowledged data is replayed on the channel that
+    /// replaces a failed one, `0` disables the replay. Once the buffer is full,
+    /// the `writes` wait for the remote side to read the data.
+    pub fn replay_buffer_size(
+        self,
+        replay_buffer_size: usize,
+    ) -> UpgradableChannelOptions {
+        return UpgradableChannelOptions {
+            replay_buffer_size,
+            ..self
+        };
+    }
+
+    /// Keep the session alive once both the active and the control channels
+    /// are lost, until it is resumed on a new transport with the `ResumptionToken`.
+    /// The data in flight is lost unless the replay is enabled too.
+    pub fn resumable(
+        self,
+        resumable: bool,
+    ) -> UpgradableChannelOptions {
+        return UpgradableChannelOptions {
+            resumable,
+            ..self
+        };
+    }
+
+    /// Call an upgrade off if the remote side does not supply its counterpart
+    /// of the new channel within the `upgrade_timeout` since the upgrade is
+    /// requested, both sides keep using the current channel then. Upgrades never
+    /// time out by default.
+    pub fn upgrade_timeout(
+        self,
+        upgrade_timeout: Duration,
+    ) -> UpgradableChannelOptions {
+        return UpgradableChannelOptions {
+            upgrade_timeout: Some(upgrade_timeout),
+            ..self
+        };
+    }
+
+    /// Fail the upgrades with `UpgradeError::HandshakeTimeout` if the remote side does not
+    /// agree on the protocol within the `handshake_timeout` since the channel is created,
+    /// or since the session is resumed. Ten seconds by default.
+    pub fn handshake_timeout(
+        self,
+        handshake_timeout: Duration,
+    ) -> UpgradableChannelOptions {
+        return UpgradableChannelOptions {
+            handshake_timeout: Some(handshake_timeout),
+            ..self
+        };
+    }
+
+    /// Once the channel and its `UpgradeHandle`s are dropped, wait for up to the
+    /// `close_timeout` for the remote side to read the data written so far,
+    /// the background tasks are stopped then even if the remote side does not read.
+    /// Five seconds by default.
+    pub fn close_timeout(
+        self,
+        close_timeout: Duration,
+    ) -> UpgradableChannelOptions {
+        return UpgradableChannelOptions {
+            close_timeout: Some(close_timeout),
+            ..self
+        };
+    }
+
+    /// Sizes of the buffers of the data and the control channels multiplexed
+    /// over the main channel, and over the transports the session is resumed on,
+    /// along with the limit of the data written to an unpaired upgrade.
+    pub fn buffers(
+        self,
+        buffers: BufferOptions,
+    ) -> UpgradableChannelOptions {
+        return UpgradableChannelOptions {
+            buffers,
+            ..self
+        };
+    }
+
+    /// Receive window of the control lane multiplexed along with the data over
+    /// the main channel, the control messages are small and rare, but a data ack
+    /// is sent on every read. Same as the window of the data lane by default.
+    pub fn control_window_size(
+        self,
+        control_window_size: usize,
+    ) -> UpgradableChannelOptions {
+        return UpgradableChannelOptions {
+            control_window_size: Some(cmp::max(control_window_size, 1)),
+            ..self
+        };
+    }
+
+    /// Trace the channel, its upgrades and its data under the `trace`, nothing is traced by default.
+    pub fn trace(
+        self,
+        trace: TraceSink,
+    ) -> UpgradableChannelOptions {
+        return UpgradableChannelOptions {
+            trace,
+            ..self
+        };
+    }
+
+    /// Spawner of the background tasks of the channel, `tokio::spawn` by default.
+    pub fn spawner(
+        self,
+        spawner: Spawner,
+    ) -> UpgradableChannelOptions {
+        return UpgradableChannelOptions {
+            spawner,
+            ..self
+        };
+    }
+
+    pub fn is_fallback(&self) -> bool {
+        return self.fallback;
+    }
+
+    pub fn is_replay(&self) -> bool {
+        return self.replay_buffer_size > 0;
+    }
+
+    pub fn is_resumable(&self) -> bool {
+        return self.resumable;
+    }
+
+    pub fn get_replay_buffer_size(&self) -> usize {
+        return self.replay_buffer_size;
+    }
+
+    pub fn get_upgrade_timeout(&self) -> Option<Duration> {
+        return self.upgrade_timeout;
+    }
+
+    pub fn get_handshake_timeout(&self) -> Duration {
+        return self.handshake_timeout.unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT);
+    }
+
+    pub fn get_close_timeout(&self) -> Duration {
+        return self.close_timeout.unwrap_or(DEFAULT_CLOSE_TIMEOUT);
+    }
+
+    pub fn get_buffers(&self) -> &BufferOptions {
+        return &self.buffers;
+    }
+
+    pub fn get_control_window_size(&self) -> usize {
+        return self.control_window_size
+            .unwrap_or(self.buffers.get_max_buffered_size());
+    }
+
+    pub fn get_trace(&self) -> &TraceSink {
+        return &self.trace;
+    }
+
+    pub fn get_spawner(&self) -> &Spawner {
+        return &self.spawner;
+    }
+
+    /// Capabilities advertised to the remote side.
+    pub fn get_capabilities(&self) -> Vec<Capability> {
+        let mut capabilities = vec![Capability::OffsetHandover, Capability::RepeatedUpgrades, Capability::Close, Capability::EndOfStream];
+
+        if self.is_fallback() {
+            capabilities.push(Capability::Fallback);
+        }
+
+        if self.is_replay() {
+            capabilities.push(Capability::Replay);
+        }
+
+        if self.is_resumable() {
+            capabilities.push(Capability::Resume);
+        }
+
+        return capabilities;
+    }
+}
@@ -0,0 +1,72 @@
+use connection_utils::Channel;
+use tokio::sync::{mpsc::UnboundedSender, oneshot};
+
+use crate::{channel::UpgradeReport, errors::UpgradeError, Tasks};
+
+pub type TUpgradeResult = Result<UpgradeReport, UpgradeError>;
+
+/// Request to upgrade to a new channel, sent to the upgrade task.
+pub struct UpgradeRequest {
+    pub channel: Box<dyn Channel>,
+    pub on_complete: oneshot::Sender<TUpgradeResult>,
+}
+
+/// Request made with the `UpgradeHandle`, the upgrade task gets them in the order they are made.
+pub enum HandleRequest {
+    Upgrade(UpgradeRequest),
+    // call the upgrade in progress off, or the next queued one if none is in progress
+    Cancel,
+}
+
+/// Handle to upgrade an `UpgradableChannel` to new channels.
+#[derive(Clone)]
+pub struct UpgradeHandle {
+    sender: UnboundedSender<HandleRequest>,
+    tasks: Tasks,
+}
+
+impl UpgradeHandle {
+    pub fn new(
+        sender: UnboundedSender<HandleRequest>,
+        tasks: Tasks,
+    ) -> UpgradeHandle {
+        return UpgradeHandle { sender, tasks };
+    }
+
+    /// Upgrade to the `channel`, the remote side must supply its counterpart.
+    /// 
+    /// Resolves once both `reads` and `writes` are switched to the new channel on
+    /// both sides, or fails if the remote side rejects the upgrade, the control
+    /// channel gets closed, the upgrade is cancelled or times out, or the remote
+    /// side does not follow the protocol. Concurrent calls are queued and processed in order.
+    pub async fn upgrade(
+        &self,
+        channel: Box<dyn Channel>,
+    ) -> TUpgradeResult {
+        let (on_complete, on_complete_receiver) = oneshot::channel();
+
+        self.sender.send(HandleRequest::Upgrade(UpgradeRequest { channel, on_complete }))
+            .map_err(|_| { return UpgradeError::ControlChannelClosed; })?;
+
+        return on_complete_receiver.await
+            .map_err(|_| { return UpgradeError::ControlChannelClosed; })?;
+    }
+
+    /// Call the upgrade in progress off, unless the remote side has paired it already.
+    ///
+    /// Both sides keep using the current channel and the new channel is dropped,
+    /// the `upgrade` call fails with `UpgradeError::Cancelled` then. Otherwise
+    /// the upgrade completes as usual. An upgrade requested before is called off
+    /// even if it has not started yet. Does nothing if no upgrade is requested.
+    pub fn cancel_upgrade(&self) {
+        // the upgrade task might have stopped already
+        let _res = self.sender.send(HandleRequest::Cancel);
+    }
+
+    /// Background tasks of the channel, including the ones of the multiplexed lanes.
+    /// Aborting them stops the channel right away, joining them waits until the
+    /// channel and its `UpgradeHandle`s are dropped and the tasks stop by themselves.
+    pub fn tasks(&self) -> &Tasks {
+        return &self.tasks;
+    }
+}
@@ -0,0 +1,62 @@
+/// Summary of a completed upgrade.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpgradeReport {
+    channel_id: u16,
+    channel_label: String,
+    local_sync_id: String,
+    remote_sync_id: String,
+    handover_offset: usize,
+}
+
+impl UpgradeReport {
+    pub fn new(
+        channel_id: u16,
+        channel_label: impl AsRef<str> + ToString,
+        local_sync_id: impl AsRef<str> + ToString,
+        remote_sync_id: impl AsRef<str> + ToString,
+        handover_offset: usize,
+    ) -> UpgradeReport {
+        return UpgradeReport {
+            channel_id,
+            channel_label: channel_label.to_string(),
+            local_sync_id: local_sync_id.to_string(),
+            remote_sync_id: remote_sync_id.to_string(),
+            handover_offset,
+        };
+    }
+
+    /// Id of the channel that was upgraded to.
+    pub fn channel_id(&self) -> u16 {
+        return self.channel_id;
+    }
+
+    /// Label of the channel that was upgraded to.
+    pub fn channel_label(&self) -> &String {
+        return &self.channel_label;
+    }
+
+    /// Sync id of the upgrade on the local side.
+    pub fn local_sync_id(&self) -> &String {
+        return &self.local_sync_id;
+    }
+
+    /// Sync id of the upgrade on the remote side.
+    pub fn remote_sync_id(&self) -> &String {
+        return &self.remote_sync_id;
+    }
+
+    /// Id of the upgrade, the same on both sides no matter which side has
+    /// initiated the upgrade first: the sync ids of both sides in order.
+    pub fn upgrade_id(&self) -> String {
+        if self.local_sync_id <= self.remote_sync_id {
+            return format!("{}:{}", self.local_sync_id, self.remote_sync_id);
+        }
+
+        return format!("{}:{}", self.remote_sync_id, self.local_sync_id);
+    }
+
+    /// Number of bytes the remote side has written to the previous channel.
+    pub fn handover_offset(&self) -> usize {
+        return self.handover_offset;
+    }
+}
@@ -0,0 +1,22 @@
+/// Phase of the current upgrade, in the order of transitions.
+#[derive(Debug, Clone, PartialEq)]
+pub enum UpgradeState {
+    /// No upgrade has been started yet.
+    Idle,
+    /// Got a new channel to upgrade to.
+    ChannelReceived,
+    /// All `writes` go to the new channel.
+    WritesUpgraded,
+    /// Notified the remote side about the new channel and the write offset.
+    SyncSent,
+    /// `Reads` switch to the new channel after the remote write offset.
+    ReadsUpgraded,
+    /// Both sides are fully upgraded to the new channel.
+    Completed,
+    /// The active channel has failed, waiting for a new channel to upgrade to.
+    Interrupted,
+    /// The active and the control channels are lost, waiting for a new transport to resume on.
+    Disconnected,
+    /// The upgrade task has stopped, no more upgrades are possible.
+    Failed(String),
+}
@@ -0,0 +1,135 @@
+use std::{pin::Pin, marker::PhantomData};
+
+use bytes::BytesMut;
+use connection_utils::Channel;
+use serde::{Serialize, de::DeserializeOwned};
+use tokio_util::codec::{Decoder, Encoder, Framed, LengthDelimitedCodec};
+
+use crate::errors::UpgradeError;
+
+pub type TMessageChannel<T> = Framed<Pin<Box<dyn Channel>>, MessageCodec<T>>;
+
+/// Length delimited JSON codec, same framing as the `GenericCodec` of `cs-utils`,
+/// but a malformed message fails the stream with an `UpgradeError::Decode` instead of a panic.
+#[derive(Debug)]
+pub struct MessageCodec<T: Serialize + DeserializeOwned> {
+    length_delimited_codec: LengthDelimitedCodec,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Serialize + DeserializeOwned> MessageCodec<T> {
+    pub fn new() -> MessageCodec<T> {
+        return MessageCodec {
+            length_delimited_codec: LengthDelimitedCodec::new(),
+            _phantom: PhantomData,
+        };
+    }
+
+    pub fn framed(channel: Box<dyn Channel>) -> TMessageChannel<T> {
+        return Framed::new(Pin::new(channel), MessageCodec::new());
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Default for MessageCodec<T> {
+    fn default() -> MessageCodec<T> {
+        return MessageCodec::new();
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Decoder for MessageCodec<T> {
+    type Item = T;
+    type Error = UpgradeError;
+
+    fn decode(
+        &mut self,
+        buf: &mut BytesMut,
+    ) -> Result<Option<T>, UpgradeError> {
+        let bytes = match self.length_delimited_codec.decode(buf)? {
+            Some(bytes) => bytes,
+            None => return Ok(None),
+        };
+
+        let message = serde_json::from_slice::<T>(&bytes[..])
+            .map_err(|error| { return UpgradeError::Decode(error.to_string()); })?;
+
+        return Ok(Some(message));
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Encoder<T> for MessageCodec<T> {
+    type Error = UpgradeError;
+
+    fn encode(
+        &mut self,
+        message: T,
+        buf: &mut BytesMut,
+    ) -> Result<(), UpgradeError> {
+        let bytes = serde_json::to_vec(&message)
+            .map_err(|error| { return UpgradeError::Failed(error.to_string()); })?;
+
+        self.length_delimited_codec.encode(bytes.into(), buf)?;
+
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+    use cs_utils::traits::Random;
+    use tokio::io::AsyncWriteExt;
+
+    use crate::{mocks::{ChannelMockOptions, channel_mock_pair}, channel::ChannelMessage, errors::UpgradeError};
+
+    use super::MessageCodec;
+
+    #[tokio::test]
+    async fn sends_messages() {
+        let (local_channel, remote_channel) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+
+        let mut local_channel = MessageCodec::<ChannelMessage>::framed(local_channel);
+        let mut remote_channel = MessageCodec::<ChannelMessage>::framed(remote_channel);
+
+        let messages = vec![
+            ChannelMessage::Sync("sync-id".to_string(), 1, 2),
+            ChannelMessage::DataAck(1_024),
+            ChannelMessage::Fallback,
+        ];
+
+        for message in messages.clone() {
+            local_channel.send(message).await
+                .expect("Cannot send message.");
+        }
+
+        for message in messages {
+            let received_message = remote_channel.next().await
+                .expect("Stream closed.")
+                .expect("Cannot receive message.");
+
+            assert_eq!(
+                received_message,
+                message,
+                "Must receive the message.",
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_on_malformed_message() {
+        let (mut local_channel, remote_channel) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+
+        let mut remote_channel = MessageCodec::<ChannelMessage>::framed(remote_channel);
+
+        // a length delimited frame with a payload that is not a message
+        local_channel.write_all(&[0, 0, 0, 4, b'{', b'}', b'{', b'}']).await
+            .expect("Cannot write data.");
+
+        let result = remote_channel.next().await
+            .expect("Stream closed.");
+
+        assert!(
+            matches!(result, Err(UpgradeError::Decode(_))),
+            "Must fail with the decode error, got: {:?}", result,
+        );
+    }
+}
@@ -0,0 +1,50 @@
+use std::io;
+
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum UpgradeError {
+    #[error("Upgrade rejected by the remote side.")]
+    Rejected,
+    #[error("Upgrade cancelled.")]
+    Cancelled,
+    #[error("Upgrade timed out.")]
+    Timeout,
+    #[error("The remote side has not agreed on the protocol in time.")]
+    HandshakeTimeout,
+    #[error("Upgrade aborted by the remote side.")]
+    Aborted,
+    #[error("Channel closed.")]
+    Closed,
+    #[error("Control channel closed.")]
+    ControlChannelClosed,
+    #[error("{0} id mismatch.")]
+    IdMismatch(String),
+    #[error("Unexpected {0} message.")]
+    UnexpectedMessage(String),
+    #[error("Unsupported protocol version {0}.")]
+    UnsupportedVersion(u16),
+    #[error("The remote side does not support {0}.")]
+    UnsupportedCapability(String),
+    #[error("Session id mismatch.")]
+    SessionMismatch,
+    #[error("Transport error: {1}")]
+    Transport(io::ErrorKind, String),
+    #[error("Cannot decode message: {0}")]
+    Decode(String),
+    #[error("Substream flow control window exceeded.")]
+    WindowExceeded,
+    #[error("Upgrade failed: {0}")]
+    Failed(String),
+}
+
+impl From<io::Error> for UpgradeError {
+    fn from(error: io::Error) -> UpgradeError {
+        // the channels multiplexed over a failed one carry its error
+        if let Some(error) = error.get_ref().and_then(|error| { return error.downcast_ref::<UpgradeError>(); }) {
+            return error.clone();
+        }
+
+        return UpgradeError::Transport(error.kind(), error.to_string());
+    }
+}
@@ -1,243 +1,568 @@
-use std::pin::Pin;
+use std::{cmp, collections::HashMap, sync::{Arc, Weak, Mutex, atomic::{AtomicUsize, Ordering}}};
 
-use anyhow::{bail, Result};
 use connection_utils::Channel;
-use cs_utils::futures::{GenericCodec, wait};
-use serde::{Serialize, Deserialize};
-use tokio::io::{duplex, split, WriteHalf, ReadHalf, AsyncReadExt, AsyncWriteExt, AsyncRead, AsyncWrite};
-use futures::{StreamExt, stream::{SplitStream, SplitSink}, future::select_all, Future, select, FutureExt, SinkExt};
+use bytes::{Bytes, BytesMut};
+use tokio::{io::{duplex, split, DuplexStream, WriteHalf, ReadHalf, AsyncReadExt, AsyncWriteExt}, sync::{mpsc, oneshot, Semaphore}, time::{sleep, timeout}};
+use futures::{StreamExt, SinkExt, FutureExt, future::{self, Either}, pin_mut, select_biased, stream::{SplitStream, SplitSink}};
 
 mod child_channel;
 use child_channel::ChildChannel;
-use tokio_util::codec::Framed;
 
-#[derive(Serialize, Deserialize, Debug)]
+mod substream_id;
+pub use substream_id::SubstreamId;
+
+mod multiplexer_options;
+pub use multiplexer_options::{MultiplexerOptions, DEFAULT_LINGER_TIMEOUT};
+
+mod multiplexer;
+pub use multiplexer::Multiplexer;
+
+mod layer_codec;
+use layer_codec::{LayerCodec, TLayerChannel};
+
+use crate::{errors::UpgradeError, BufferOptions, Spawner, TraceSink};
+
+/// Message of the multiplexed channel, the substream ids are relative to the sender.
+#[derive(Debug, Clone, PartialEq)]
 pub enum LayerMessage {
-    Channel1(Vec<u8>),
-    Channel2(Vec<u8>),
+    /// The sender has opened a new substream.
+    Open(SubstreamId, String),
+    Data(SubstreamId, Bytes),
+    /// The sender has room for more data of the substream.
+    Credit(SubstreamId, usize),
+    /// The sender won't write to the substream anymore.
+    Close(SubstreamId),
 }
 
-async fn forward_reads(
-    mut channel: SplitStream<Framed<Box<dyn Channel>, GenericCodec<LayerMessage>>>,
-    mut child1: WriteHalf<Pin<Box<dyn Channel>>>,
-    mut child2: WriteHalf<Pin<Box<dyn Channel>>>,
-)-> Result<()> {
-    let mut child1 = Pin::new(&mut child1);
-    let mut child2 = Pin::new(&mut child2);
+/// Substream as seen by the `reads` forwarder.
+struct InboundSubstream {
+    id: SubstreamId,
+    // data for the substream writer task, `None` once the remote side closes the substream
+    data: Option<mpsc::UnboundedSender<Bytes>>,
+    // credit granted by the remote side to the substream reader task
+    credit: Arc<Semaphore>,
+    // data received but not written to the substream yet
+    bytes_buffered: Arc<AtomicUsize>,
+    window_size: usize,
+    // receive window of the remote side, that is its first credit grant
+    remote_window_size: Option<usize>,
+}
+
+/// Inbound substreams of the `reads` forwarder, the substream
+/// reader tasks stop waiting for credit once it is dropped.
+struct InboundSubstreams(HashMap<SubstreamId, InboundSubstream>);
+
+impl Drop for InboundSubstreams {
+    fn drop(&mut self) {
+        for substream in self.0.values() {
+            substream.credit.close();
+        }
+    }
+}
+
+/// Keeps the forwarders running, held by the multiplexer and its substreams. Once all
+/// of them are dropped, the forwarders send the data written so far and shut the channel down,
+/// they are stopped once the linger timeout expires even if the remote side does not read.
+pub struct ForwarderGuard {
+    _on_stop: oneshot::Sender<()>,
+    _on_linger: oneshot::Sender<()>,
+}
 
-    let mut child1_shutdown = false;
-    let mut child2_shutdown = false;
+impl ForwarderGuard {
+    fn new() -> (ForwarderGuard, ForwarderStop) {
+        let (stop_sender, stop_receiver) = oneshot::channel();
+        let (linger_sender, linger_receiver) = oneshot::channel();
+
+        let guard = ForwarderGuard {
+            _on_stop: stop_sender,
+            _on_linger: linger_sender,
+        };
+
+        let stop = ForwarderStop {
+            on_stop: stop_receiver,
+            on_linger: linger_receiver,
+        };
+
+        return (guard, stop);
+    }
+}
+
+/// Both resolve once the `ForwarderGuard` is dropped, the first one stops the
+/// `reads` forwarder and the second one starts the linger timeout.
+struct ForwarderStop {
+    on_stop: oneshot::Receiver<()>,
+    on_linger: oneshot::Receiver<()>,
+}
+
+/// Creates the substreams and hands them over to the forwarders.
+#[derive(Clone)]
+struct SubstreamFactory {
+    channel_id: u16,
+    buffers: BufferOptions,
+    trace: TraceSink,
+    spawner: Spawner,
+    // the substreams keep the forwarders running
+    forwarders: Weak<ForwarderGuard>,
+    error: Arc<Mutex<Option<UpgradeError>>>,
+    on_substream: mpsc::UnboundedSender<InboundSubstream>,
+    on_message: mpsc::UnboundedSender<LayerMessage>,
+}
+
+impl SubstreamFactory {
+    /// Create the substream, the remote side is notified of it if `is_announced`.
+    fn create(
+        &self,
+        id: SubstreamId,
+        label: impl AsRef<str> + ToString,
+        window_size: usize,
+        is_announced: bool,
+    ) -> Box<dyn Channel> {
+        let trace = child_trace!(self.trace, "substream", substream = ?id, label = %label.as_ref());
+
+        let (channel, forwarded_channel) = duplex(self.buffers.get_duplex_capacity());
+        let (reader, writer) = split(forwarded_channel);
+
+        let (data_sender, data_receiver) = mpsc::unbounded_channel();
+        let credit = Arc::new(Semaphore::new(0));
+        let bytes_buffered = Arc::new(AtomicUsize::new(0));
+
+        // the substream is registered before the remote side can learn of it
+        let _res = self.on_substream.send(InboundSubstream {
+            id,
+            data: Some(data_sender),
+            credit: credit.clone(),
+            bytes_buffered: bytes_buffered.clone(),
+            window_size,
+            remote_window_size: None,
+        });
+
+        if is_announced {
+            let _res = self.on_message.send(LayerMessage::Open(id, label.to_string()));
+        }
+
+        let _res = self.on_message.send(LayerMessage::Credit(id, window_size));
+
+        self.spawner.spawn(
+            "read_substream",
+            read_substream(id, reader, credit, self.on_message.clone(), self.buffers.get_read_chunk_size(), trace.clone()),
+        );
+        self.spawner.spawn(
+            "write_substream",
+            write_substream(id, writer, data_receiver, bytes_buffered, self.on_message.clone(), window_size, trace),
+        );
+
+        return ChildChannel::new(
+            self.channel_id,
+            label,
+            Box::new(channel),
+            self.error.clone(),
+            self.forwarders.upgrade(),
+        );
+    }
+}
+
+/// Forward the data written to the substream to the remote side,
+/// as long as the remote side has room for it.
+async fn read_substream(
+    id: SubstreamId,
+    mut reader: ReadHalf<DuplexStream>,
+    credit: Arc<Semaphore>,
+    on_message: mpsc::UnboundedSender<LayerMessage>,
+    read_chunk_size: usize,
+    trace: TraceSink,
+) {
+    let mut buf = BytesMut::with_capacity(read_chunk_size);
 
     loop {
-        let item = match channel.next().await {
-            Some(item) => item?,
-            None => {
-                println!("[forward_reads]> stream closed!");
-                
-                bail!("Stream closed.");
-            },
+        // the semaphore is closed once the forwarders stop
+        let permit = match credit.acquire().await {
+            Ok(permit) => permit,
+            Err(_) => return,
         };
 
-        println!("[forward][forward_reads]> got item");
+        let bytes_to_read = cmp::min(read_chunk_size, credit.available_permits() + 1);
 
-        // TODO: channels should not block each other, use `write()` instead.
-        //  Or maybe run the channels under a separate thread.
-        //  Or maybe use buffers.
-        match item {
-            LayerMessage::Channel1(data) => {
-                println!("[1][forward_reads]> got message: {:?}", data.len());
+        permit.forget();
 
-                assert!(!child1_shutdown, "Child1 already shutdown.");
+        buf.reserve(bytes_to_read);
 
-                if data.len() == 0 {
-                    child1.shutdown().await?;
+        // a failed substream is closed
+        let bytes_read = (&mut reader).take(bytes_to_read as u64).read_buf(&mut buf).await
+            .unwrap_or(0);
 
-                    child1_shutdown = true;
+        if bytes_read == 0 {
+            trace.debug("substream closed");
 
-                    continue;
-                }
+            let _res = on_message.send(LayerMessage::Close(id));
+
+            return;
+        }
 
-                println!("[1][forward_reads]> writing all");
+        // this task is the only consumer of the credit, hence the permits are there
+        if let Ok(permits) = credit.try_acquire_many((bytes_read - 1) as u32) {
+            permits.forget();
+        }
 
-                child1.write_all(&data[..]).await?;
+        if on_message.send(LayerMessage::Data(id, buf.split().freeze())).is_err() {
+            return;
+        }
+    }
+}
 
-                println!("[1][forward_reads]> written all");
-            },
-            LayerMessage::Channel2(data) => {
-                println!("[2][forward_reads]> got message: {:?}", data.len());
+/// Write the data received from the remote side to the substream,
+/// and let the remote side send more data once it is written.
+async fn write_substream(
+    id: SubstreamId,
+    mut writer: WriteHalf<DuplexStream>,
+    mut on_data: mpsc::UnboundedReceiver<Bytes>,
+    bytes_buffered: Arc<AtomicUsize>,
+    on_message: mpsc::UnboundedSender<LayerMessage>,
+    window_size: usize,
+    trace: TraceSink,
+) {
+    let mut is_dropped = false;
+    // data written to the substream the remote side has no credit for yet
+    let mut bytes_to_credit = 0;
+
+    while let Some(data) = on_data.recv().await {
+        // nobody reads the substream anymore, discard its data
+        if !is_dropped && writer.write_all(&data[..]).await.is_err() {
+            trace.debug("substream dropped, discarding its data");
+
+            is_dropped = true;
+        }
+
+        bytes_buffered.fetch_sub(data.len(), Ordering::SeqCst);
+        bytes_to_credit += data.len();
+
+        // the credit is granted in batches to save on messages, the remote side
+        // runs out of credit only once there is a full window to grant
+        if bytes_to_credit >= window_size / 2 {
+            let _res = on_message.send(LayerMessage::Credit(id, bytes_to_credit));
+
+            bytes_to_credit = 0;
+        }
+    }
+
+    let _res = writer.shutdown().await;
+}
 
-                assert!(!child2_shutdown, "Child2 already shutdown.");
+/// Get the next substream to register, never resolves once no more substreams can be created.
+async fn next_substream(
+    on_substream: &mut mpsc::UnboundedReceiver<InboundSubstream>,
+) -> InboundSubstream {
+    return match on_substream.recv().await {
+        Some(substream) => substream,
+        None => future::pending().await,
+    };
+}
 
-                if data.len() == 0 {
-                    child2.shutdown().await?;
+/// Resolves once the multiplexer and its substreams are dropped, never if it is stopping already.
+async fn next_stop(
+    on_stop: &mut oneshot::Receiver<()>,
+    is_stopping: bool,
+) {
+    if is_stopping {
+        return future::pending().await;
+    }
 
-                    println!("[2][forward_reads]> shut down");
+    let _res = on_stop.await;
+}
 
-                    child2_shutdown = true;
+async fn forward_reads(
+    mut channel: SplitStream<TLayerChannel>,
+    mut on_substream: mpsc::UnboundedReceiver<InboundSubstream>,
+    factory: SubstreamFactory,
+    on_accept: mpsc::UnboundedSender<Box<dyn Channel>>,
+    window_size: usize,
+    mut on_stop: oneshot::Receiver<()>,
+)-> Result<(), UpgradeError> {
+    let mut substreams = InboundSubstreams(HashMap::new());
+    let trace = factory.trace.clone();
+    // no new substreams once the multiplexer is stopping
+    let mut factory = Some(factory);
 
-                    continue;
+    loop {
+        let is_stopping = factory.is_none();
+
+        let item = select_biased! {
+            maybe_substream = next_substream(&mut on_substream).fuse() => {
+                let mut substream = maybe_substream;
+
+                // nobody reads the substream anymore
+                if is_stopping {
+                    substream.data.take();
                 }
 
-                println!("[2][forward_reads]> writing all");
+                substreams.0.insert(substream.id, substream);
 
-                child2.write_all(&data[..]).await?;
+                continue;
+            },
+            // the multiplexer and its substreams are dropped, the inbound data is discarded
+            // while the substream reader tasks send what is left, the writes forwarder stops
+            // once they are done; the credit for that data still has to be received
+            _ = next_stop(&mut on_stop, is_stopping).fuse() => {
+                trace.debug("multiplexer dropped, stopping");
 
-                println!("[2][forward_reads]> written all");
+                factory.take();
+
+                for substream in substreams.0.values_mut() {
+                    substream.data.take();
+                }
+
+                continue;
+            },
+            maybe_item = channel.next().fuse() => {
+                match maybe_item {
+                    Some(item) => item?,
+                    // the children get EOF once the forwarders stop
+                    None => {
+                        trace.debug("multiplexed channel closed");
+
+                        return Ok(());
+                    },
+                }
             },
         };
-    }
-}
 
-async fn forward_writes(
-    mut channel: SplitSink<Framed<Box<dyn Channel>, GenericCodec<LayerMessage>>, LayerMessage>,
-    mut child1: ReadHalf<Pin<Box<dyn Channel>>>,
-    mut child2: ReadHalf<Pin<Box<dyn Channel>>>,
-) -> Result<()> {
-    let mut child1 = Pin::new(&mut child1);
-    let mut child2 = Pin::new(&mut child2);
+        match item {
+            LayerMessage::Open(id, label) => {
+                let id = id.remote();
 
-    let mut buf1 = [0; 1024];
-    let mut buf2 = [0; 1024];
+                trace.debug(&format!("remote side opened substream {:?}: {}", id, label));
 
-    let mut child1_shutdown = false;
-    let mut child2_shutdown = false;
+                if substreams.0.contains_key(&id) {
+                    return Err(UpgradeError::IdMismatch("Substream".to_string()));
+                }
 
-    loop {
-        select! {
-            maybe_bytes_read = child1.read(&mut buf1).fuse() => {
-                if child1_shutdown {
-                    wait(1).await;
-                    continue;
+                // the substream is dropped if nobody accepts it
+                if let Some(factory) = factory.as_ref() {
+                    let _res = on_accept.send(factory.create(id, label, window_size, false));
                 }
+            },
+            LayerMessage::Data(id, data) => {
+                let id = id.remote();
 
-                println!("[1][forward-writes]> maybe_bytes_read: {:?}", maybe_bytes_read);
-                let bytes_read = maybe_bytes_read?;
+                if !trace.is_silent() {
+                    trace.trace(&format!("received {} bytes of substream {:?}", data.len(), id));
+                }
 
-                channel.send(LayerMessage::Channel1(buf1[..bytes_read].to_vec())).await?;
+                let substream = substreams.0.get(&id)
+                    .ok_or_else(|| UpgradeError::IdMismatch("Substream".to_string()))?;
 
-                if bytes_read == 0 {
-                    println!("[1][forward-writes]> shut down");
-                    child1_shutdown = true;
+                let bytes_buffered = substream.bytes_buffered.fetch_add(data.len(), Ordering::SeqCst) + data.len();
+
+                if bytes_buffered > substream.window_size {
+                    return Err(UpgradeError::WindowExceeded);
                 }
+
+                // the writer task is never slower than the remote side's credit allows,
+                // hence the data never blocks the other substreams
+                match substream.data.as_ref() {
+                    Some(on_data) => {
+                        let _res = on_data.send(data);
+                    },
+                    None if is_stopping => {},
+                    None => return Err(UpgradeError::UnexpectedMessage("Data".to_string())),
+                };
             },
-            maybe_bytes_read = child2.read(&mut buf2).fuse() => {
-                if child2_shutdown {
-                    wait(1).await;
-                    continue;
+            LayerMessage::Credit(id, bytes) => {
+                let substream = substreams.0.get_mut(&id.remote())
+                    .ok_or_else(|| UpgradeError::IdMismatch("Substream".to_string()))?;
+
+                // the windows of the sides may differ, the remote side advertises its own
+                // one with the first grant and never grants more than that afterwards
+                let remote_window_size = *substream.remote_window_size.get_or_insert(bytes);
+
+                if remote_window_size > Semaphore::MAX_PERMITS {
+                    return Err(UpgradeError::WindowExceeded);
+                }
+
+                if bytes > remote_window_size - cmp::min(substream.credit.available_permits(), remote_window_size) {
+                    return Err(UpgradeError::WindowExceeded);
                 }
 
-                println!("[2][forward-writes]> maybe_bytes_read: {:?}", maybe_bytes_read);
+                substream.credit.add_permits(bytes);
+            },
+            LayerMessage::Close(id) => {
+                let id = id.remote();
 
-                let bytes_read = maybe_bytes_read?;
+                trace.debug(&format!("remote side closed substream {:?}", id));
 
-                channel.send(LayerMessage::Channel2(buf2[..bytes_read].to_vec())).await?;
+                let substream = substreams.0.get_mut(&id)
+                    .ok_or_else(|| UpgradeError::IdMismatch("Substream".to_string()))?;
 
-                if bytes_read == 0 {
-                    println!("[2][forward-writes]> shut down");
-                    child2_shutdown = true;
+                // the writer task shuts the substream down once the data is written
+                if substream.data.take().is_none() && !is_stopping {
+                    return Err(UpgradeError::UnexpectedMessage("Close".to_string()));
                 }
             },
-        }
+        };
     }
 }
 
+async fn forward_writes(
+    mut channel: SplitSink<TLayerChannel, LayerMessage>,
+    mut on_message: mpsc::UnboundedReceiver<LayerMessage>,
+) -> Result<(), UpgradeError> {
+    while let Some(message) = on_message.recv().await {
+        channel.send(message).await?;
+    }
+
+    // nothing can be written anymore, let the remote side know
+    channel.close().await?;
+
+    return Ok(());
+}
+
 fn forward(
     channel: Box<dyn Channel>,
-    child1: Box<dyn Channel>,
-    child2: Box<dyn Channel>,
+    on_substream: mpsc::UnboundedReceiver<InboundSubstream>,
+    on_message: mpsc::UnboundedReceiver<LayerMessage>,
+    factory: SubstreamFactory,
+    on_accept: mpsc::UnboundedSender<Box<dyn Channel>>,
+    options: &MultiplexerOptions,
+    stop: ForwarderStop,
 ) {
-    let child1 = Pin::new(child1);
-    let child2 = Pin::new(child2);
-
-    let (sink, source) = Framed::new(
-        channel,
-        GenericCodec::<LayerMessage>::new(),
-    ).split();
-
-    let (child1_read, child1_write) = split(child1);
-    let (child2_read, child2_write) = split(child2);
-
-    let futures: Vec<Pin<Box<dyn Future<Output = _> + Send + 'static>>> = vec![
-        Box::pin(async move {
-            match forward_reads(source, child1_write, child2_write).await {
-                Ok(_) => {
-                    println!("[forward]> forward_reads succeed");
-                },
-                Err(error) => {
-                    println!("[forward]> forward_reads failed");
-
-                    panic!("[forward]> forward_reads failed {}", error);
-                },
-            };
-        }),
-        Box::pin(async move {
-            match forward_writes(sink, child1_read, child2_read).await {
-                Ok(_) => {
-                    println!("[forward]> forward_writes succeed");
-                },
-                Err(error) => {
-                    println!("[forward]> forward_writes failed");
-
-                    panic!("[forward]> forward_writes failed {}", error);
-                },
-            };
-        }),
-    ];
-
-    let _res = tokio::spawn(select_all(futures));
+    let ForwarderStop { on_stop, on_linger } = stop;
+    let window_size = options.get_window_size();
+    let linger_timeout = options.get_linger_timeout();
+    let (sink, source) = LayerCodec::new(options.get_buffers().get_max_frame_size()).framed(channel).split();
+
+    let reads_error = factory.error.clone();
+    let writes_error = factory.error.clone();
+    let reads_trace = factory.trace.clone();
+    let writes_trace = factory.trace.clone();
+    let spawner = factory.spawner.clone();
+
+    // both resolve with `false` if the forwarder fails
+    let reads = async move {
+        return match forward_reads(source, on_substream, factory, on_accept, window_size, on_stop).await {
+            Ok(_) => {
+                reads_trace.debug("reads forwarder stopped");
+
+                true
+            },
+            Err(error) => {
+                reads_trace.warn(&format!("reads forwarder failed: {}", error));
+
+                reads_error.lock().unwrap().get_or_insert(error);
+
+                false
+            },
+        };
+    };
+
+    let writes = async move {
+        return match forward_writes(sink, on_message).await {
+            Ok(_) => {
+                writes_trace.debug("writes forwarder stopped");
+
+                true
+            },
+            Err(error) => {
+                writes_trace.warn(&format!("writes forwarder failed: {}", error));
+
+                writes_error.lock().unwrap().get_or_insert(error);
+
+                false
+            },
+        };
+    };
+
+    // the children are closed once either of the forwarders fails, the error is set
+    // before that so the children return it instead of EOF; once the reads forwarder
+    // gets EOF or the children are dropped, the writes forwarder sends the data in
+    // flight and shuts the channel down once there is nobody to write more
+    let forwarders = async move {
+        pin_mut!(reads, writes);
+
+        match future::select(reads, writes).await {
+            Either::Left((true, writes)) => {
+                writes.await;
+            },
+            // the reads forwarder stops on the remote EOF
+            Either::Right((true, reads)) => {
+                let _res = timeout(linger_timeout, reads).await;
+            },
+            _ => {},
+        };
+    };
+
+    // the substream reader tasks wait for the credit the remote side may never grant,
+    // dropping the forwarders closes the credit semaphores and stops the tasks
+    let linger = async move {
+        let _res = on_linger.await;
+
+        sleep(linger_timeout).await;
+    };
+
+    spawner.spawn("forward", async move {
+        pin_mut!(forwarders, linger);
+
+        future::select(forwarders, linger).await;
+    });
+}
+
+/// Split the `channel` into `count` substreams, the remote side must split its
+/// channel into the same number of substreams, see `Multiplexer::with_substreams`.
+pub fn multiplex(
+    channel: Box<dyn Channel>,
+    count: u32,
+) -> Vec<Box<dyn Channel>> {
+    let (_multiplexer, substreams) = Multiplexer::with_substreams(channel, count);
+
+    return substreams;
+}
+
+/// Split the `channel` into `count` substreams with the `buffers`, see `multiplex`.
+pub fn multiplex_with_options(
+    channel: Box<dyn Channel>,
+    count: u32,
+    buffers: BufferOptions,
+) -> Vec<Box<dyn Channel>> {
+    let options = MultiplexerOptions::default()
+        .buffers(buffers)
+        .substreams(count);
+
+    let (_multiplexer, substreams) = Multiplexer::with_options(channel, options);
+
+    return substreams;
 }
 
+/// Split the `channel` into two substreams with the `options`, see `multiplex`.
+/// The fixed substreams of the `options` are kept, the missing ones are added.
+/// Use `Multiplexer::with_options` to get the handles of the background tasks.
 pub fn divide_channel(
     channel: Box<dyn Channel>,
+    options: MultiplexerOptions,
 ) -> (Box<dyn Channel>, Box<dyn Channel>) {
-    let id = channel.id();
-    let label = channel.label().clone();
-    // TODO: add `buffer_size` attribute to the Channel trait
-    // let buffer_size = channel.buffer_size();
-    
-    let (child1_sink, child1_source) = duplex(1024);
-    let (child2_sink, child2_source) = duplex(1024);
-
-    let child_channel1 = ChildChannel::new(
-        id,
-        &label,
-        Box::new(child1_sink),
-    );
-
-    let child_channel2 = ChildChannel::new(
-        id,
-        &label,
-        Box::new(child2_sink),
-    );
-
-    forward(
-        channel,
-        ChildChannel::new(
-            id,
-            &label,
-            Box::new(child1_source),
-        ),
-        ChildChannel::new(
-            id,
-            &label,
-            Box::new(child2_source),
-        ),
-    );
+    let missing_count = 2 - cmp::min(options.get_substream_window_sizes().len(), 2);
+
+    let (_multiplexer, mut substreams) = Multiplexer::with_options(channel, options.substreams(missing_count as u32));
+
+    let channel2 = substreams.remove(1);
+    let channel1 = substreams.remove(0);
 
-    return (child_channel1, child_channel2);
+    return (channel1, channel2);
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{io, time::Duration, sync::atomic::Ordering};
+
     use rstest::rstest;
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
     use connection_utils::test::test_async_stream;
-    use cs_utils::{random_number, random_str, futures::wait_random, traits::Random};
+    use cs_utils::{random_number, random_str, futures::{wait, wait_random}, traits::Random};
     
     use crate::utils::{test_framed_stream, TestOptions, StreamTestMessage};
-    use crate::utils::create_framed_stream;
+    use crate::utils::{create_framed_stream, PollCounter, TaskCounter};
     use crate::mocks::{channel_mock_pair, ChannelMockOptions};
+    use crate::{errors::UpgradeError, BufferOptions, DEFAULT_WINDOW_SIZE};
 
-    use super::divide_channel;
+    use super::{divide_channel, multiplex, LayerCodec, LayerMessage, SubstreamId, Multiplexer, MultiplexerOptions};
 
     #[rstest]
     #[case(128)]
@@ -256,8 +581,8 @@ mod tests {
     ) {
         let (local_channel, remote_channel) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
 
-        let (local_channel1, local_channel2) = divide_channel(local_channel);
-        let (remote_channel1, remote_channel2) = divide_channel(remote_channel);
+        let (local_channel1, local_channel2) = divide_channel(local_channel, MultiplexerOptions::default());
+        let (remote_channel1, remote_channel2) = divide_channel(remote_channel, MultiplexerOptions::default());
 
         tokio::try_join!(
             tokio::spawn(async move {
@@ -294,8 +619,8 @@ mod tests {
     ) {
         let (local_channel, remote_channel) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
 
-        let (local_channel1, _local_channel2) = divide_channel(local_channel);
-        let (remote_channel1, _remote_channel2) = divide_channel(remote_channel);
+        let (local_channel1, _local_channel2) = divide_channel(local_channel, MultiplexerOptions::default());
+        let (remote_channel1, _remote_channel2) = divide_channel(remote_channel, MultiplexerOptions::default());
 
         test_async_stream(
             local_channel1,
@@ -321,8 +646,8 @@ mod tests {
     ) {
         let (local_channel, remote_channel) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
 
-        let (_local_channel1, local_channel2) = divide_channel(local_channel);
-        let (_remote_channel1, remote_channel2) = divide_channel(remote_channel);
+        let (_local_channel1, local_channel2) = divide_channel(local_channel, MultiplexerOptions::default());
+        let (_remote_channel1, remote_channel2) = divide_channel(remote_channel, MultiplexerOptions::default());
 
         test_async_stream(
             local_channel2,
@@ -348,8 +673,8 @@ mod tests {
     ) {
         let (local_channel, remote_channel) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
 
-        let (local_channel1, local_channel2) = divide_channel(local_channel);
-        let (remote_channel1, remote_channel2) = divide_channel(remote_channel);
+        let (local_channel1, local_channel2) = divide_channel(local_channel, MultiplexerOptions::default());
+        let (remote_channel1, remote_channel2) = divide_channel(remote_channel, MultiplexerOptions::default());
 
         drop(local_channel2);
         drop(remote_channel2);
@@ -378,8 +703,8 @@ mod tests {
     ) {
         let (local_channel, remote_channel) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
 
-        let (local_channel1, local_channel2) = divide_channel(local_channel);
-        let (remote_channel1, remote_channel2) = divide_channel(remote_channel);
+        let (local_channel1, local_channel2) = divide_channel(local_channel, MultiplexerOptions::default());
+        let (remote_channel1, remote_channel2) = divide_channel(remote_channel, MultiplexerOptions::default());
 
         drop(local_channel1);
         drop(remote_channel1);
@@ -405,8 +730,8 @@ mod tests {
     ) {
         let (local_channel, remote_channel) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
 
-        let (local_channel1, local_channel2) = divide_channel(local_channel);
-        let (remote_channel1, remote_channel2) = divide_channel(remote_channel);
+        let (local_channel1, local_channel2) = divide_channel(local_channel, MultiplexerOptions::default());
+        let (remote_channel1, remote_channel2) = divide_channel(remote_channel, MultiplexerOptions::default());
 
         let local_channel2 = create_framed_stream::<StreamTestMessage>(local_channel2);
         let remote_channel2 = create_framed_stream::<StreamTestMessage>(remote_channel2);
@@ -447,8 +772,8 @@ mod tests {
     ) {
         let (local_channel, remote_channel) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
 
-        let (local_channel1, local_channel2) = divide_channel(local_channel);
-        let (remote_channel1, remote_channel2) = divide_channel(remote_channel);
+        let (local_channel1, local_channel2) = divide_channel(local_channel, MultiplexerOptions::default());
+        let (remote_channel1, remote_channel2) = divide_channel(remote_channel, MultiplexerOptions::default());
 
         let local_channel1 = create_framed_stream::<StreamTestMessage>(local_channel1);
         let remote_channel1 = create_framed_stream::<StreamTestMessage>(remote_channel1);
@@ -489,8 +814,8 @@ mod tests {
     ) {
         let (local_channel, remote_channel) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
 
-        let (local_channel1, local_channel2) = divide_channel(local_channel);
-        let (remote_channel1, remote_channel2) = divide_channel(remote_channel);
+        let (local_channel1, local_channel2) = divide_channel(local_channel, MultiplexerOptions::default());
+        let (remote_channel1, remote_channel2) = divide_channel(remote_channel, MultiplexerOptions::default());
 
         let local_channel1 = create_framed_stream::<StreamTestMessage>(local_channel1);
         let remote_channel1 = create_framed_stream::<StreamTestMessage>(remote_channel1);
@@ -540,8 +865,8 @@ mod tests {
             remote_channel,
         ) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
 
-        let (local_channel1, local_channel2) = divide_channel(local_channel);
-        let (remote_channel1, remote_channel2) = divide_channel(remote_channel);
+        let (local_channel1, _local_channel2) = divide_channel(local_channel, MultiplexerOptions::default());
+        let (remote_channel1, _remote_channel2) = divide_channel(remote_channel, MultiplexerOptions::default());
 
         test_async_stream(
             local_channel1,
@@ -551,4 +876,648 @@ mod tests {
 
         // local_channel1
     }
+
+    #[tokio::test]
+    async fn fails_children_on_duplicate_close() {
+        let (local_channel, remote_channel) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+
+        let (mut local_channel1, mut local_channel2) = divide_channel(local_channel, MultiplexerOptions::default());
+
+        // misbehaving remote side closes the first child twice
+        let mut remote_channel = LayerCodec::default().framed(remote_channel);
+
+        remote_channel.send(LayerMessage::Close(SubstreamId::Fixed(0))).await
+            .expect("Cannot send message.");
+        remote_channel.send(LayerMessage::Close(SubstreamId::Fixed(0))).await
+            .expect("Cannot send message.");
+
+        let mut buf = [0; 32];
+
+        let error = local_channel2.read(&mut buf).await
+            .expect_err("Must fail the read.");
+
+        assert_eq!(
+            error.kind(),
+            io::ErrorKind::InvalidData,
+            "Must fail with the protocol error.",
+        );
+
+        assert_eq!(
+            error.into_inner().and_then(|error| error.downcast::<UpgradeError>().ok()).map(|error| *error),
+            Some(UpgradeError::UnexpectedMessage("Close".to_string())),
+            "Must fail with the protocol error.",
+        );
+
+        local_channel1.write_all(b"data").await
+            .expect_err("Must fail the write.");
+    }
+
+    #[tokio::test]
+    async fn fails_children_on_malformed_frame() {
+        let (local_channel, mut remote_channel) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+
+        let (mut local_channel1, mut local_channel2) = divide_channel(local_channel, MultiplexerOptions::default());
+
+        // a frame of an unknown kind
+        remote_channel.write_all(&[9, 0, 0, 0, 0, 0, 0, 0, 0, 0]).await
+            .expect("Cannot write data.");
+
+        let mut buf = [0; 32];
+
+        for child in [&mut local_channel1, &mut local_channel2] {
+            let error = child.read(&mut buf).await
+                .expect_err("Must fail the read.");
+
+            assert_eq!(
+                error.kind(),
+                io::ErrorKind::InvalidData,
+                "Must fail with the decode error.",
+            );
+        }
+    }
+
+    #[rstest]
+    #[case(1)]
+    #[case(3)]
+    #[case(8)]
+    #[tokio::test]
+    async fn multiplexes_channel(
+        #[case] substreams_count: u32,
+    ) {
+        let (local_channel, remote_channel) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+
+        let local_substreams = multiplex(local_channel, substreams_count);
+        let remote_substreams = multiplex(remote_channel, substreams_count);
+
+        let tasks = local_substreams.into_iter()
+            .zip(remote_substreams)
+            .map(|(local_substream, remote_substream)| {
+                return tokio::spawn(async move {
+                    wait_random(1..=25).await;
+
+                    test_async_stream(
+                        local_substream,
+                        remote_substream,
+                        random_str(1_024),
+                    ).await;
+                });
+            });
+
+        for result in futures::future::join_all(tasks).await {
+            result.expect("Substream test failed.");
+        }
+    }
+
+    #[tokio::test]
+    async fn opens_substreams_on_both_sides() {
+        let (local_channel, remote_channel) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+
+        let mut local_multiplexer = Multiplexer::new(local_channel);
+        let mut remote_multiplexer = Multiplexer::new(remote_channel);
+
+        // both sides open substreams at the same time
+        let local_control = local_multiplexer.open_substream("control");
+        let remote_telemetry = remote_multiplexer.open_substream("telemetry");
+        let local_data = local_multiplexer.open_substream("data");
+
+        let remote_control = remote_multiplexer.accept_substream().await
+            .expect("Cannot accept substream.");
+        let remote_data = remote_multiplexer.accept_substream().await
+            .expect("Cannot accept substream.");
+        let local_telemetry = local_multiplexer.accept_substream().await
+            .expect("Cannot accept substream.");
+
+        assert_eq!(
+            [remote_control.label(), remote_data.label(), local_telemetry.label()],
+            ["control", "data", "telemetry"],
+            "Must accept the substreams in order.",
+        );
+
+        let pairs = vec![
+            (local_control, remote_control),
+            (local_data, remote_data),
+            (local_telemetry, remote_telemetry),
+        ];
+
+        let tasks = pairs.into_iter()
+            .map(|(local_substream, remote_substream)| {
+                return tokio::spawn(async move {
+                    test_async_stream(
+                        local_substream,
+                        remote_substream,
+                        random_str(512),
+                    ).await;
+                });
+            });
+
+        for result in futures::future::join_all(tasks).await {
+            result.expect("Substream test failed.");
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_substreams_if_one_is_dropped() {
+        let (local_channel, remote_channel) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+
+        let local_multiplexer = Multiplexer::new(local_channel);
+        let mut remote_multiplexer = Multiplexer::new(remote_channel);
+
+        let mut local_substream1 = local_multiplexer.open_substream("substream1");
+        let local_substream2 = local_multiplexer.open_substream("substream2");
+
+        let remote_substream1 = remote_multiplexer.accept_substream().await
+            .expect("Cannot accept substream.");
+        let remote_substream2 = remote_multiplexer.accept_substream().await
+            .expect("Cannot accept substream.");
+
+        drop(remote_substream1);
+
+        // nobody reads the data anymore, the remote side discards it
+        local_substream1.write_all(random_str(4_096).as_bytes()).await
+            .expect("Cannot write data.");
+
+        test_async_stream(
+            local_substream2,
+            remote_substream2,
+            random_str(1_024),
+        ).await;
+    }
+
+    #[tokio::test]
+    async fn slow_substream_does_not_block_others() {
+        let (local_channel, remote_channel) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+
+        let options = MultiplexerOptions::default()
+            .window_size(1_024)
+            .substreams(2);
+
+        let (_local_multiplexer, mut local_substreams) = Multiplexer::with_options(local_channel, options.clone());
+        let (_remote_multiplexer, mut remote_substreams) = Multiplexer::with_options(remote_channel, options);
+
+        let (mut local_substream1, local_substream2) = (local_substreams.remove(0), local_substreams.remove(0));
+        let (mut remote_substream1, remote_substream2) = (remote_substreams.remove(0), remote_substreams.remove(0));
+
+        // nobody reads the first substream for a while
+        let data = random_str(64 * 1_024);
+        let data_to_write = data.clone();
+
+        let writes = tokio::spawn(async move {
+            remote_substream1.write_all(data_to_write.as_bytes()).await
+                .expect("Cannot write data.");
+
+            return remote_substream1;
+        });
+
+        test_async_stream(
+            local_substream2,
+            remote_substream2,
+            random_str(1_024),
+        ).await;
+
+        let mut received_data = vec![0; data.len()];
+
+        local_substream1.read_exact(&mut received_data).await
+            .expect("Cannot read data.");
+
+        writes.await
+            .expect("Cannot write data.");
+
+        assert_eq!(
+            String::from_utf8(received_data).unwrap(),
+            data,
+            "Must receive the data of the slow substream.",
+        );
+    }
+
+    #[rstest]
+    #[case(1)]
+    #[case(100)]
+    #[case(4_096)]
+    #[tokio::test]
+    async fn sends_data_in_read_chunks(
+        #[case] read_chunk_size: usize,
+    ) {
+        // no throttling, a frame per byte takes too long otherwise
+        let (local_channel, remote_channel) = channel_mock_pair(ChannelMockOptions::default(), ChannelMockOptions::default());
+
+        let buffers = BufferOptions::default()
+            .read_chunk_size(read_chunk_size);
+
+        let (mut local_channel1, _local_channel2) = divide_channel(local_channel, MultiplexerOptions::default().buffers(buffers));
+
+        let mut remote_channel = LayerCodec::default().framed(remote_channel);
+
+        remote_channel.send(LayerMessage::Credit(SubstreamId::Fixed(0), 10_000)).await
+            .expect("Cannot send message.");
+
+        let data = random_str(10_000);
+
+        local_channel1.write_all(data.as_bytes()).await
+            .expect("Cannot write data.");
+
+        let mut received_data = vec![];
+
+        while received_data.len() < data.len() {
+            let message = remote_channel.next().await
+                .expect("Stream closed.")
+                .expect("Cannot receive message.");
+
+            if let LayerMessage::Data(SubstreamId::Fixed(0), data) = message {
+                assert!(
+                    data.len() <= read_chunk_size,
+                    "Must not send more data than the read chunk size at once, got: {}", data.len(),
+                );
+
+                received_data.extend_from_slice(&data[..]);
+            }
+        }
+
+        assert_eq!(
+            String::from_utf8(received_data).unwrap(),
+            data,
+            "Must send all the data.",
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_on_frame_larger_than_max_frame_size() {
+        let (local_channel, remote_channel) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+
+        let buffers = BufferOptions::default()
+            .max_frame_size(1_024);
+
+        let (mut local_channel1, _local_channel2) = divide_channel(local_channel, MultiplexerOptions::default().buffers(buffers));
+
+        let mut remote_channel = LayerCodec::default().framed(remote_channel);
+
+        // the local side closes the channel once it gets the frame header
+        let _res = remote_channel.send(LayerMessage::Data(SubstreamId::Fixed(0), vec![0; 1_025].into())).await;
+
+        let mut buf = [0; 32];
+
+        let error = local_channel1.read(&mut buf).await
+            .expect_err("Must fail the read.");
+
+        assert!(
+            matches!(
+                error.into_inner().and_then(|error| error.downcast::<UpgradeError>().ok()).map(|error| *error),
+                Some(UpgradeError::Decode(_)),
+            ),
+            "Must fail with the decode error.",
+        );
+    }
+
+    #[tokio::test]
+    async fn waits_for_credit() {
+        let (local_channel, remote_channel) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+
+        let (mut local_channel1, _local_channel2) = divide_channel(local_channel, MultiplexerOptions::default());
+
+        let mut remote_channel = LayerCodec::default().framed(remote_channel);
+
+        // the remote side has room for 100 bytes only
+        remote_channel.send(LayerMessage::Credit(SubstreamId::Fixed(0), 100)).await
+            .expect("Cannot send message.");
+
+        let data = random_str(1_000);
+
+        local_channel1.write_all(data.as_bytes()).await
+            .expect("Cannot write data.");
+
+        let mut received_data = vec![];
+
+        while received_data.len() < 100 {
+            let message = remote_channel.next().await
+                .expect("Stream closed.")
+                .expect("Cannot receive message.");
+
+            if let LayerMessage::Data(SubstreamId::Fixed(0), data) = message {
+                received_data.extend_from_slice(&data[..]);
+            }
+        }
+
+        assert_eq!(
+            received_data.len(),
+            100,
+            "Must not send more data than the credit allows.",
+        );
+
+        let maybe_message = tokio::time::timeout(Duration::from_millis(50), remote_channel.next()).await;
+
+        assert!(
+            maybe_message.is_err(),
+            "Must wait for more credit, got: {:?}", maybe_message,
+        );
+
+        let mut bytes_granted = 100;
+
+        while received_data.len() < data.len() {
+            // the window is 100 bytes, the credit is granted back once the data is consumed
+            if received_data.len() == bytes_granted {
+                remote_channel.send(LayerMessage::Credit(SubstreamId::Fixed(0), 100)).await
+                    .expect("Cannot send message.");
+
+                bytes_granted += 100;
+            }
+
+            let message = remote_channel.next().await
+                .expect("Stream closed.")
+                .expect("Cannot receive message.");
+
+            if let LayerMessage::Data(SubstreamId::Fixed(0), data) = message {
+                received_data.extend_from_slice(&data[..]);
+            }
+        }
+
+        assert_eq!(
+            String::from_utf8(received_data).unwrap(),
+            data,
+            "Must send all the data once there is credit.",
+        );
+    }
+
+    #[tokio::test]
+    async fn does_not_poll_idle_channel() {
+        let (local_channel, remote_channel) = channel_mock_pair(ChannelMockOptions::default(), ChannelMockOptions::default());
+
+        let (local_channel, poll_count) = PollCounter::new(local_channel);
+
+        let (mut local_channel1, local_channel2) = divide_channel(local_channel, MultiplexerOptions::default());
+        let (mut remote_channel1, _remote_channel2) = divide_channel(remote_channel, MultiplexerOptions::default());
+
+        // a child that has shut down must not keep the forwarders busy either
+        drop(local_channel2);
+
+        let data = random_str(1_024);
+
+        local_channel1.write_all(data.as_bytes()).await
+            .expect("Cannot write data.");
+
+        let mut received_data = vec![0; data.len()];
+
+        remote_channel1.read_exact(&mut received_data).await
+            .expect("Cannot read data.");
+
+        wait(100).await;
+
+        // the runtime advances the paused clock whenever it has nothing else
+        // to do, hence any busy-wait sleep would poll the channel in no time
+        tokio::time::pause();
+
+        let polls_before = poll_count.load(Ordering::SeqCst);
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+
+        assert_eq!(
+            poll_count.load(Ordering::SeqCst),
+            polls_before,
+            "Must not poll the channel while idle.",
+        );
+    }
+
+    #[rstest]
+    #[case(1)]
+    #[case(5_000)]
+    #[tokio::test]
+    async fn stops_forwarders_once_dropped(
+        #[case] channel_count: usize,
+    ) {
+        let (spawner, task_count) = TaskCounter::new();
+        let options = MultiplexerOptions::default()
+            .spawner(spawner);
+
+        for _ in 0..channel_count {
+            let (local_channel, remote_channel) = channel_mock_pair(ChannelMockOptions::default(), ChannelMockOptions::default());
+
+            let (mut local_channel1, _local_channel2) = divide_channel(local_channel, options.clone());
+            let (mut remote_channel1, _remote_channel2) = divide_channel(remote_channel, options.clone());
+
+            let data = random_str(32);
+
+            local_channel1.write_all(data.as_bytes()).await
+                .expect("Cannot write data.");
+
+            let mut received_data = vec![0; data.len()];
+
+            remote_channel1.read_exact(&mut received_data).await
+                .expect("Cannot read data.");
+        }
+
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while task_count.load(Ordering::SeqCst) > 0 {
+                wait(10).await;
+            }
+        }).await
+            .expect("Must stop all the tasks once the channels are dropped.");
+    }
+
+    #[tokio::test]
+    async fn stops_forwarders_after_linger_timeout() {
+        let (spawner, task_count) = TaskCounter::new();
+        let options = MultiplexerOptions::default()
+            .spawner(spawner)
+            .linger_timeout(Duration::from_millis(100));
+
+        let (local_channel, remote_channel) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+
+        let (mut local_channel1, local_channel2) = divide_channel(local_channel, options);
+
+        // the remote side never grants any credit nor closes the channel
+        let _remote_channel = LayerCodec::default().framed(remote_channel);
+
+        local_channel1.write_all(random_str(1_024).as_bytes()).await
+            .expect("Cannot write data.");
+
+        drop(local_channel1);
+        drop(local_channel2);
+
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while task_count.load(Ordering::SeqCst) > 0 {
+                wait(10).await;
+            }
+        }).await
+            .expect("Must stop all the tasks once the linger timeout expires.");
+    }
+
+    #[tokio::test]
+    async fn aborts_and_joins_tasks() {
+        let (spawner, task_count) = TaskCounter::new();
+        let options = MultiplexerOptions::default()
+            .spawner(spawner)
+            .substreams(2);
+
+        let (local_channel, remote_channel) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+
+        let (local_multiplexer, mut local_substreams) = Multiplexer::with_options(local_channel, options);
+
+        // the remote side never grants any credit nor closes the channel
+        let _remote_channel = LayerCodec::default().framed(remote_channel);
+
+        // the forwarder, and the reader and writer of both substreams
+        assert_eq!(
+            task_count.load(Ordering::SeqCst),
+            1 + 2 * 2,
+            "Must keep the handles of all the tasks.",
+        );
+
+        local_multiplexer.tasks().abort();
+
+        tokio::time::timeout(Duration::from_secs(1), local_multiplexer.tasks().join()).await
+            .expect("Must join the aborted tasks.");
+
+        assert_eq!(
+            task_count.load(Ordering::SeqCst),
+            0,
+            "Must stop all the tasks.",
+        );
+
+        let mut buf = [0; 32];
+
+        assert_eq!(
+            local_substreams[0].read(&mut buf).await.unwrap_or(0),
+            0,
+            "Must close the substreams.",
+        );
+    }
+
+    #[tokio::test]
+    async fn closes_remote_substreams_once_dropped() {
+        let (local_channel, remote_channel) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+
+        let (mut local_channel1, local_channel2) = divide_channel(local_channel, MultiplexerOptions::default());
+        let (mut remote_channel1, mut remote_channel2) = divide_channel(remote_channel, MultiplexerOptions::default());
+
+        let data = random_str(1_024);
+
+        local_channel1.write_all(data.as_bytes()).await
+            .expect("Cannot write data.");
+
+        drop(local_channel1);
+        drop(local_channel2);
+
+        let mut received_data = vec![];
+
+        remote_channel1.read_to_end(&mut received_data).await
+            .expect("Cannot read data.");
+
+        assert_eq!(
+            String::from_utf8(received_data).unwrap(),
+            data,
+            "Must receive the data written before the drop.",
+        );
+
+        let mut buf = [0; 32];
+
+        assert_eq!(
+            remote_channel2.read(&mut buf).await
+                .expect("Cannot read data."),
+            0,
+            "Must get EOF once the remote side is dropped.",
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_if_remote_exceeds_window() {
+        let (local_channel, remote_channel) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+
+        let options = MultiplexerOptions::default()
+            .substream(1_024);
+
+        let (_local_multiplexer, mut local_substreams) = Multiplexer::with_options(local_channel, options);
+
+        let mut remote_channel = LayerCodec::default().framed(remote_channel);
+
+        remote_channel.send(LayerMessage::Data(SubstreamId::Fixed(0), vec![0; 2_048].into())).await
+            .expect("Cannot send message.");
+
+        let mut buf = [0; 32];
+
+        let error = local_substreams[0].read(&mut buf).await
+            .expect_err("Must fail the read.");
+
+        assert_eq!(
+            error.into_inner().and_then(|error| error.downcast::<UpgradeError>().ok()).map(|error| *error),
+            Some(UpgradeError::WindowExceeded),
+            "Must fail with the flow control error.",
+        );
+    }
+
+    #[rstest]
+    #[case(vec![u64::MAX as usize])]
+    #[case(vec![1_024, 1_024])]
+    #[case(vec![1_024, u64::MAX as usize])]
+    #[tokio::test]
+    async fn fails_if_remote_exceeds_window_with_credit(
+        #[case] credits: Vec<usize>,
+    ) {
+        let (local_channel, remote_channel) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+
+        let (_local_multiplexer, mut local_substreams) = Multiplexer::with_substreams(local_channel, 1);
+
+        let mut remote_channel = LayerCodec::default().framed(remote_channel);
+
+        // the first grant advertises the window of the remote side
+        for credit in credits {
+            remote_channel.send(LayerMessage::Credit(SubstreamId::Fixed(0), credit)).await
+                .expect("Cannot send message.");
+        }
+
+        let mut buf = [0; 32];
+
+        let error = local_substreams[0].read(&mut buf).await
+            .expect_err("Must fail the read.");
+
+        assert_eq!(
+            error.into_inner().and_then(|error| error.downcast::<UpgradeError>().ok()).map(|error| *error),
+            Some(UpgradeError::WindowExceeded),
+            "Must fail with the flow control error.",
+        );
+    }
+
+    #[rstest]
+    #[case(1_024, DEFAULT_WINDOW_SIZE)]
+    #[case(DEFAULT_WINDOW_SIZE, 256 * 1_024)]
+    #[case(256 * 1_024, 1_024)]
+    #[tokio::test]
+    async fn works_with_different_windows_on_both_sides(
+        #[case] local_window_size: usize,
+        #[case] remote_window_size: usize,
+    ) {
+        let (local_channel, remote_channel) = channel_mock_pair(ChannelMockOptions::random(), ChannelMockOptions::random());
+
+        let local_options = MultiplexerOptions::default()
+            .window_size(local_window_size)
+            .substreams(1);
+        let remote_options = MultiplexerOptions::default()
+            .window_size(remote_window_size)
+            .substreams(1);
+
+        let (local_multiplexer, mut local_substreams) = Multiplexer::with_options(local_channel, local_options);
+        let (mut remote_multiplexer, mut remote_substreams) = Multiplexer::with_options(remote_channel, remote_options);
+
+        // each side uses its own window for the substreams it receives the data of
+        let local_data = local_multiplexer.open_substream("data");
+        let remote_data = remote_multiplexer.accept_substream().await
+            .expect("Cannot accept substream.");
+
+        let pairs = vec![
+            (local_substreams.remove(0), remote_substreams.remove(0)),
+            (local_data, remote_data),
+        ];
+
+        let tasks = pairs.into_iter()
+            .map(|(local_substream, remote_substream)| {
+                return tokio::spawn(async move {
+                    test_async_stream(
+                        local_substream,
+                        remote_substream,
+                        random_str(16 * 1_024),
+                    ).await;
+                });
+            });
+
+        for result in futures::future::join_all(tasks).await {
+            result.expect("Substream test failed.");
+        }
+    }
 }
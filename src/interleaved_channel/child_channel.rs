@@ -1,27 +1,42 @@
-use std::{pin::Pin, task::{Context, Poll}, io};
+use std::{pin::Pin, task::{Context, Poll}, io, sync::{Arc, Mutex}};
 
 use connection_utils::Channel;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 #[cfg(test)]
 use cs_utils::{random_number, random_str};
 
+use crate::errors::UpgradeError;
+
+use super::ForwarderGuard;
+
 pub struct ChildChannel<TAsyncDuplex: AsyncRead + AsyncWrite + Send + Unpin + 'static> {
     id: u16,
     label: String,
     channel: Pin<Box<TAsyncDuplex>>,
+    // failure of the parent channel, set before the `channel` is closed
+    error: Arc<Mutex<Option<UpgradeError>>>,
+    // the forwarders of the parent channel stop once all of its children are dropped
+    _forwarders: Option<Arc<ForwarderGuard>>,
 }
 
 impl<TAsyncDuplex: AsyncRead + AsyncWrite + Send + Unpin + 'static> ChildChannel<TAsyncDuplex> {
+    /// Create a child channel that fails with the `error` once it is set,
+    /// and keeps the `forwarders` of the parent channel running.
+    #[allow(clippy::new_ret_no_self)] // the substreams are handed out as `Channel`s
     pub fn new(
         id: u16,
         label: impl AsRef<str> + ToString,
         channel: Box<TAsyncDuplex>,
+        error: Arc<Mutex<Option<UpgradeError>>>,
+        forwarders: Option<Arc<ForwarderGuard>>,
     ) -> Box<dyn Channel> {
         return Box::new(
             ChildChannel {
                 id,
                 label: label.to_string(),
                 channel: Pin::new(channel),
+                error,
+                _forwarders: forwarders,
             },
         );
     }
@@ -33,9 +48,26 @@ impl<TAsyncDuplex: AsyncRead + AsyncWrite + Send + Unpin + 'static> ChildChannel
                 id: random_number(0..=u16::MAX),
                 label: format!("child-channel-{}", random_str(8)),
                 channel: Pin::new(channel),
+                error: Arc::new(Mutex::new(None)),
+                _forwarders: None,
             },
         );
     }
+
+    /// Error of the parent channel, if it has failed.
+    fn check_error(&self) -> io::Result<()> {
+        let error = match self.error.lock().unwrap().clone() {
+            Some(error) => error,
+            None => return Ok(()),
+        };
+
+        let kind = match error {
+            UpgradeError::Transport(kind, _) => kind,
+            _ => io::ErrorKind::InvalidData,
+        };
+
+        return Err(io::Error::new(kind, error));
+    }
 }
 
 impl<TAsyncDuplex: AsyncRead + AsyncWrite + Send + Unpin + 'static> Channel for ChildChannel<TAsyncDuplex> {
@@ -54,6 +86,8 @@ impl<TAsyncDuplex: AsyncRead + AsyncWrite + Send + Unpin + 'static> AsyncRead fo
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
+        self.check_error()?;
+
         return self.channel.as_mut()
             .poll_read(cx, buf);
     }
@@ -65,11 +99,15 @@ impl<TAsyncDuplex: AsyncRead + AsyncWrite + Send + Unpin + 'static> AsyncWrite f
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<io::Result<usize>> {
+        self.check_error()?;
+
         return self.channel.as_mut()
             .poll_write(cx, buf);
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.check_error()?;
+
         return self.channel.as_mut()
             .poll_flush(cx);
     }
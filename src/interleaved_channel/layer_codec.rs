@@ -0,0 +1,271 @@
+use std::{pin::Pin, mem, convert::TryFrom};
+
+use bytes::{Buf, BufMut, BytesMut};
+use connection_utils::Channel;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+use crate::{errors::UpgradeError, DEFAULT_MAX_FRAME_SIZE};
+
+use super::{LayerMessage, SubstreamId};
+
+pub type TLayerChannel = Framed<Pin<Box<dyn Channel>>, LayerCodec>;
+
+const DATA: u8 = 0;
+const OPEN: u8 = 1;
+const CREDIT: u8 = 2;
+const CLOSE: u8 = 3;
+
+const FIXED: u8 = 0;
+const LOCAL: u8 = 1;
+const REMOTE: u8 = 2;
+
+/// Frame kind, substream kind, substream id and payload length.
+const HEADER_SIZE: usize = 1 + 1 + mem::size_of::<u32>() + mem::size_of::<u32>();
+
+/// Binary codec of the multiplexed channel messages, all integers are big endian:
+///
+/// | frame kind: `u8` | substream kind: `u8` | substream id: `u32` | payload length: `u32` | payload |
+///
+/// The payload is the data, the substream label of an `Open` frame,
+/// or the `u64` credit of a `Credit` frame.
+#[derive(Debug)]
+pub struct LayerCodec {
+    // largest payload accepted from the remote side
+    max_payload_size: usize,
+}
+
+impl LayerCodec {
+    pub fn new(max_payload_size: usize) -> LayerCodec {
+        return LayerCodec { max_payload_size };
+    }
+
+    pub fn framed(self, channel: Box<dyn Channel>) -> TLayerChannel {
+        return Framed::new(Pin::new(channel), self);
+    }
+}
+
+impl Default for LayerCodec {
+    fn default() -> LayerCodec {
+        return LayerCodec::new(DEFAULT_MAX_FRAME_SIZE);
+    }
+}
+
+impl Decoder for LayerCodec {
+    type Item = LayerMessage;
+    type Error = UpgradeError;
+
+    fn decode(
+        &mut self,
+        buf: &mut BytesMut,
+    ) -> Result<Option<LayerMessage>, UpgradeError> {
+        if buf.len() < HEADER_SIZE {
+            buf.reserve(HEADER_SIZE - buf.len());
+
+            return Ok(None);
+        }
+
+        let payload_size = (&buf[HEADER_SIZE - mem::size_of::<u32>()..HEADER_SIZE]).get_u32() as usize;
+
+        // peers without the `Hello` message send length delimited JSON, its `{"` lands where
+        // the substream id ends and its text makes up a payload length of gigabytes
+        if payload_size > self.max_payload_size && &buf[4..6] == b"{\"" {
+            return Err(UpgradeError::UnsupportedVersion(0));
+        }
+
+        if payload_size > self.max_payload_size {
+            return Err(UpgradeError::Decode(format!("Frame payload of {} bytes is too large.", payload_size)));
+        }
+
+        if buf.len() < HEADER_SIZE + payload_size {
+            buf.reserve(HEADER_SIZE + payload_size - buf.len());
+
+            return Ok(None);
+        }
+
+        let frame_kind = buf.get_u8();
+        let substream_kind = buf.get_u8();
+        let substream_id = buf.get_u32();
+        let _payload_size = buf.get_u32();
+        let payload = buf.split_to(payload_size).freeze();
+
+        let id = match substream_kind {
+            FIXED => SubstreamId::Fixed(substream_id),
+            LOCAL => SubstreamId::Local(substream_id),
+            REMOTE => SubstreamId::Remote(substream_id),
+            _ => return Err(UpgradeError::Decode(format!("Unknown substream kind {}.", substream_kind))),
+        };
+
+        let message = match frame_kind {
+            DATA => LayerMessage::Data(id, payload),
+            OPEN => {
+                let label = String::from_utf8(payload.to_vec())
+                    .map_err(|error| { return UpgradeError::Decode(error.to_string()); })?;
+
+                LayerMessage::Open(id, label)
+            },
+            CREDIT => {
+                if payload.len() != mem::size_of::<u64>() {
+                    return Err(UpgradeError::Decode("Malformed credit frame.".to_string()));
+                }
+
+                let credit = usize::try_from((&payload[..]).get_u64())
+                    .map_err(|_| { return UpgradeError::Decode("Credit out of range.".to_string()); })?;
+
+                LayerMessage::Credit(id, credit)
+            },
+            CLOSE => LayerMessage::Close(id),
+            _ => return Err(UpgradeError::Decode(format!("Unknown frame kind {}.", frame_kind))),
+        };
+
+        return Ok(Some(message));
+    }
+}
+
+impl Encoder<LayerMessage> for LayerCodec {
+    type Error = UpgradeError;
+
+    fn encode(
+        &mut self,
+        message: LayerMessage,
+        buf: &mut BytesMut,
+    ) -> Result<(), UpgradeError> {
+        let (frame_kind, id, payload) = match &message {
+            LayerMessage::Data(id, data) => (DATA, id, &data[..]),
+            LayerMessage::Open(id, label) => (OPEN, id, label.as_bytes()),
+            LayerMessage::Credit(id, _) => (CREDIT, id, &[][..]),
+            LayerMessage::Close(id) => (CLOSE, id, &[][..]),
+        };
+
+        let (substream_kind, substream_id) = match *id {
+            SubstreamId::Fixed(id) => (FIXED, id),
+            SubstreamId::Local(id) => (LOCAL, id),
+            SubstreamId::Remote(id) => (REMOTE, id),
+        };
+
+        let payload_size = match &message {
+            LayerMessage::Credit(..) => mem::size_of::<u64>(),
+            _ => payload.len(),
+        };
+
+        buf.reserve(HEADER_SIZE + payload_size);
+        buf.put_u8(frame_kind);
+        buf.put_u8(substream_kind);
+        buf.put_u32(substream_id);
+        buf.put_u32(payload_size as u32);
+
+        match &message {
+            LayerMessage::Credit(_, credit) => buf.put_u64(*credit as u64),
+            _ => buf.put_slice(payload),
+        };
+
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Bytes, BytesMut, BufMut};
+    use rstest::rstest;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use crate::errors::UpgradeError;
+
+    use super::{LayerCodec, LayerMessage, SubstreamId};
+
+    #[rstest]
+    #[case(LayerMessage::Data(SubstreamId::Fixed(0), Bytes::from_static(b"data")))]
+    #[case(LayerMessage::Data(SubstreamId::Local(7), Bytes::new()))]
+    #[case(LayerMessage::Open(SubstreamId::Local(u32::MAX), "telemetry".to_string()))]
+    #[case(LayerMessage::Credit(SubstreamId::Remote(3), 64 * 1_024))]
+    #[case(LayerMessage::Close(SubstreamId::Remote(1)))]
+    fn encodes_messages(
+        #[case] message: LayerMessage,
+    ) {
+        let mut buf = BytesMut::new();
+
+        LayerCodec::default().encode(message.clone(), &mut buf)
+            .expect("Cannot encode message.");
+
+        // the frame is decoded only once all of it is received
+        let mut partial_buf = BytesMut::from(&buf[..buf.len() - 1]);
+
+        assert_eq!(
+            LayerCodec::default().decode(&mut partial_buf).expect("Cannot decode message."),
+            None,
+            "Must wait for the rest of the frame.",
+        );
+
+        assert_eq!(
+            LayerCodec::default().decode(&mut buf).expect("Cannot decode message."),
+            Some(message),
+            "Must decode the message.",
+        );
+
+        assert!(
+            buf.is_empty(),
+            "Must consume the whole frame.",
+        );
+    }
+
+    #[rstest]
+    #[case(&[9, 0, 0, 0, 0, 0, 0, 0, 0, 0])]
+    #[case(&[0, 9, 0, 0, 0, 0, 0, 0, 0, 0])]
+    #[case(&[2, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0])]
+    #[case(&[1, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0xff])]
+    fn fails_on_malformed_frame(
+        #[case] frame: &[u8],
+    ) {
+        let mut buf = BytesMut::from(frame);
+
+        assert!(
+            matches!(LayerCodec::default().decode(&mut buf), Err(UpgradeError::Decode(_))),
+            "Must fail with the decode error.",
+        );
+    }
+
+    #[test]
+    fn fails_on_json_frame() {
+        // the data of the first substream sent by a peer without the `Hello` message
+        let payload = br#"{"Channel1":[123,34,72,101,108,108,111,34,125]}"#;
+
+        let mut buf = BytesMut::new();
+
+        buf.put_u32(payload.len() as u32);
+        buf.put_slice(payload);
+
+        assert_eq!(
+            LayerCodec::default().decode(&mut buf),
+            Err(UpgradeError::UnsupportedVersion(0)),
+            "Must fail with the version error.",
+        );
+    }
+
+    #[test]
+    fn decodes_substream_id_resembling_json() {
+        let message = LayerMessage::Data(SubstreamId::Fixed(0x7B22), Bytes::from_static(b"data"));
+
+        let mut buf = BytesMut::new();
+
+        LayerCodec::default().encode(message.clone(), &mut buf)
+            .expect("Cannot encode message.");
+
+        assert_eq!(
+            LayerCodec::default().decode(&mut buf),
+            Ok(Some(message)),
+            "Must decode the message.",
+        );
+    }
+
+    #[test]
+    fn fails_on_oversized_frame() {
+        let mut buf = BytesMut::new();
+
+        buf.put_slice(&[0, 0, 0, 0, 0, 0]);
+        buf.put_u32(1_025);
+
+        assert!(
+            matches!(LayerCodec::new(1_024).decode(&mut buf), Err(UpgradeError::Decode(_))),
+            "Must not wait for an oversized frame.",
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use std::{cmp, sync::{Arc, Mutex, atomic::{AtomicU32, Ordering}}};
+
+use connection_utils::Channel;
+use tokio::sync::mpsc;
+
+use crate::Tasks;
+
+use super::{SubstreamId, SubstreamFactory, MultiplexerOptions, ForwarderGuard, forward};
+
+/// Multiplexes substreams over a single channel, both sides can open new substreams
+/// at any time. The channel is shut down once the multiplexer and its substreams are dropped.
+pub struct Multiplexer {
+    next_substream_id: AtomicU32,
+    window_size: usize,
+    factory: SubstreamFactory,
+    on_accept: mpsc::UnboundedReceiver<Box<dyn Channel>>,
+    tasks: Tasks,
+    _forwarders: Arc<ForwarderGuard>,
+}
+
+impl Multiplexer {
+    pub fn new(channel: Box<dyn Channel>) -> Multiplexer {
+        let (multiplexer, _substreams) = Multiplexer::with_options(channel, MultiplexerOptions::default());
+
+        return multiplexer;
+    }
+
+    /// Create the multiplexer along with `count` substreams known to both sides
+    /// upfront, the remote side must create the same number of them.
+    pub fn with_substreams(
+        channel: Box<dyn Channel>,
+        count: u32,
+    ) -> (Multiplexer, Vec<Box<dyn Channel>>) {
+        return Multiplexer::with_options(channel, MultiplexerOptions::default().substreams(count));
+    }
+
+    /// Create the multiplexer along with the fixed substreams of the `options`.
+    pub fn with_options(
+        channel: Box<dyn Channel>,
+        options: MultiplexerOptions,
+    ) -> (Multiplexer, Vec<Box<dyn Channel>>) {
+        let id = channel.id();
+        let label = channel.label().clone();
+
+        let (substream_sender, substream_receiver) = mpsc::unbounded_channel();
+        let (message_sender, message_receiver) = mpsc::unbounded_channel();
+        let (accept_sender, accept_receiver) = mpsc::unbounded_channel();
+        let (forwarders, stop) = ForwarderGuard::new();
+        let forwarders = Arc::new(forwarders);
+        let spawner = options.get_spawner().tracked();
+
+        let factory = SubstreamFactory {
+            channel_id: id,
+            buffers: options.get_buffers().clone(),
+            trace: child_trace!(options.get_trace(), "multiplexer", id = id, label = %label),
+            spawner: spawner.clone(),
+            forwarders: Arc::downgrade(&forwarders),
+            error: Arc::new(Mutex::new(None)),
+            on_substream: substream_sender,
+            on_message: message_sender,
+        };
+
+        let substreams = options.get_substream_window_sizes().into_iter()
+            .enumerate()
+            .map(|(index, window_size)| {
+                return factory.create(SubstreamId::Fixed(index as u32), &label, window_size, false);
+            })
+            .collect();
+
+        forward(
+            channel,
+            substream_receiver,
+            message_receiver,
+            factory.clone(),
+            accept_sender,
+            &options,
+            stop,
+        );
+
+        let multiplexer = Multiplexer {
+            next_substream_id: AtomicU32::new(0),
+            window_size: options.get_window_size(),
+            factory,
+            on_accept: accept_receiver,
+            tasks: spawner.tasks(),
+            _forwarders: forwarders,
+        };
+
+        return (multiplexer, substreams);
+    }
+
+    /// Open a new substream, the remote side gets it from `accept_substream`.
+    pub fn open_substream(
+        &self,
+        label: impl AsRef<str> + ToString,
+    ) -> Box<dyn Channel> {
+        return self.open_substream_with_window(label, self.window_size);
+    }
+
+    /// Open a new substream with its own receive window, see `MultiplexerOptions::window_size`.
+    pub fn open_substream_with_window(
+        &self,
+        label: impl AsRef<str> + ToString,
+        window_size: usize,
+    ) -> Box<dyn Channel> {
+        let id = SubstreamId::Local(self.next_substream_id.fetch_add(1, Ordering::SeqCst));
+
+        return self.factory.create(id, label, cmp::max(window_size, 1), true);
+    }
+
+    /// Wait for the next substream opened by the remote side,
+    /// returns `None` once the multiplexed channel is closed.
+    pub async fn accept_substream(&mut self) -> Option<Box<dyn Channel>> {
+        return self.on_accept.recv().await;
+    }
+
+    /// Background tasks of the multiplexer and its substreams. Aborting them closes the
+    /// substreams right away, joining them waits until the multiplexer and its substreams
+    /// are dropped and the forwarders stop by themselves, see `MultiplexerOptions::linger_timeout`.
+    pub fn tasks(&self) -> &Tasks {
+        return &self.tasks;
+    }
+}
@@ -0,0 +1,137 @@
+use std::{cmp, time::Duration};
+
+use crate::{BufferOptions, Spawner, TraceSink};
+
+/// How long the forwarders keep running once the multiplexer and its substreams
+/// are dropped unless configured otherwise, see `MultiplexerOptions::linger_timeout`.
+pub const DEFAULT_LINGER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Options of a `Multiplexer`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MultiplexerOptions {
+    buffers: BufferOptions,
+    // receive windows of the fixed substreams, the default one if not set
+    fixed_substreams: Vec<Option<usize>>,
+    trace: TraceSink,
+    spawner: Spawner,
+    linger_timeout: Option<Duration>,
+}
+
+impl MultiplexerOptions {
+    /// Receive window of the substreams without their own one, that is the number
+    /// of bytes the remote side can send before the local side reads them. Once
+    /// the window is full, the remote side waits without blocking other substreams.
+    /// Same as the `BufferOptions::max_buffered_size`.
+    pub fn window_size(
+        self,
+        window_size: usize,
+    ) -> MultiplexerOptions {
+        return MultiplexerOptions {
+            buffers: self.buffers.max_buffered_size(window_size),
+            ..self
+        };
+    }
+
+    /// Sizes of the buffers of the substreams, replaces the `window_size`.
+    pub fn buffers(
+        self,
+        buffers: BufferOptions,
+    ) -> MultiplexerOptions {
+        return MultiplexerOptions {
+            buffers,
+            ..self
+        };
+    }
+
+    /// Trace the multiplexer and its substreams under the `trace`, nothing is traced by default.
+    pub fn trace(
+        self,
+        trace: TraceSink,
+    ) -> MultiplexerOptions {
+        return MultiplexerOptions {
+            trace,
+            ..self
+        };
+    }
+
+    /// Spawner of the forwarders and the substream tasks, `tokio::spawn` by default.
+    pub fn spawner(
+        self,
+        spawner: Spawner,
+    ) -> MultiplexerOptions {
+        return MultiplexerOptions {
+            spawner,
+            ..self
+        };
+    }
+
+    /// Once the multiplexer and its substreams are dropped, the forwarders keep running
+    /// for up to the `linger_timeout` to send the data written so far and let the remote
+    /// side read it, then they are stopped even if the remote side does not read.
+    pub fn linger_timeout(
+        self,
+        linger_timeout: Duration,
+    ) -> MultiplexerOptions {
+        return MultiplexerOptions {
+            linger_timeout: Some(linger_timeout),
+            ..self
+        };
+    }
+
+    /// Add `count` fixed substreams, created by both sides along with the multiplexer.
+    pub fn substreams(
+        self,
+        count: u32,
+    ) -> MultiplexerOptions {
+        let mut fixed_substreams = self.fixed_substreams;
+
+        fixed_substreams.extend((0..count).map(|_| None));
+
+        return MultiplexerOptions {
+            fixed_substreams,
+            ..self
+        };
+    }
+
+    /// Add a fixed substream with its own receive window, see `substreams`.
+    pub fn substream(
+        self,
+        window_size: usize,
+    ) -> MultiplexerOptions {
+        let mut fixed_substreams = self.fixed_substreams;
+
+        fixed_substreams.push(Some(cmp::max(window_size, 1)));
+
+        return MultiplexerOptions {
+            fixed_substreams,
+            ..self
+        };
+    }
+
+    pub fn get_window_size(&self) -> usize {
+        return self.buffers.get_max_buffered_size();
+    }
+
+    pub fn get_buffers(&self) -> &BufferOptions {
+        return &self.buffers;
+    }
+
+    pub fn get_trace(&self) -> &TraceSink {
+        return &self.trace;
+    }
+
+    pub fn get_spawner(&self) -> &Spawner {
+        return &self.spawner;
+    }
+
+    pub fn get_linger_timeout(&self) -> Duration {
+        return self.linger_timeout.unwrap_or(DEFAULT_LINGER_TIMEOUT);
+    }
+
+    /// Receive windows of the fixed substreams.
+    pub fn get_substream_window_sizes(&self) -> Vec<usize> {
+        return self.fixed_substreams.iter()
+            .map(|window_size| { return window_size.unwrap_or(self.get_window_size()); })
+            .collect();
+    }
+}
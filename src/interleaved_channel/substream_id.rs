@@ -0,0 +1,23 @@
+/// Id of a multiplexed substream, relative to the side that holds it.
+/// The sides number the substreams they open independently, hence the
+/// ids of the substreams opened simultaneously never collide.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SubstreamId {
+    /// Created by both sides along with the multiplexer.
+    Fixed(u32),
+    /// Opened by the local side.
+    Local(u32),
+    /// Opened by the remote side.
+    Remote(u32),
+}
+
+impl SubstreamId {
+    /// Id of the same substream on the remote side.
+    pub fn remote(&self) -> SubstreamId {
+        return match *self {
+            SubstreamId::Fixed(id) => SubstreamId::Fixed(id),
+            SubstreamId::Local(id) => SubstreamId::Remote(id),
+            SubstreamId::Remote(id) => SubstreamId::Local(id),
+        };
+    }
+}
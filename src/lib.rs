@@ -3,15 +3,34 @@ pub mod types;
 mod traits;
 pub use traits::TUpgradableChannel;
 
+#[macro_use]
+mod trace_sink;
+pub use trace_sink::TraceSink;
+
+mod spawner;
+pub use spawner::{Spawner, Spawn, TTask, Task, TaskHandle, Tasks};
+
 mod channel;
-pub use channel::UpgradableChannel;
+pub use channel::{UpgradableChannel, UpgradableChannelOptions, UpgradableChannelBuilder, UpgradeHandle, UpgradeReport, UpgradeState, ResumptionToken, Capability, PROTOCOL_VERSION};
+
+mod errors;
+pub use errors::UpgradeError;
+
+mod buffer_options;
+pub use buffer_options::{BufferOptions, DEFAULT_WINDOW_SIZE, DEFAULT_DUPLEX_CAPACITY, DEFAULT_READ_CHUNK_SIZE, DEFAULT_MAX_FRAME_SIZE, DEFAULT_MAX_UNCONFIRMED_SIZE};
 
 pub mod mocks;
 
+mod codec;
+
 mod interleaved_channel;
+pub use interleaved_channel::{Multiplexer, MultiplexerOptions, DEFAULT_LINGER_TIMEOUT, multiplex, multiplex_with_options};
 
 mod utils;
 
+/// Printable dump of the data, the bytes that are not printable ASCII
+/// are escaped and only the first 64 bytes are shown.
+#[deprecated(note = "Dumps of the data are meant for the logs only, the function will be removed.")]
 pub fn buf_to_str(buf: &[u8]) -> String {
-    return String::from_utf8(buf.to_vec()).unwrap();
+    return utils::dump_buf(buf);
 }
@@ -1,4 +1,4 @@
-use std::{pin::Pin, task::{Context, Poll}, io, ops::RangeInclusive};
+use std::{pin::Pin, task::{Context, Poll}, io, cmp, ops::RangeInclusive};
 
 use futures::{Future, ready};
 use connection_utils::Channel;
@@ -24,6 +24,7 @@ pub struct ChannelMock<TAsyncDuplex: AsyncRead + AsyncWrite + Send + Unpin + 'st
 }
 
 impl<TAsyncDuplex: AsyncRead + AsyncWrite + Send + Unpin + 'static> ChannelMock<TAsyncDuplex> {
+    #[allow(clippy::new_ret_no_self)] // the mock stands in for any `Channel`
     pub fn new(
         channel: Box<TAsyncDuplex>,
         options: ChannelMockOptions,
@@ -46,6 +47,7 @@ pub struct ChannelMockOptions {
     id: u16,
     label: String,
     throttle_range: RangeInclusive<u64>,
+    buffer_size: usize,
 }
 
 impl ChannelMockOptions {
@@ -78,6 +80,22 @@ impl ChannelMockOptions {
             ..self
         };
     }
+
+    /// Capacity of the in-memory pipe between the mocks,
+    /// a pair uses the larger one of both sides.
+    pub fn buffer_size(
+        self,
+        buffer_size: usize,
+    ) -> ChannelMockOptions {
+        return ChannelMockOptions {
+            buffer_size: cmp::max(buffer_size, 1),
+            ..self
+        };
+    }
+
+    fn is_throttled(&self) -> bool {
+        return *self.throttle_range.end() > 0;
+    }
 }
 
 impl Random for ChannelMockOptions {
@@ -96,6 +114,7 @@ impl Default for ChannelMockOptions {
             id: random_number(0..=u16::MAX),
             label: format!("channel-mock-{}", random_str(8)),
             throttle_range: (0..=0),
+            buffer_size: 1024,
         };
     }
 }
@@ -104,7 +123,7 @@ pub fn channel_mock_pair(
     options1: ChannelMockOptions,
     options2: ChannelMockOptions,
 ) -> (Box<dyn Channel>, Box<dyn Channel>) {
-    let (channel1, channel2) = duplex(1024);
+    let (channel1, channel2) = duplex(cmp::max(options1.buffer_size, options2.buffer_size));
 
     return (
         ChannelMock::new(Box::new(channel1), options1),
@@ -138,14 +157,11 @@ impl<TAsyncDuplex: AsyncRead + AsyncWrite + Send + Unpin + 'static> AsyncRead fo
         // otherwise run the read future to completion
         let result = ready!(self.channel.as_mut().poll_read(cx, buf));
 
-        // println!("[{}]> read some data: {:?}", self.id, result);
-
-        // optionally create a throttle delay future
-        // if random_bool() {
-            // println!("[{}]> create new timeout", self.id);
-        
-        self.read_delay_future = Some(Box::pin(wait_random(self.options.throttle_range.clone())));
-        // }
+        // optionally create a throttle delay future, even a zero delay
+        // takes a timer tick, hence skip it if there is no throttling
+        if self.options.is_throttled() {
+            self.read_delay_future = Some(Box::pin(wait_random(self.options.throttle_range.clone())));
+        }
 
         return Poll::Ready(result);
     }
@@ -166,12 +182,11 @@ impl<TAsyncDuplex: AsyncRead + AsyncWrite + Send + Unpin + 'static> AsyncWrite f
 
         let result = ready!(self.channel.as_mut().poll_write(cx, buf));
 
-        // optionally create a throttle delay future
-        // if random_bool() {
-            // println!("[{}]> create new timeout", self.id);
-        
-        self.write_delay_future = Some(Box::pin(wait_random(self.options.throttle_range.clone())));
-        // }
+        // optionally create a throttle delay future, even a zero delay
+        // takes a timer tick, hence skip it if there is no throttling
+        if self.options.is_throttled() {
+            self.write_delay_future = Some(Box::pin(wait_random(self.options.throttle_range.clone())));
+        }
 
         return Poll::Ready(result);
     }
@@ -189,7 +204,7 @@ impl<TAsyncDuplex: AsyncRead + AsyncWrite + Send + Unpin + 'static> AsyncWrite f
         cx: &mut Context<'_>,
     ) -> Poll<io::Result<()>> {
         return self.channel.as_mut()
-            .poll_flush(cx);
+            .poll_shutdown(cx);
     }
 }
 
@@ -0,0 +1,398 @@
+use std::{fmt, future::Future, pin::Pin, sync::{Arc, Mutex, OnceLock}};
+
+use futures::{channel::oneshot, future::{FutureExt, Shared}};
+use tokio::{runtime::Handle, task::JoinHandle};
+
+/// Background task of a channel or a multiplexer.
+pub type TTask = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// Handle of a spawned task, returned by `Spawn::spawn`. The `Spawner`
+/// tells the completion of the task itself, see `Task::join`.
+pub trait TaskHandle: Send + Sync {
+    /// Stop the task, it is dropped at its next await point.
+    fn abort(&self);
+
+    /// Whether the task has completed or was aborted.
+    fn is_finished(&self) -> bool;
+}
+
+impl TaskHandle for JoinHandle<()> {
+    fn abort(&self) {
+        JoinHandle::abort(self);
+    }
+
+    fn is_finished(&self) -> bool {
+        return JoinHandle::is_finished(self);
+    }
+}
+
+/// Runs the background tasks, implement it to spawn them
+/// into a supervised task tree, see `Spawner::new`.
+pub trait Spawn: Send + Sync {
+    /// Spawn the `task`, the `name` tells the kind of the task apart. The tasks stop
+    /// by themselves once the channel is dropped, the handle stops them right away.
+    fn spawn(&self, name: &'static str, task: TTask) -> Box<dyn TaskHandle>;
+}
+
+/// Task spawned by a `Spawner`, the clones refer to the same task.
+#[derive(Clone)]
+pub struct Task {
+    name: &'static str,
+    handle: Arc<dyn TaskHandle>,
+    // resolves once the task has completed or got dropped, aborted tasks are dropped
+    done: Shared<oneshot::Receiver<()>>,
+}
+
+impl Task {
+    /// Kind of the task, see `Spawn::spawn`.
+    pub fn name(&self) -> &'static str {
+        return self.name;
+    }
+
+    /// Stop the task, it is dropped at its next await point.
+    pub fn abort(&self) {
+        self.handle.abort();
+    }
+
+    /// Whether the task has completed or was aborted.
+    pub fn is_finished(&self) -> bool {
+        return self.handle.is_finished();
+    }
+
+    /// Resolves once the task has completed or was aborted.
+    pub async fn join(&self) {
+        let _res = self.done.clone().await;
+    }
+}
+
+impl fmt::Debug for Task {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return f.debug_struct("Task")
+            .field("name", &self.name)
+            .field("is_finished", &self.is_finished())
+            .finish();
+    }
+}
+
+/// Tasks of a channel or a multiplexer, see `UpgradeHandle::tasks` and
+/// `Multiplexer::tasks`. The finished tasks are forgotten as the new ones are spawned.
+#[derive(Clone, Default)]
+pub struct Tasks {
+    tasks: Arc<Mutex<Vec<Task>>>,
+}
+
+impl Tasks {
+    fn push(
+        &self,
+        task: Task,
+    ) {
+        let mut tasks = self.tasks.lock().unwrap();
+
+        tasks.retain(|task| { return !task.is_finished(); });
+        tasks.push(task);
+    }
+
+    /// Stop all the tasks, the channel fails right away without notifying the remote side.
+    pub fn abort(&self) {
+        for task in self.tasks.lock().unwrap().iter() {
+            task.abort();
+        }
+    }
+
+    /// Resolves once all the tasks, including the ones spawned meanwhile, have finished.
+    pub async fn join(&self) {
+        loop {
+            // the tasks spawned meanwhile are joined on the next iteration
+            let tasks = self.tasks.lock().unwrap().clone();
+
+            let tasks_left = tasks.iter()
+                .filter(|task| { return task.done.peek().is_none(); })
+                .count();
+
+            if tasks_left == 0 {
+                return;
+            }
+
+            for task in tasks {
+                task.join().await;
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Tasks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return f.debug_struct("Tasks")
+            .field("count", &self.tasks.lock().unwrap().len())
+            .finish();
+    }
+}
+
+/// Spawns with `tokio::spawn`, or on the runtime of the `handle` if set.
+struct TokioSpawn {
+    handle: Option<Handle>,
+}
+
+impl Spawn for TokioSpawn {
+    fn spawn(&self, _name: &'static str, task: TTask) -> Box<dyn TaskHandle> {
+        return match &self.handle {
+            Some(handle) => Box::new(handle.spawn(task)),
+            None => Box::new(tokio::spawn(task)),
+        };
+    }
+}
+
+/// Spawns with `tokio::task::spawn_local`.
+struct LocalSpawn;
+
+impl Spawn for LocalSpawn {
+    fn spawn(&self, _name: &'static str, task: TTask) -> Box<dyn TaskHandle> {
+        return Box::new(tokio::task::spawn_local(task));
+    }
+}
+
+static TOKIO_SPAWN: OnceLock<Arc<dyn Spawn>> = OnceLock::new();
+static LOCAL_SPAWN: OnceLock<Arc<dyn Spawn>> = OnceLock::new();
+
+/// Spawner of the background tasks of the channels and the multiplexers,
+/// the ones of a channel are spawned once it is created.
+#[derive(Clone)]
+pub struct Spawner {
+    spawn: Arc<dyn Spawn>,
+    name: &'static str,
+    // handles of the spawned tasks, kept by the channels and the multiplexers
+    tasks: Option<Tasks>,
+}
+
+impl Spawner {
+    /// Spawn the tasks with the `spawn`.
+    pub fn new(spawn: impl Spawn + 'static) -> Spawner {
+        return Spawner {
+            spawn: Arc::new(spawn),
+            name: "custom",
+            tasks: None,
+        };
+    }
+
+    /// Spawn the tasks on the Tokio runtime the channel is created
+    /// on, creating a channel outside of a runtime panics. The default.
+    pub fn tokio() -> Spawner {
+        let spawn = TOKIO_SPAWN.get_or_init(|| { return Arc::new(TokioSpawn { handle: None }); });
+
+        return Spawner {
+            spawn: spawn.clone(),
+            name: "tokio",
+            tasks: None,
+        };
+    }
+
+    /// Spawn the tasks on the Tokio runtime of the `handle`,
+    /// the channel can be created outside of the runtime.
+    pub fn runtime(handle: Handle) -> Spawner {
+        return Spawner {
+            spawn: Arc::new(TokioSpawn { handle: Some(handle) }),
+            name: "runtime",
+            tasks: None,
+        };
+    }
+
+    /// Spawn the tasks on the current `LocalSet`, that is on the thread the
+    /// channel is created on, creating a channel outside of a `LocalSet` panics.
+    pub fn local() -> Spawner {
+        let spawn = LOCAL_SPAWN.get_or_init(|| { return Arc::new(LocalSpawn); });
+
+        return Spawner {
+            spawn: spawn.clone(),
+            name: "local",
+            tasks: None,
+        };
+    }
+
+    /// Spawner keeping the handles of its tasks, in the same `Tasks` if this one keeps them already.
+    pub(crate) fn tracked(&self) -> Spawner {
+        return Spawner {
+            tasks: Some(self.tasks.clone().unwrap_or_default()),
+            ..self.clone()
+        };
+    }
+
+    /// Handles of the tasks spawned so far, empty unless the spawner is `tracked`.
+    pub(crate) fn tasks(&self) -> Tasks {
+        return self.tasks.clone().unwrap_or_default();
+    }
+
+    /// Spawn the `task`, the handle is kept by the `tasks` too if the spawner is `tracked`.
+    pub fn spawn(
+        &self,
+        name: &'static str,
+        task: impl Future<Output = ()> + Send + 'static,
+    ) -> Task {
+        let (on_done, done) = oneshot::channel();
+
+        let handle = self.spawn.spawn(name, Box::pin(async move {
+            // dropped along with the task, if aborted too
+            let _on_done = on_done;
+
+            task.await;
+        }));
+
+        let task = Task {
+            name,
+            handle: Arc::from(handle),
+            done: done.shared(),
+        };
+
+        if let Some(tasks) = &self.tasks {
+            tasks.push(task.clone());
+        }
+
+        return task;
+    }
+}
+
+impl Default for Spawner {
+    fn default() -> Spawner {
+        return Spawner::tokio();
+    }
+}
+
+impl fmt::Debug for Spawner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return f.debug_struct("Spawner")
+            .field("name", &self.name)
+            .finish();
+    }
+}
+
+/// Spawners are equal if they share the same `Spawn`.
+impl PartialEq for Spawner {
+    fn eq(&self, other: &Spawner) -> bool {
+        return Arc::ptr_eq(&self.spawn, &other.spawn);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::atomic::Ordering, time::Duration};
+
+    use futures::future;
+    use tokio::{runtime::Builder, sync::oneshot, task::LocalSet, time::timeout};
+
+    use crate::utils::TaskCounter;
+
+    use super::Spawner;
+
+    #[test]
+    fn spawns_on_runtime_outside_of_it() {
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(1)
+            .build()
+            .expect("Cannot create runtime.");
+
+        let (on_done, done) = oneshot::channel();
+
+        Spawner::runtime(runtime.handle().clone())
+            .spawn("test", async move {
+                let _res = on_done.send(());
+            });
+
+        runtime.block_on(done)
+            .expect("Task must complete.");
+    }
+
+    #[tokio::test]
+    async fn spawns_on_local_set() {
+        let local_set = LocalSet::new();
+
+        local_set.run_until(async {
+            let (on_done, done) = oneshot::channel();
+
+            Spawner::local().spawn("test", async move {
+                let _res = on_done.send(());
+            });
+
+            done.await
+                .expect("Task must complete.");
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn spawns_with_custom_spawn() {
+        let (spawner, task_count) = TaskCounter::new();
+        let (on_done, done) = oneshot::channel::<()>();
+
+        spawner.spawn("test", async move {
+            let _res = done.await;
+        });
+
+        assert_eq!(
+            task_count.load(Ordering::SeqCst),
+            1,
+            "Must spawn with the custom spawn.",
+        );
+
+        drop(on_done);
+
+        assert_eq!(
+            Spawner::tokio(),
+            Spawner::default(),
+            "Must spawn on the Tokio runtime by default.",
+        );
+
+        assert_ne!(
+            Spawner::tokio(),
+            Spawner::local(),
+            "Must tell the spawners apart.",
+        );
+    }
+
+    #[tokio::test]
+    async fn joins_and_aborts_tracked_tasks() {
+        let spawner = Spawner::tokio().tracked();
+        let (on_done, done) = oneshot::channel();
+
+        spawner.spawn("test", async move {
+            let _res = on_done.send(());
+        });
+
+        timeout(Duration::from_secs(1), spawner.tasks().join()).await
+            .expect("Must join the completed task.");
+
+        done.await
+            .expect("Task must complete.");
+
+        spawner.spawn("test", future::pending());
+
+        spawner.tasks().abort();
+
+        timeout(Duration::from_secs(1), spawner.tasks().join()).await
+            .expect("Must join the aborted task.");
+    }
+
+    #[tokio::test]
+    async fn returns_task_handle() {
+        let spawner = Spawner::tokio().tracked();
+
+        let task = spawner.spawn("test", future::pending());
+
+        assert_eq!(
+            task.name(),
+            "test",
+            "Must tell the kind of the task.",
+        );
+
+        // the handle and the tracked one are the same task
+        task.abort();
+
+        timeout(Duration::from_secs(1), spawner.tasks().join()).await
+            .expect("Must join the task aborted with the returned handle.");
+
+        timeout(Duration::from_secs(1), task.join()).await
+            .expect("Must join the aborted task.");
+
+        let task = Spawner::tokio().spawn("test", async {});
+
+        timeout(Duration::from_secs(1), task.join()).await
+            .expect("Must join the task of an untracked spawner.");
+    }
+}
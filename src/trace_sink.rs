@@ -0,0 +1,146 @@
+use std::{fmt, ops::Deref, sync::Arc};
+
+use cs_trace::{Span, Trace, Tracer};
+
+/// Name of the trace tree of the channels created without a `TraceSink`.
+const SILENT_TRACE_NAME: &str = "upgradable-channel";
+
+/// Parent trace of the channels and the multiplexers, their spans and events go to the
+/// `tracing` subscriber of the caller, see `cs_trace::create_trace_listener`. Silent by
+/// default, nothing is traced unless the caller provides a trace that is not silent.
+#[derive(Clone)]
+pub struct TraceSink {
+    trace: Arc<dyn Tracer>,
+}
+
+impl TraceSink {
+    /// Trace under the span of the `trace`, silent if the `trace` is.
+    pub fn new(trace: Box<dyn Tracer>) -> TraceSink {
+        return TraceSink {
+            trace: Arc::from(trace),
+        };
+    }
+
+    /// Trace nothing, the spans are not even created.
+    pub fn silent() -> TraceSink {
+        return TraceSink::new(
+            Trace::new(
+                SILENT_TRACE_NAME,
+                true,
+                Span::none(),
+                Span::none(),
+                Span::none(),
+                Span::none(),
+                Span::none(),
+            ),
+        );
+    }
+}
+
+impl Default for TraceSink {
+    fn default() -> TraceSink {
+        return TraceSink::silent();
+    }
+}
+
+impl Deref for TraceSink {
+    type Target = dyn Tracer;
+
+    fn deref(&self) -> &(dyn Tracer + 'static) {
+        return self.trace.as_ref();
+    }
+}
+
+impl fmt::Debug for TraceSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return f.debug_struct("TraceSink")
+            .field("name", &self.trace.trace_tree_name())
+            .field("is_silent", &self.trace.is_silent())
+            .finish();
+    }
+}
+
+/// Sinks are equal if they share the same trace, or if both are silent.
+impl PartialEq for TraceSink {
+    fn eq(&self, other: &TraceSink) -> bool {
+        return Arc::ptr_eq(&self.trace, &other.trace) || (self.is_silent() && other.is_silent());
+    }
+}
+
+/// Create a child `TraceSink` of the `parent` one with the span `name` and `fields`,
+/// the fields take the `tracing` syntax. The child of a silent sink is silent too.
+macro_rules! child_trace {
+    ($parent:expr, $name:expr $(, $($fields:tt)+)?) => {
+        {
+            let parent: &$crate::TraceSink = &$parent;
+
+            if parent.is_silent() {
+                $crate::TraceSink::silent()
+            } else {
+                let parent_span = parent.span();
+
+                $crate::TraceSink::new(
+                    cs_trace::Trace::new(
+                        parent.trace_tree_name(),
+                        false,
+                        cs_trace::tracing_macros::trace_span!(parent: parent_span, $name $(, $($fields)+)?),
+                        cs_trace::tracing_macros::info_span!(parent: parent_span, $name $(, $($fields)+)?),
+                        cs_trace::tracing_macros::debug_span!(parent: parent_span, $name $(, $($fields)+)?),
+                        cs_trace::tracing_macros::warn_span!(parent: parent_span, $name $(, $($fields)+)?),
+                        cs_trace::tracing_macros::error_span!(parent: parent_span, $name $(, $($fields)+)?),
+                    ),
+                )
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use cs_trace::create_trace;
+
+    use super::TraceSink;
+
+    #[test]
+    fn is_silent_by_default() {
+        assert!(
+            TraceSink::default().is_silent(),
+            "Must be silent by default.",
+        );
+
+        assert_eq!(
+            child_trace!(TraceSink::default(), "child", id = 1),
+            TraceSink::silent(),
+            "Must be silent under a silent sink.",
+        );
+    }
+
+    #[test]
+    fn inherits_trace() {
+        let trace = TraceSink::new(create_trace!("parent"));
+        let child = child_trace!(trace, "child", id = 1, label = %"label");
+
+        assert!(
+            !child.is_silent(),
+            "Must trace under a trace that is not silent.",
+        );
+
+        assert_eq!(
+            child.trace_tree_name(),
+            "parent",
+            "Must belong to the trace tree of the parent.",
+        );
+
+        assert_eq!(
+            trace.clone(),
+            trace,
+            "Must share the trace with its clones.",
+        );
+
+        assert_ne!(
+            child,
+            trace,
+            "Must not share the trace with its children.",
+        );
+    }
+}
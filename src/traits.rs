@@ -1,7 +1,29 @@
-use anyhow::Result;
+use async_trait::async_trait;
 use connection_utils::Channel;
+use tokio::sync::watch;
 
+use crate::{UpgradeReport, UpgradeError, UpgradeState, ResumptionToken, Capability};
+
+#[async_trait]
 pub trait TUpgradableChannel: Channel {
-    // fn is_upgrdaded(&self) -> bool;
-    fn upgrade(&mut self, new_channel: Box<dyn Channel>) -> Result<()>;
+    /// Upgrade to the `new_channel`, the remote side must supply its counterpart.
+    /// See `UpgradeHandle::upgrade` for the details.
+    async fn upgrade(&mut self, new_channel: Box<dyn Channel>) -> Result<UpgradeReport, UpgradeError>;
+    /// Whether the channel was upgraded at least once.
+    fn is_upgraded(&self) -> bool;
+    /// Number of completed upgrades.
+    fn upgrade_count(&self) -> usize;
+    /// Id of the currently active channel.
+    fn channel_id(&self) -> u16;
+    /// Label of the currently active channel.
+    fn channel_label(&self) -> String;
+    /// Receiver of the upgrade phase transitions.
+    fn upgrade_state(&self) -> watch::Receiver<UpgradeState>;
+    /// Receiver of the capabilities supported by both sides, `None` until
+    /// the protocol version and capabilities are negotiated.
+    fn capabilities(&self) -> watch::Receiver<Option<Vec<Capability>>>;
+    /// Token to resume the session on a new transport, see `UpgradableChannel::resume`.
+    /// Resolves once the session ids are exchanged with the remote side, fails if
+    /// the control channel is lost before that.
+    async fn resumption_token(&self) -> Result<ResumptionToken, UpgradeError>;
 }
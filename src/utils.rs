@@ -7,3 +7,21 @@ pub use create_framed_stream::create_framed_stream;
 mod test_framed_stream;
 #[cfg(test)] // TODO: remove?
 pub use test_framed_stream::{test_framed_stream, TestOptions, StreamTestMessage};
+
+#[cfg(test)]
+mod poll_counter;
+#[cfg(test)]
+pub use poll_counter::PollCounter;
+
+#[cfg(test)]
+mod read_counter;
+#[cfg(test)]
+pub use read_counter::ReadCounter;
+
+#[cfg(test)]
+mod task_counter;
+#[cfg(test)]
+pub use task_counter::TaskCounter;
+
+mod dump_buf;
+pub use dump_buf::dump_buf;
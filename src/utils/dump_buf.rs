@@ -0,0 +1,56 @@
+use std::ascii;
+
+/// Largest number of bytes shown by `dump_buf`.
+pub const DUMP_SIZE: usize = 64;
+
+/// Printable dump of the data for the logs, the bytes that are not printable
+/// ASCII are escaped, e.g. `\xe2`, only the first `DUMP_SIZE` bytes are shown.
+pub fn dump_buf(buf: &[u8]) -> String {
+    let mut dump: String = buf.iter()
+        .take(DUMP_SIZE)
+        .flat_map(|byte| ascii::escape_default(*byte))
+        .map(char::from)
+        .collect();
+
+    if buf.len() > DUMP_SIZE {
+        dump.push_str(&format!("... ({} bytes)", buf.len()));
+    }
+
+    return dump;
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::{dump_buf, DUMP_SIZE};
+
+    #[rstest]
+    #[case(b"", "")]
+    #[case(b"data", "data")]
+    #[case(b"\"data\"\n", "\\\"data\\\"\\n")]
+    #[case(&[0x00, 0x7f, 0xff], "\\x00\\x7f\\xff")]
+    // a multi-byte character split between the reads
+    #[case(&"é".as_bytes()[..1], "\\xc3")]
+    fn dumps_data(
+        #[case] buf: &[u8],
+        #[case] expected_dump: &str,
+    ) {
+        assert_eq!(
+            dump_buf(buf),
+            expected_dump,
+            "Must escape the data that is not printable.",
+        );
+    }
+
+    #[test]
+    fn bounds_dump_size() {
+        let buf = vec![0xff; 10 * DUMP_SIZE];
+
+        assert_eq!(
+            dump_buf(&buf),
+            format!("{}... ({} bytes)", "\\xff".repeat(DUMP_SIZE), buf.len()),
+            "Must show the first bytes only.",
+        );
+    }
+}
@@ -0,0 +1,79 @@
+use std::{pin::Pin, task::{Context, Poll}, io, sync::{Arc, atomic::{AtomicUsize, Ordering}}};
+
+use connection_utils::Channel;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Channel that counts how many times it is polled, to tell
+/// if anything busy-waits on an idle channel.
+pub struct PollCounter {
+    channel: Pin<Box<dyn Channel>>,
+    poll_count: Arc<AtomicUsize>,
+}
+
+impl PollCounter {
+    #[allow(clippy::new_ret_no_self)] // wraps the `channel`, the test keeps the count only
+    pub fn new(
+        channel: Box<dyn Channel>,
+    ) -> (Box<dyn Channel>, Arc<AtomicUsize>) {
+        let poll_count = Arc::new(AtomicUsize::new(0));
+
+        let counter = PollCounter {
+            channel: Pin::new(channel),
+            poll_count: poll_count.clone(),
+        };
+
+        return (Box::new(counter), poll_count);
+    }
+}
+
+impl Channel for PollCounter {
+    fn id(&self) -> u16 {
+        return self.channel.id();
+    }
+
+    fn label(&self) -> &String {
+        return self.channel.label();
+    }
+}
+
+impl AsyncRead for PollCounter {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.poll_count.fetch_add(1, Ordering::SeqCst);
+
+        return self.channel.as_mut().poll_read(cx, buf);
+    }
+}
+
+impl AsyncWrite for PollCounter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_count.fetch_add(1, Ordering::SeqCst);
+
+        return self.channel.as_mut().poll_write(cx, buf);
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.poll_count.fetch_add(1, Ordering::SeqCst);
+
+        return self.channel.as_mut().poll_flush(cx);
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.poll_count.fetch_add(1, Ordering::SeqCst);
+
+        return self.channel.as_mut().poll_shutdown(cx);
+    }
+}
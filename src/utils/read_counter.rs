@@ -0,0 +1,76 @@
+use std::{pin::Pin, task::{Context, Poll}, io, sync::{Arc, atomic::{AtomicUsize, Ordering}}};
+
+use connection_utils::Channel;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Channel that counts the bytes read from it, to tell how much
+/// of the remote side's data was taken off the transport.
+pub struct ReadCounter {
+    channel: Pin<Box<dyn Channel>>,
+    bytes_read: Arc<AtomicUsize>,
+}
+
+impl ReadCounter {
+    #[allow(clippy::new_ret_no_self)] // wraps the `channel`, the test keeps the count only
+    pub fn new(
+        channel: Box<dyn Channel>,
+    ) -> (Box<dyn Channel>, Arc<AtomicUsize>) {
+        let bytes_read = Arc::new(AtomicUsize::new(0));
+
+        let counter = ReadCounter {
+            channel: Pin::new(channel),
+            bytes_read: bytes_read.clone(),
+        };
+
+        return (Box::new(counter), bytes_read);
+    }
+}
+
+impl Channel for ReadCounter {
+    fn id(&self) -> u16 {
+        return self.channel.id();
+    }
+
+    fn label(&self) -> &String {
+        return self.channel.label();
+    }
+}
+
+impl AsyncRead for ReadCounter {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled_before = buf.filled().len();
+        let result = self.channel.as_mut().poll_read(cx, buf);
+
+        self.bytes_read.fetch_add(buf.filled().len() - filled_before, Ordering::SeqCst);
+
+        return result;
+    }
+}
+
+impl AsyncWrite for ReadCounter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        return self.channel.as_mut().poll_write(cx, buf);
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        return self.channel.as_mut().poll_flush(cx);
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        return self.channel.as_mut().poll_shutdown(cx);
+    }
+}
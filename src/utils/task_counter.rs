@@ -0,0 +1,45 @@
+use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+
+use crate::{Spawn, Spawner, TTask, TaskHandle};
+
+/// Spawns the tasks with `tokio::spawn` and counts the ones still
+/// running, to tell if anything outlives the channel it belongs to.
+pub struct TaskCounter {
+    task_count: Arc<AtomicUsize>,
+}
+
+impl TaskCounter {
+    #[allow(clippy::new_ret_no_self)] // the counter is used as a `Spawner`, the test keeps the count only
+    pub fn new() -> (Spawner, Arc<AtomicUsize>) {
+        let task_count = Arc::new(AtomicUsize::new(0));
+
+        let counter = TaskCounter {
+            task_count: task_count.clone(),
+        };
+
+        return (Spawner::new(counter), task_count);
+    }
+}
+
+/// Counts a task off once it completes or gets dropped.
+struct TaskGuard(Arc<AtomicUsize>);
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Spawn for TaskCounter {
+    fn spawn(&self, _name: &'static str, task: TTask) -> Box<dyn TaskHandle> {
+        self.task_count.fetch_add(1, Ordering::SeqCst);
+
+        let guard = TaskGuard(self.task_count.clone());
+
+        return Box::new(tokio::spawn(async move {
+            let _guard = guard;
+
+            task.await;
+        }));
+    }
+}